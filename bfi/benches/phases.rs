@@ -0,0 +1,97 @@
+//! Times each phase separately for every program in `programs`: parsing, the
+//! merge and unroll passes, Cranelift translation and compilation together,
+//! and running the compiled code. `bfi --timings` prints the same breakdown
+//! for a single run.
+
+use std::{fs, path::Path, time::Duration};
+
+use bfi::{
+    replay, runtime, unroll, BFInstruction, BFJitFunction, CellWidth, OptimizedBFInstruction,
+    Parser, JIT,
+};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+/// Every bundled program with the cell width it needs, input for the ones that
+/// read any comes from `tests/golden` like in the tests
+const PROGRAMS: [(&str, u32); 10] = [
+    ("LostKng.b", 8),
+    ("PRIME.BF", 64),
+    ("collatz.b", 64),
+    ("gameoflife.b", 8),
+    ("hanoi.bf", 64),
+    ("mandelbrot-huge.b", 64),
+    ("mandelbrot.b", 64),
+    ("ryan-beer.bf", 64),
+    ("triangle.bf", 64),
+    ("z9a.b", 8),
+];
+
+const TAPE_LEN: usize = 1 << 22;
+const START: usize = TAPE_LEN / 2;
+
+fn optimize(insns: Vec<BFInstruction>) -> Vec<OptimizedBFInstruction> {
+    OptimizedBFInstruction::optimize(insns, &mut 0, 0, false)
+}
+
+fn jit(opt: &[OptimizedBFInstruction], width: CellWidth) -> (JIT, BFJitFunction) {
+    let mut jit = JIT::new();
+    jit.set_cell_width(width);
+    let f = unsafe { std::mem::transmute::<*const u8, BFJitFunction>(jit.jit(opt)) };
+    (jit, f)
+}
+
+fn phases(c: &mut Criterion) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    runtime::capture_output();
+    for &(program, bits) in PROGRAMS.iter() {
+        let code = fs::read_to_string(root.join("programs").join(program)).unwrap();
+        let name = Path::new(program).file_stem().unwrap().to_str().unwrap();
+        let golden = root.join("tests/golden");
+        let input = match fs::read_to_string(golden.join(format!("{}.replay", name))) {
+            Ok(recording) => replay::parse(&recording).unwrap(),
+            Err(_) => fs::read(golden.join(format!("{}.in", name))).unwrap_or_default(),
+        };
+        let width = CellWidth::from_bits(bits).unwrap();
+        let insns = Parser::new(&code).parse();
+        let (opt, _) = unroll::unroll(optimize(insns.clone()), width.mask());
+
+        let mut group = c.benchmark_group(program);
+        group.sample_size(10);
+        group.bench_function("parse", |b| b.iter(|| Parser::new(&code).parse()));
+        group.bench_function("merge", |b| {
+            b.iter_batched(|| insns.clone(), optimize, BatchSize::LargeInput)
+        });
+        group.bench_function("unroll", |b| {
+            b.iter_batched(
+                || optimize(insns.clone()),
+                |merged| unroll::unroll(merged, width.mask()),
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_function("compile", |b| {
+            b.iter(|| {
+                let (jit, f) = jit(&opt, width);
+                unsafe { jit.free_memory() };
+                f
+            })
+        });
+        let (compiled, f) = jit(&opt, width);
+        group.measurement_time(Duration::from_secs(10));
+        group.bench_function("run", |b| {
+            b.iter_batched(
+                || {
+                    runtime::set_embedded_input(input.clone(), false);
+                    runtime::take_output();
+                    vec![0u64; TAPE_LEN]
+                },
+                |mut tape| f(unsafe { tape.as_mut_ptr().add(START) }),
+                BatchSize::LargeInput,
+            )
+        });
+        group.finish();
+        unsafe { compiled.free_memory() };
+    }
+}
+
+criterion_group!(benches, phases);
+criterion_main!(benches);
@@ -0,0 +1,141 @@
+//! Runs generated programs through the reference interpreter, the optimizer
+//! followed by the interpreter, tiered mode, the bytecode VM and the JIT, at
+//! every cell width, and checks they all print the same and leave the same
+//! tape behind.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use bfi::{
+    bytecode, interp, runtime, tiered::Tiered, unroll, vm::Vm, BFJitFunction, CellWidth,
+    OptimizedBFInstruction, Parser, JIT,
+};
+
+const TAPE_LEN: usize = 1 << 16;
+const START: usize = TAPE_LEN / 2;
+/// Commands the reference interpreter may run, longer programs are skipped
+const STEPS: u64 = 100_000;
+
+/// Turns fuzzer bytes into a program and its input: every byte up to the first
+/// 0xff picks a command, with unmatched brackets dropped or closed at the end,
+/// the rest is fed to `,`.
+fn generate(data: &[u8]) -> (String, Vec<u8>) {
+    let split = data.iter().position(|&b| b == 0xff).unwrap_or(data.len());
+    let mut program = String::new();
+    let mut open = 0;
+    for &b in &data[..split] {
+        let c = b"+-<>.,[]"[b as usize % 8] as char;
+        match c {
+            '[' => open += 1,
+            ']' if open == 0 => continue,
+            ']' => open -= 1,
+            _ => {}
+        }
+        program.push(c);
+    }
+    program.push_str(&"]".repeat(open));
+    let input = data.get(split + 1..).unwrap_or_default().to_vec();
+    (program, input)
+}
+
+/// Output and final tape of one run
+#[derive(Debug, PartialEq, Eq)]
+struct Outcome {
+    output: Vec<u8>,
+    pointer: usize,
+    tape: Vec<u64>,
+}
+
+fn run(input: &[u8], f: impl FnOnce(&mut [u64]) -> Option<usize>) -> Option<Outcome> {
+    runtime::set_embedded_input(input.to_vec(), false);
+    runtime::take_output();
+    let mut tape = vec![0; TAPE_LEN];
+    let pointer = f(&mut tape)?;
+    Some(Outcome {
+        output: runtime::take_output(),
+        pointer,
+        tape,
+    })
+}
+
+/// Checks every engine against the reference interpreter for one cell width.
+fn compare(program: &str, input: &[u8], width: CellWidth) {
+    let insns = Parser::new(program).parse();
+
+    // only programs that finish in time and stay on the tape are compared
+    let reference = match run(input, |tape| {
+        interp::run(&insns, tape, START, STEPS, width).ok()
+    }) {
+        Some(r) => r,
+        None => return,
+    };
+
+    let opt = OptimizedBFInstruction::optimize(insns, &mut 0, 0, false);
+    let (opt, _) = unroll::unroll(opt, width.mask());
+    let jit = || {
+        let mut jit = JIT::new();
+        jit.set_cell_width(width);
+        jit
+    };
+
+    let interpreted = run(input, |tape| {
+        Some(Tiered::new(jit(), u32::MAX).run(&opt, tape, START))
+    });
+    assert_eq!(
+        interpreted.as_ref(),
+        Some(&reference),
+        "optimized interpreter differs for {} with {}-bit cells",
+        program,
+        width.bits()
+    );
+
+    // loops switch to compiled code after one interpreted iteration
+    let mut tiered = Tiered::new(jit(), 2);
+    let tiered_out = run(input, |tape| Some(tiered.run(&opt, tape, START)));
+    unsafe { tiered.into_jit().free_memory() };
+    assert_eq!(
+        tiered_out.as_ref(),
+        Some(&reference),
+        "tiered differs for {} with {}-bit cells",
+        program,
+        width.bits()
+    );
+
+    // through a .bfc file, so the encoding gets checked as well
+    let ops = bytecode::compile(&opt);
+    let (ops, read_width) = bytecode::read(&bytecode::write(&ops, width)).unwrap();
+    assert_eq!(read_width, width);
+    let vm = run(input, |tape| Some(Vm::new(&ops, width).run(tape, START)));
+    assert_eq!(
+        vm.as_ref(),
+        Some(&reference),
+        "VM differs for {} with {}-bit cells",
+        program,
+        width.bits()
+    );
+
+    let mut jit = jit();
+    let f = unsafe { std::mem::transmute::<*const u8, BFJitFunction>(jit.jit(&opt)) };
+    let jitted = run(input, |tape| {
+        let base = tape.as_mut_ptr();
+        let end = f(unsafe { base.add(START) });
+        Some(unsafe { end.offset_from(base) } as usize)
+    });
+    unsafe { jit.free_memory() };
+    assert_eq!(
+        jitted.as_ref(),
+        Some(&reference),
+        "JIT differs for {} with {}-bit cells",
+        program,
+        width.bits()
+    );
+}
+
+fuzz_target!(|data: &[u8]| {
+    runtime::capture_output();
+    let (program, input) = generate(data);
+    for &width in CellWidth::ALL.iter() {
+        compare(&program, &input, width);
+    }
+});
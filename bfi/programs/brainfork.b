@@ -0,0 +1,25 @@
+Brainfork threads
+The main thread prints A and B and C and a newline and forks a thread after
+each letter that prints x and y and z; with the deterministic schedule the
+threads run in the order they were forked once the main thread is done
+
+A forked thread carries on with the rest of the program after its own loop
+so everything the main thread does next is inside a loop on a flag cell
+that only the main thread sees set; each step uses ten cells of its own
+
++ flag
+>>>>>++++++++[>++++++++<-]>+.[-]<<<<<< print A
+>Y[>++++++++[>++++++++<-]>++++++++++++++++++++++++++++++++++++++++++++++++++++++++.[-]<<-]<
+[
+    >>>>>>>>>>+ next flag
+    >>>>>++++++++[>++++++++<-]>++.[-]<<<<<< print B
+    >Y[>++++++++[>++++++++<-]>+++++++++++++++++++++++++++++++++++++++++++++++++++++++++.[-]<<-]<
+    [
+        >>>>>>>>>>+ next flag
+        >>>>>++++++++[>++++++++<-]>+++.[-]<<<<<< print C
+        >Y[>++++++++[>++++++++<-]>++++++++++++++++++++++++++++++++++++++++++++++++++++++++++.[-]<<-]<
+        [
+            >>>>>++++++++++.[-]<<<<<- newline and clear the flag
+        ]
+    ]
+]
@@ -0,0 +1,21 @@
+pbrain procedures with definitions and calls and recursion and redefinition
+Expected output is AAA then five stars then B on one line
+
++ procedure 1 prints A using the two cells after its own
+(>++++++++[>++++++++<-]>+.[-]<<)
+:: call it twice
+
+>>>>++ procedure 2 calls procedure 1 from four cells back
+(<<<<:>>>>)
+: call it
+
+>>>>+++ procedure 3 counts down the next cell and prints the one after
+>+++++>>++++++[<+++++++>-]<<<
+(>[->.<<:>]<)
+: call it once and let it recurse
+
+<<<<<<<< redefine procedure 1 to print B
+(>++++++++[>++++++++<-]>++.[-]<<)
+>>>>: procedure 2 now prints B
+
+<<<++++++++++.[-] newline
@@ -0,0 +1,320 @@
+//! Compact bytecode for the VM: the optimized tree flattened into a list of ops
+//! with relative jumps, where common loop shapes become superinstructions.
+//!
+//! Programs can be written out as `.bfc` files and run again later without
+//! parsing or optimizing them, see `write` and `read`.
+
+use std::fmt::Write as _;
+
+use crate::{CellWidth, OptimizedBFInstruction as Opt};
+
+pub const MAGIC: &[u8; 4] = b"BFBC";
+/// Bump whenever the encoding below changes.
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// `>` and `<` runs
+    Move(i64),
+    /// `+` and `-` runs
+    Add(i64),
+    /// An add followed by a move, the most common pair by far
+    AddMove(i64, i64),
+    /// `[-]` and `[+]`
+    Clear,
+    /// A clear followed by an add
+    Set(i64),
+    /// Adds the cell times `factor` to the cell at `offset`. Loops like
+    /// `[->+>++<<]` become one of these per cell they add to and a `Clear`.
+    MulAdd(i64, i64),
+    /// `[>]`, `[<<]` and so on: moves by this much until the cell is 0
+    Scan(i64),
+    Put,
+    Get,
+    /// `[`, jumps forward this many ops to just past the matching
+    /// `JumpNonZero` if the cell is 0
+    JumpZero(u32),
+    /// `]`, jumps back this many ops to just past the matching `JumpZero`
+    /// unless the cell is 0
+    JumpNonZero(u32),
+    /// pbrain `(`, defines the procedure starting at the next op and jumps
+    /// this many ops forward past its `Return`
+    Define(u32),
+    /// pbrain `)`
+    Return,
+    /// pbrain `:`
+    Call,
+}
+
+/// Flattens an optimized program. It must not fork, the VM has no threads.
+pub fn compile(insns: &[Opt]) -> Vec<Op> {
+    let mut out = Vec::new();
+    sequence(insns, &mut out);
+    out
+}
+
+fn sequence(insns: &[Opt], out: &mut Vec<Op>) {
+    for insn in insns {
+        match insn {
+            Opt::DataPtrModify(x) => match out.last_mut() {
+                Some(Op::Add(a)) => *out.last_mut().unwrap() = Op::AddMove(*a, *x),
+                _ => out.push(Op::Move(*x)),
+            },
+            Opt::DataValueModify(x) => match out.last_mut() {
+                Some(Op::Clear) => *out.last_mut().unwrap() = Op::Set(*x),
+                _ => out.push(Op::Add(*x)),
+            },
+            Opt::DataValuePutchar => out.push(Op::Put),
+            Opt::DataValueScanchar => out.push(Op::Get),
+            Opt::WhileDataValueNonZero(body) => {
+                if !superinstruction(body, out) {
+                    let start = out.len();
+                    out.push(Op::JumpZero(0));
+                    sequence(body, out);
+                    let distance = (out.len() - start) as u32;
+                    out.push(Op::JumpNonZero(distance));
+                    out[start] = Op::JumpZero(distance);
+                }
+            }
+            Opt::DefineProcedure(body) => {
+                let start = out.len();
+                out.push(Op::Define(0));
+                sequence(body, out);
+                out.push(Op::Return);
+                out[start] = Op::Define((out.len() - start) as u32);
+            }
+            Opt::CallProcedure => out.push(Op::Call),
+            Opt::Fork => panic!("`Y` needs --engine jit"),
+        }
+    }
+}
+
+/// Emits a loop as superinstructions if it has one of the shapes above.
+fn superinstruction(body: &[Opt], out: &mut Vec<Op>) -> bool {
+    match body {
+        [Opt::DataValueModify(1)] | [Opt::DataValueModify(-1)] => {
+            out.push(Op::Clear);
+            return true;
+        }
+        [Opt::DataPtrModify(x)] => {
+            out.push(Op::Scan(*x));
+            return true;
+        }
+        _ => {}
+    }
+    // balanced loops that only add, counting the cell down or up by one
+    let mut ptr = 0;
+    let mut deltas: Vec<(i64, i64)> = Vec::new();
+    for insn in body {
+        match insn {
+            Opt::DataPtrModify(x) => ptr += x,
+            Opt::DataValueModify(x) => match deltas.iter_mut().find(|(o, _)| *o == ptr) {
+                Some((_, d)) => *d = d.wrapping_add(*x),
+                None => deltas.push((ptr, *x)),
+            },
+            _ => return false,
+        }
+    }
+    let step = match deltas.iter().find(|(o, _)| *o == 0) {
+        Some(&(_, step)) if ptr == 0 && (step == 1 || step == -1) => step,
+        _ => return false,
+    };
+    // counting up from n takes -n trips, so the factors flip
+    for &(offset, delta) in deltas.iter().filter(|(o, _)| *o != 0) {
+        out.push(Op::MulAdd(offset, delta.wrapping_mul(-step)));
+    }
+    out.push(Op::Clear);
+    true
+}
+
+/// One op per line with its index, jumps show where they go.
+pub fn format(ops: &[Op]) -> String {
+    let mut out = String::new();
+    for (i, op) in ops.iter().enumerate() {
+        write!(out, "{:>6}  ", i).unwrap();
+        match op {
+            Op::Move(x) => writeln!(out, "move {}", x),
+            Op::Add(x) => writeln!(out, "add {}", x),
+            Op::AddMove(x, y) => writeln!(out, "add-move {} {}", x, y),
+            Op::Clear => writeln!(out, "clear"),
+            Op::Set(x) => writeln!(out, "set {}", x),
+            Op::MulAdd(offset, factor) => writeln!(out, "mul-add {} {}", offset, factor),
+            Op::Scan(x) => writeln!(out, "scan {}", x),
+            Op::Put => writeln!(out, "put"),
+            Op::Get => writeln!(out, "get"),
+            Op::JumpZero(d) => writeln!(out, "jz {}", i + *d as usize + 1),
+            Op::JumpNonZero(d) => writeln!(out, "jnz {}", i - *d as usize + 1),
+            Op::Define(d) => writeln!(out, "define {}", i + *d as usize),
+            Op::Return => writeln!(out, "return"),
+            Op::Call => writeln!(out, "call"),
+        }
+        .unwrap();
+    }
+    out
+}
+
+const TAG_MOVE: u8 = 0;
+const TAG_ADD: u8 = 1;
+const TAG_ADD_MOVE: u8 = 2;
+const TAG_CLEAR: u8 = 3;
+const TAG_SET: u8 = 4;
+const TAG_MUL_ADD: u8 = 5;
+const TAG_SCAN: u8 = 6;
+const TAG_PUT: u8 = 7;
+const TAG_GET: u8 = 8;
+const TAG_JUMP_ZERO: u8 = 9;
+const TAG_JUMP_NON_ZERO: u8 = 10;
+const TAG_DEFINE: u8 = 11;
+const TAG_RETURN: u8 = 12;
+const TAG_CALL: u8 = 13;
+
+/// Encodes a `.bfc` file. The cell width is stored since the optimizer's
+/// output depends on it.
+pub fn write(ops: &[Op], width: CellWidth) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    out.push(width.bits() as u8);
+    varint(ops.len() as u64, &mut out);
+    for op in ops {
+        let (tag, args): (u8, &[i64]) = match *op {
+            Op::Move(x) => (TAG_MOVE, &[x]),
+            Op::Add(x) => (TAG_ADD, &[x]),
+            Op::AddMove(x, y) => (TAG_ADD_MOVE, &[x, y]),
+            Op::Clear => (TAG_CLEAR, &[]),
+            Op::Set(x) => (TAG_SET, &[x]),
+            Op::MulAdd(x, y) => (TAG_MUL_ADD, &[x, y]),
+            Op::Scan(x) => (TAG_SCAN, &[x]),
+            Op::Put => (TAG_PUT, &[]),
+            Op::Get => (TAG_GET, &[]),
+            Op::JumpZero(d) => (TAG_JUMP_ZERO, &[d as i64]),
+            Op::JumpNonZero(d) => (TAG_JUMP_NON_ZERO, &[d as i64]),
+            Op::Define(d) => (TAG_DEFINE, &[d as i64]),
+            Op::Return => (TAG_RETURN, &[]),
+            Op::Call => (TAG_CALL, &[]),
+        };
+        out.push(tag);
+        for &x in args {
+            // zigzag, so small negative numbers stay small
+            varint(((x << 1) ^ (x >> 63)) as u64, &mut out);
+        }
+    }
+    out
+}
+
+pub(crate) fn varint(mut x: u64, out: &mut Vec<u8>) {
+    while x >= 0x80 {
+        out.push(x as u8 | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+/// Decodes a `.bfc` file, checking that its jumps pair up.
+pub fn read(data: &[u8]) -> Result<(Vec<Op>, CellWidth), String> {
+    let mut r = Reader { data };
+    if r.bytes(4)? != MAGIC {
+        return Err("not a bytecode file".to_string());
+    }
+    let version = u32::from_le_bytes([r.byte()?, r.byte()?, r.byte()?, r.byte()?]);
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "bytecode format {} isn't supported, this bfi reads format {}",
+            version, FORMAT_VERSION
+        ));
+    }
+    let bits = r.byte()?;
+    let width = CellWidth::from_bits(bits as u32)
+        .ok_or_else(|| format!("unsupported cell width {}", bits))?;
+    let len = r.varint()? as usize;
+    // every op takes at least a byte, don't trust len for the allocation
+    let mut ops = Vec::with_capacity(len.min(r.data.len()));
+    for _ in 0..len {
+        let tag = r.byte()?;
+        ops.push(match tag {
+            TAG_MOVE => Op::Move(r.arg()?),
+            TAG_ADD => Op::Add(r.arg()?),
+            TAG_ADD_MOVE => Op::AddMove(r.arg()?, r.arg()?),
+            TAG_CLEAR => Op::Clear,
+            TAG_SET => Op::Set(r.arg()?),
+            TAG_MUL_ADD => Op::MulAdd(r.arg()?, r.arg()?),
+            TAG_SCAN => Op::Scan(r.arg()?),
+            TAG_PUT => Op::Put,
+            TAG_GET => Op::Get,
+            TAG_JUMP_ZERO => Op::JumpZero(r.arg()? as u32),
+            TAG_JUMP_NON_ZERO => Op::JumpNonZero(r.arg()? as u32),
+            TAG_DEFINE => Op::Define(r.arg()? as u32),
+            TAG_RETURN => Op::Return,
+            TAG_CALL => Op::Call,
+            _ => return Err(format!("unknown op {}", tag)),
+        });
+    }
+    if !r.data.is_empty() {
+        return Err("trailing data after the last op".to_string());
+    }
+    check_jumps(&ops)?;
+    Ok((ops, width))
+}
+
+/// Every `JumpZero` and `Define` has to land on its closing op, so the VM can
+/// trust them.
+fn check_jumps(ops: &[Op]) -> Result<(), String> {
+    let mut open = Vec::new();
+    for (i, op) in ops.iter().enumerate() {
+        match op {
+            Op::JumpZero(_) | Op::Define(_) => open.push(i),
+            Op::JumpNonZero(_) | Op::Return => {
+                let start = open.pop().ok_or_else(|| format!("unmatched op {}", i))?;
+                let fits = match (ops[start], op) {
+                    (Op::JumpZero(a), Op::JumpNonZero(b)) => a == *b && start + a as usize == i,
+                    (Op::Define(a), Op::Return) => start + a as usize == i + 1,
+                    _ => false,
+                };
+                if !fits {
+                    return Err(format!("op {} doesn't match op {}", i, start));
+                }
+            }
+            _ => {}
+        }
+    }
+    match open.pop() {
+        Some(i) => Err(format!("unmatched op {}", i)),
+        None => Ok(()),
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    pub data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < n {
+            return Err("file ends early".to_string());
+        }
+        let (b, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(b)
+    }
+
+    pub fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn varint(&mut self) -> Result<u64, String> {
+        let mut x = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            x |= ((b & 0x7f) as u64) << shift;
+            if b < 0x80 {
+                return Ok(x);
+            }
+        }
+        Err("varint too long".to_string())
+    }
+
+    fn arg(&mut self) -> Result<i64, String> {
+        let x = self.varint()?;
+        Ok((x >> 1) as i64 ^ -((x & 1) as i64))
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    convert::TryInto,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::OptimizedBFInstruction;
+
+const MAGIC: &[u8; 4] = b"BFIC";
+/// Bump whenever the encoding below or the optimizer output changes shape.
+const FORMAT_VERSION: u32 = 1;
+
+/// Identifies one optimized program: the source text, the options that
+/// influenced optimization and the bfi version that produced it.
+pub struct Key {
+    hash: u64,
+    desc: String,
+}
+
+impl Key {
+    pub fn new(source: &str, options: &str) -> Self {
+        let desc = format!(
+            "bfi {} format {} options [{}] source {} bytes",
+            env!("CARGO_PKG_VERSION"),
+            FORMAT_VERSION,
+            options,
+            source.len()
+        );
+        let mut hash = Fnv::new();
+        hash.write(desc.as_bytes());
+        hash.write(source.as_bytes());
+        Self {
+            hash: hash.finish(),
+            desc,
+        }
+    }
+}
+
+/// Optimized programs stored under `$XDG_CACHE_HOME/bfi`.
+///
+/// Only the optimized instructions are cached, not machine code: the JIT'd
+/// code has relocations into this process, while parsing and optimizing is
+/// what dominates startup for big programs anyway.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// Returns `None` when there is nowhere to put the cache.
+    pub fn open() -> Option<Self> {
+        let base = match std::env::var_os("XDG_CACHE_HOME") {
+            Some(d) if !d.is_empty() => PathBuf::from(d),
+            _ => Path::new(&std::env::var_os("HOME")?).join(".cache"),
+        };
+        Some(Self {
+            dir: base.join("bfi"),
+        })
+    }
+
+    fn path(&self, key: &Key) -> PathBuf {
+        self.dir.join(format!("{:016x}.bfo", key.hash))
+    }
+
+    pub fn load(&self, key: &Key) -> Option<Vec<OptimizedBFInstruction>> {
+        let data = fs::read(self.path(key)).ok()?;
+        let mut r = Reader { data: &data };
+        if r.bytes(4)? != MAGIC || r.u64()? != key.hash {
+            return None;
+        }
+        let desc_len = r.u64()? as usize;
+        if r.bytes(desc_len)? != key.desc.as_bytes() {
+            return None;
+        }
+        let insns = r.insns()?;
+        if !r.data.is_empty() {
+            return None;
+        }
+        Some(insns)
+    }
+
+    pub fn store(&self, key: &Key, insns: &[OptimizedBFInstruction]) {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&key.hash.to_le_bytes());
+        out.extend_from_slice(&(key.desc.len() as u64).to_le_bytes());
+        out.extend_from_slice(key.desc.as_bytes());
+        write_insns(insns, &mut out);
+
+        // write then rename so a concurrent run never sees half a file
+        let path = self.path(key);
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        let res = fs::create_dir_all(&self.dir)
+            .and_then(|_| fs::write(&tmp, &out))
+            .and_then(|_| fs::rename(&tmp, &path));
+        if let Err(e) = res {
+            eprintln!("Warning: could not write cache {}: {}", path.display(), e);
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+}
+
+const TAG_PTR: u8 = 0;
+const TAG_VALUE: u8 = 1;
+const TAG_PUTCHAR: u8 = 2;
+const TAG_SCANCHAR: u8 = 3;
+const TAG_LOOP: u8 = 4;
+const TAG_DEFINE_PROCEDURE: u8 = 5;
+const TAG_CALL_PROCEDURE: u8 = 6;
+const TAG_FORK: u8 = 7;
+
+fn write_insns(insns: &[OptimizedBFInstruction], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(insns.len() as u64).to_le_bytes());
+    for insn in insns {
+        match insn {
+            OptimizedBFInstruction::DataPtrModify(x) => {
+                out.push(TAG_PTR);
+                out.extend_from_slice(&x.to_le_bytes());
+            }
+            OptimizedBFInstruction::DataValueModify(x) => {
+                out.push(TAG_VALUE);
+                out.extend_from_slice(&x.to_le_bytes());
+            }
+            OptimizedBFInstruction::DataValuePutchar => out.push(TAG_PUTCHAR),
+            OptimizedBFInstruction::DataValueScanchar => out.push(TAG_SCANCHAR),
+            OptimizedBFInstruction::WhileDataValueNonZero(inner) => {
+                out.push(TAG_LOOP);
+                write_insns(inner, out);
+            }
+            OptimizedBFInstruction::DefineProcedure(inner) => {
+                out.push(TAG_DEFINE_PROCEDURE);
+                write_insns(inner, out);
+            }
+            OptimizedBFInstruction::CallProcedure => out.push(TAG_CALL_PROCEDURE),
+            OptimizedBFInstruction::Fork => out.push(TAG_FORK),
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+        let (b, rest) = self.data.split_at(n);
+        self.data = rest;
+        Some(b)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Option<i64> {
+        Some(self.u64()? as i64)
+    }
+
+    fn insns(&mut self) -> Option<Vec<OptimizedBFInstruction>> {
+        let len = self.u64()? as usize;
+        // every instruction takes at least a byte, don't trust len for the allocation
+        let mut insns = Vec::with_capacity(len.min(self.data.len()));
+        for _ in 0..len {
+            insns.push(match self.bytes(1)?[0] {
+                TAG_PTR => OptimizedBFInstruction::DataPtrModify(self.i64()?),
+                TAG_VALUE => OptimizedBFInstruction::DataValueModify(self.i64()?),
+                TAG_PUTCHAR => OptimizedBFInstruction::DataValuePutchar,
+                TAG_SCANCHAR => OptimizedBFInstruction::DataValueScanchar,
+                TAG_LOOP => OptimizedBFInstruction::WhileDataValueNonZero(self.insns()?),
+                TAG_DEFINE_PROCEDURE => OptimizedBFInstruction::DefineProcedure(self.insns()?),
+                TAG_CALL_PROCEDURE => OptimizedBFInstruction::CallProcedure,
+                TAG_FORK => OptimizedBFInstruction::Fork,
+                _ => return None,
+            });
+        }
+        Some(insns)
+    }
+}
+
+/// 64-bit FNV-1a, used instead of `DefaultHasher` since the key has to stay
+/// the same across builds.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Fnv(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use OptimizedBFInstruction::*;
+
+    /// A cache in a directory of its own under the system's temp dir
+    fn cache(name: &str) -> Cache {
+        let dir = std::env::temp_dir().join(format!("bfi-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Cache { dir }
+    }
+
+    fn program() -> Vec<OptimizedBFInstruction> {
+        vec![
+            DataValueModify(-3),
+            WhileDataValueNonZero(vec![DataPtrModify(i64::MIN), DataValuePutchar]),
+            DefineProcedure(vec![DataValueScanchar, Fork]),
+            CallProcedure,
+        ]
+    }
+
+    #[test]
+    fn fnv_matches_reference_values() {
+        let hash = |s: &str| {
+            let mut h = Fnv::new();
+            h.write(s.as_bytes());
+            h.finish()
+        };
+        assert_eq!(hash(""), 0xcbf29ce484222325);
+        assert_eq!(hash("a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(hash("foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn key_covers_source_and_options() {
+        let key = Key::new("+[.]", "merge, unroll 64-bit");
+        assert_eq!(key.hash, Key::new("+[.]", "merge, unroll 64-bit").hash);
+        assert_ne!(key.hash, Key::new("+[,]", "merge, unroll 64-bit").hash);
+        assert_ne!(key.hash, Key::new("+[.]", "merge, unroll 8-bit").hash);
+        assert_ne!(key.hash, Key::new("+[.]", "merge").hash);
+    }
+
+    #[test]
+    fn round_trip() {
+        let cache = cache("round-trip");
+        let key = Key::new("source", "options");
+        assert_eq!(cache.load(&key), None);
+        cache.store(&key, &program());
+        assert_eq!(cache.load(&key), Some(program()));
+        // same hash but different options, as if two keys collided
+        let other = Key {
+            hash: key.hash,
+            desc: "something else".to_string(),
+        };
+        assert_eq!(cache.load(&other), None);
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_entries_miss() {
+        let cache = cache("corrupt");
+        let key = Key::new("source", "options");
+        cache.store(&key, &program());
+        let good = fs::read(cache.path(&key)).unwrap();
+
+        let mut bad_tag = good.clone();
+        // the first instruction's tag comes right after its list's length
+        let first = 4 + 8 + 8 + key.desc.len() + 8;
+        bad_tag[first] = 0xff;
+        let mut trailing = good.clone();
+        trailing.push(0);
+        let mut huge_len = good.clone();
+        huge_len[first - 8..first].copy_from_slice(&u64::MAX.to_le_bytes());
+        for bad in [
+            Vec::new(),
+            b"garbage".to_vec(),
+            good[..good.len() - 1].to_vec(),
+            bad_tag,
+            trailing,
+            huge_len,
+        ] {
+            fs::write(cache.path(&key), &bad).unwrap();
+            assert_eq!(cache.load(&key), None);
+        }
+
+        // storing again replaces the broken entry
+        cache.store(&key, &program());
+        assert_eq!(cache.load(&key), Some(program()));
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
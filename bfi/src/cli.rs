@@ -0,0 +1,368 @@
+use std::path::PathBuf;
+
+use crate::{
+    dump::{DumpTarget, Dumper, Stage},
+    lexer::{Dialect, Extensions},
+    report::Radix,
+    runtime::{IoMode, Schedule},
+    CellWidth,
+};
+
+pub const USAGE: &str = "\
+Usage: bfi [OPTIONS] [FILE]
+       bfi repl [OPTIONS]
+       bfi fmt [OPTIONS] [FILE]
+       bfi lint [OPTIONS] FILE
+       bfi compile [OPTIONS] FILE
+
+Runs FILE, or asks for a filename if none is given.
+`bfi repl` starts an interactive session instead.
+`bfi fmt` prints FILE (or stdin) indented by nesting, or minified.
+`bfi lint` warns about suspicious code in FILE.
+`bfi compile` writes FILE as bytecode for --engine vm, into FILE.bfc.
+Running a bytecode file always uses the VM, running a snapshot resumes it.
+
+Options:
+    --dump STAGES     dump compilation stages, comma separated:
+                      ast, opt, clif, clif-opt, asm, bytecode, all
+    --dump-dir DIR    write dumps into DIR instead of stderr
+    --engine ENGINE   how to run the program:
+                      jit     compile everything up front (default)
+                      tiered  interpret, compiling loops once they get hot
+                      vm      interpret bytecode, without generating
+                              any machine code
+    --jit-threshold N times a loop header runs before tiered mode
+                      compiles it (default 1000)
+    --no-cache        don't read or write the optimized code cache
+    --no-unroll       don't replace loops with known trip counts by straight code
+    --no-outline      don't compile repeated loops once as shared functions
+    --chunk-size N    compile instruction sequences longer than N in chunks
+                      of their own, 0 to never split them (default 2000)
+    --compile-threads N
+                      compile chunks and shared loops on N threads at once,
+                      0 for one per CPU (default 1)
+    --perf-map        list JIT'd functions in /tmp/perf-PID.map for perf
+    --coverage        count what ran and write FILE.cov, the source with
+                      counts, and FILE.info for lcov; implies --no-unroll
+                      and --no-outline
+    --cell-width BITS bits per cell, 8, 16, 32 or 64 (default)
+    --checked         stop with an error when the pointer leaves the tape
+    --dialect NAME    source language: brainfuck (default), ook, blub
+    --token-map FILE  read commands spelled as in a TOML token map, see README
+    --pbrain          enable pbrain procedures: ( ) and :
+    --brainfork       enable Brainfork's Y, which forks a thread
+    --schedule SCHED  how forked threads run:
+                      threads        on a thread pool (default)
+                      deterministic  one at a time, in the order they forked
+    --max-threads N   most threads the pool starts, 0 for no limit (default)
+    --bang-input MODE treat everything after the first top level `!` as input:
+                      then-stdin  read it before stdin
+                      only        read it instead of stdin
+    --output MODE     how `.` writes a cell:
+                      byte     its low 8 bits (default)
+                      utf8     UTF-8 encoded, as a Unicode scalar value
+                      decimal  as a signed number and a newline
+    --input MODE      how `,` reads a cell: byte, utf8 or decimal
+    --snapshot FILE   write the state of the run to FILE on every SIGUSR1,
+                      run FILE to carry on from there; VM only, jit and
+                      tiered runs can't be snapshotted
+    --record FILE     write every byte read from stdin to FILE, with when
+    --replay FILE     read input from a FILE written by --record, not stdin
+    --dump-tape RADIX after running, print the nonzero part of the tape
+                      in hex or dec
+    --timings         after running, print how long each phase took
+    --exit-code       exit with the value of the final cell
+    --minify          fmt: strip comments and cancel out +- and <> pairs
+    --width N         fmt: wrap lines at N columns (default 80)
+    --json            lint: print warnings as JSON
+    -o FILE           compile: write the bytecode to FILE instead
+    -q, --quiet       don't print progress, only the program's output
+    -h, --help        print this message
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Jit,
+    Tiered,
+    Vm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Repl,
+    Fmt,
+    Lint,
+    Compile,
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub command: Command,
+    pub file: Option<String>,
+    pub dumper: Dumper,
+    pub engine: Engine,
+    pub jit_threshold: u32,
+    pub no_cache: bool,
+    pub unroll: bool,
+    pub outline: bool,
+    pub chunk_size: u64,
+    pub compile_threads: usize,
+    pub perf_map: bool,
+    pub coverage: bool,
+    pub checked: bool,
+    pub quiet: bool,
+    pub timings: bool,
+    pub cell_width: CellWidth,
+    pub dialect: Dialect,
+    pub extensions: Extensions,
+    pub schedule: Schedule,
+    /// `Some(then_stdin)` when input is embedded after `!`
+    pub bang_input: Option<bool>,
+    pub input_mode: IoMode,
+    pub output_mode: IoMode,
+    pub snapshot: Option<PathBuf>,
+    pub record: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+    pub dump_tape: Option<Radix>,
+    pub exit_code: bool,
+    pub minify: bool,
+    pub width: usize,
+    pub json: bool,
+    /// Where `bfi compile` writes to
+    pub out: Option<PathBuf>,
+}
+
+impl Options {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut file = None;
+        let mut stages = Vec::new();
+        let mut dump_dir = None;
+        let mut engine = Engine::Jit;
+        let mut jit_threshold = 1000;
+        let mut no_cache = false;
+        let mut unroll = true;
+        let mut outline = true;
+        let mut chunk_size = 2000;
+        let mut compile_threads = 1;
+        let mut perf_map = false;
+        let mut coverage = false;
+        let mut checked = false;
+        let mut quiet = false;
+        let mut timings = false;
+        let mut cell_width = CellWidth::default();
+        let mut dialect = Dialect::Brainfuck;
+        let mut extensions = Extensions::default();
+        let mut deterministic = false;
+        let mut max_threads = 0;
+        let mut bang_input = None;
+        let mut input_mode = IoMode::Byte;
+        let mut output_mode = IoMode::Byte;
+        let mut snapshot = None;
+        let mut record = None;
+        let mut replay = None;
+        let mut dump_tape = None;
+        let mut exit_code = false;
+        let mut minify = false;
+        let mut width = 80;
+        let mut json = false;
+        let mut out = None;
+
+        let mut args = args.into_iter().peekable();
+        let command = match args.peek().map(String::as_str) {
+            Some("repl") => {
+                args.next();
+                Command::Repl
+            }
+            Some("fmt") => {
+                args.next();
+                Command::Fmt
+            }
+            Some("lint") => {
+                args.next();
+                Command::Lint
+            }
+            Some("compile") => {
+                args.next();
+                Command::Compile
+            }
+            _ => Command::Run,
+        };
+        while let Some(arg) = args.next() {
+            // accept both `--flag value` and `--flag=value`
+            let (flag, inline) = match arg.split_once('=') {
+                Some((f, v)) if f.starts_with("--") => (f.to_string(), Some(v.to_string())),
+                _ => (arg.clone(), None),
+            };
+            let mut value = |name: &str| {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("{} needs a value", name))
+            };
+            match flag.as_str() {
+                "-h" | "--help" => return Err(String::new()),
+                "--dump" => {
+                    for s in value("--dump")?.split(',') {
+                        if s == "all" {
+                            stages.extend_from_slice(&Stage::ALL);
+                        } else {
+                            stages.push(
+                                Stage::from_name(s)
+                                    .ok_or_else(|| format!("unknown dump stage `{}`", s))?,
+                            );
+                        }
+                    }
+                }
+                "--dump-dir" => dump_dir = Some(PathBuf::from(value("--dump-dir")?)),
+                "--engine" => {
+                    engine = match value("--engine")?.as_str() {
+                        "jit" => Engine::Jit,
+                        "tiered" => Engine::Tiered,
+                        "vm" => Engine::Vm,
+                        e => return Err(format!("unknown engine `{}`", e)),
+                    }
+                }
+                "--jit-threshold" => {
+                    jit_threshold = value("--jit-threshold")?
+                        .parse()
+                        .map_err(|e| format!("bad --jit-threshold: {}", e))?
+                }
+                "--no-cache" => no_cache = true,
+                "--no-unroll" => unroll = false,
+                "--no-outline" => outline = false,
+                "--chunk-size" => {
+                    chunk_size = value("--chunk-size")?
+                        .parse()
+                        .map_err(|e| format!("bad --chunk-size: {}", e))?
+                }
+                "--perf-map" => perf_map = true,
+                "--coverage" => coverage = true,
+                "--compile-threads" => {
+                    compile_threads = value("--compile-threads")?
+                        .parse()
+                        .map_err(|e| format!("bad --compile-threads: {}", e))?
+                }
+                "--checked" => checked = true,
+                "-q" | "--quiet" => quiet = true,
+                "--timings" => timings = true,
+                "--cell-width" => {
+                    let bits = value("--cell-width")?;
+                    cell_width = bits
+                        .parse()
+                        .ok()
+                        .and_then(CellWidth::from_bits)
+                        .ok_or_else(|| format!("unsupported cell width `{}`", bits))?;
+                }
+                "--dialect" => {
+                    let name = value("--dialect")?;
+                    dialect = Dialect::from_name(&name)
+                        .ok_or_else(|| format!("unknown dialect `{}`", name))?;
+                }
+                "--pbrain" => extensions.pbrain = true,
+                "--brainfork" => extensions.brainfork = true,
+                "--schedule" => {
+                    deterministic = match value("--schedule")?.as_str() {
+                        "threads" => false,
+                        "deterministic" => true,
+                        s => return Err(format!("unknown schedule `{}`", s)),
+                    }
+                }
+                "--bang-input" => {
+                    bang_input = match value("--bang-input")?.as_str() {
+                        "then-stdin" => Some(true),
+                        "only" => Some(false),
+                        m => return Err(format!("unknown --bang-input mode `{}`", m)),
+                    }
+                }
+                "--input" => {
+                    let mode = value("--input")?;
+                    input_mode = IoMode::from_name(&mode)
+                        .ok_or_else(|| format!("unknown input mode `{}`", mode))?;
+                }
+                "--output" => {
+                    let mode = value("--output")?;
+                    output_mode = IoMode::from_name(&mode)
+                        .ok_or_else(|| format!("unknown output mode `{}`", mode))?;
+                }
+                "--snapshot" => snapshot = Some(PathBuf::from(value("--snapshot")?)),
+                "--record" => record = Some(PathBuf::from(value("--record")?)),
+                "--replay" => replay = Some(PathBuf::from(value("--replay")?)),
+                "--dump-tape" => {
+                    dump_tape = Some(match value("--dump-tape")?.as_str() {
+                        "hex" => Radix::Hex,
+                        "dec" => Radix::Dec,
+                        r => return Err(format!("unknown radix `{}`", r)),
+                    })
+                }
+                "--exit-code" => exit_code = true,
+                "--minify" => minify = true,
+                "--json" => json = true,
+                "-o" => out = Some(PathBuf::from(value("-o")?)),
+                "--width" => {
+                    width = value("--width")?
+                        .parse()
+                        .map_err(|e| format!("bad --width: {}", e))?
+                }
+                "--max-threads" => {
+                    max_threads = value("--max-threads")?
+                        .parse()
+                        .map_err(|e| format!("bad --max-threads: {}", e))?
+                }
+                "--token-map" => dialect = Dialect::TokenMap(PathBuf::from(value("--token-map")?)),
+                f if f.starts_with('-') && f.len() > 1 => {
+                    return Err(format!("unknown option `{}`", f))
+                }
+                _ if file.is_none() && command != Command::Repl => file = Some(arg),
+                _ => return Err(format!("unexpected argument `{}`", arg)),
+            }
+        }
+
+        let target = match dump_dir {
+            Some(dir) => DumpTarget::Dir(dir),
+            None => DumpTarget::Stderr,
+        };
+        Ok(Self {
+            command,
+            file,
+            dumper: Dumper::new(stages, target),
+            engine,
+            jit_threshold,
+            no_cache,
+            // coverage counts loops as written, see `coverage`
+            unroll: unroll && !coverage,
+            outline: outline && !coverage,
+            chunk_size,
+            compile_threads: if compile_threads == 0 {
+                std::thread::available_parallelism().map_or(1, |n| n.get())
+            } else {
+                compile_threads
+            },
+            perf_map,
+            coverage,
+            checked,
+            quiet,
+            timings,
+            cell_width,
+            dialect,
+            extensions,
+            schedule: if deterministic {
+                Schedule::Deterministic
+            } else {
+                Schedule::Threads(max_threads)
+            },
+            bang_input,
+            input_mode,
+            output_mode,
+            snapshot,
+            record,
+            replay,
+            dump_tape,
+            exit_code,
+            minify,
+            width,
+            json,
+            out,
+        })
+    }
+}
@@ -0,0 +1,257 @@
+//! `--coverage`: counts how often every loop and procedure ran and maps the
+//! counts back onto the source.
+//!
+//! Each loop gets two counters in the generated code: how often it was reached
+//! and how often its body ran. A pbrain procedure likewise counts how often it
+//! was defined and how often it was called. That's enough to tell for every
+//! instruction whether it ran, as straight-line code runs exactly as often as
+//! the body it's in. Counters are numbered in program order, which the merge
+//! pass keeps, so the `n`th pair belongs to the `n`th opening bracket in the
+//! source. Unrolling would break that and outlining would share one pair
+//! between several loops, so coverage runs go without both.
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    lexer::{Token, TokenKind},
+    OptimizedBFInstruction,
+};
+
+pub struct Counters {
+    /// Which pair each loop and procedure definition counts in, keyed by address
+    index: HashMap<*const OptimizedBFInstruction, usize>,
+    /// The pair of each procedure, in the order `collect_procedures` finds them
+    procedures: Vec<usize>,
+    counts: Box<[AtomicU64]>,
+}
+
+// the keys in `index` are only compared, never dereferenced
+unsafe impl Send for Counters {}
+unsafe impl Sync for Counters {}
+
+impl Counters {
+    pub fn new(insns: &[OptimizedBFInstruction]) -> Self {
+        let mut counters = Counters {
+            index: HashMap::new(),
+            procedures: Vec::new(),
+            counts: Box::new([]),
+        };
+        counters.number(insns);
+        counters.counts = (0..2 * counters.index.len())
+            .map(|_| AtomicU64::new(0))
+            .collect();
+        counters
+    }
+
+    fn number(&mut self, insns: &[OptimizedBFInstruction]) {
+        for insn in insns {
+            match insn {
+                OptimizedBFInstruction::WhileDataValueNonZero(body) => {
+                    self.index.insert(insn, self.index.len());
+                    self.number(body);
+                }
+                OptimizedBFInstruction::DefineProcedure(body) => {
+                    self.procedures.push(self.index.len());
+                    self.index.insert(insn, self.index.len());
+                    self.number(body);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Address of the counter for how often `insn` is reached, the one for
+    /// how often its body runs comes right after
+    pub(crate) fn counter(&self, insn: &OptimizedBFInstruction) -> Option<usize> {
+        let i = *self.index.get(&(insn as *const _))?;
+        Some(self.counts[2 * i].as_ptr() as usize)
+    }
+
+    /// Address of the counter for how often the `i`th procedure is called
+    pub(crate) fn procedure_calls(&self, i: usize) -> usize {
+        self.counts[2 * self.procedures[i] + 1].as_ptr() as usize
+    }
+
+    /// Reached and run counts of every loop and procedure, in program order
+    pub fn counts(&self) -> Vec<(u64, u64)> {
+        self.counts
+            .chunks(2)
+            .map(|c| (c[0].load(Ordering::Relaxed), c[1].load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// What ran, per token of the program
+pub struct Coverage<'a> {
+    source: &'a str,
+    tokens: &'a [Token],
+    /// Byte offset of every line in `source`
+    line_starts: Vec<usize>,
+    /// How often each token ran
+    runs: Vec<u64>,
+    /// Index into `tokens` of each opening bracket, with its counts
+    blocks: Vec<(usize, (u64, u64))>,
+}
+
+impl<'a> Coverage<'a> {
+    /// Maps `counts` onto `tokens`, read from `source`. Code outside any loop
+    /// counts as run once.
+    pub fn new(source: &'a str, tokens: &'a [Token], counts: &[(u64, u64)]) -> Self {
+        let mut runs = Vec::with_capacity(tokens.len());
+        let mut blocks = Vec::new();
+        // the counts of each block we're in
+        let mut open: Vec<(u64, u64)> = Vec::new();
+        for (i, token) in tokens.iter().enumerate() {
+            let here = open.last().map_or(1, |c| c.1);
+            runs.push(match token.kind {
+                TokenKind::LoopStart | TokenKind::ProcedureStart => {
+                    let c = counts.get(blocks.len()).copied().unwrap_or_default();
+                    blocks.push((i, c));
+                    open.push(c);
+                    c.0
+                }
+                TokenKind::LoopEnd => open.pop().map_or(0, |c| c.1),
+                TokenKind::ProcedureEnd => open.pop().map_or(0, |c| c.0),
+                _ => here,
+            });
+        }
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            source,
+            tokens,
+            line_starts,
+            runs,
+            blocks,
+        }
+    }
+
+    fn line(&self, token: usize) -> usize {
+        self.line_col(token).0
+    }
+
+    /// 1-based line and column (in characters) where `token` starts
+    fn line_col(&self, token: usize) -> (usize, usize) {
+        let offset = self.tokens[token].span.start;
+        let line = self.line_starts.partition_point(|&s| s <= offset);
+        let col = self.source[self.line_starts[line - 1]..offset]
+            .chars()
+            .count()
+            + 1;
+        (line, col)
+    }
+
+    /// The most and fewest times any instruction on each line ran, for lines
+    /// with any
+    fn lines(&self) -> HashMap<usize, (u64, u64)> {
+        let mut lines = HashMap::new();
+        for (i, &runs) in self.runs.iter().enumerate() {
+            let l = lines.entry(self.line(i)).or_insert((runs, runs));
+            l.0 = l.0.max(runs);
+            l.1 = l.1.min(runs);
+        }
+        lines
+    }
+
+    /// Instructions that ran and how many there are
+    pub fn instructions(&self) -> (usize, usize) {
+        let run = self.runs.iter().filter(|&&r| r > 0).count();
+        (run, self.runs.len())
+    }
+
+    /// Loops and procedures whose body ran and how many there are
+    pub fn blocks(&self) -> (usize, usize) {
+        let run = self.blocks.iter().filter(|(_, c)| c.1 > 0).count();
+        (run, self.blocks.len())
+    }
+
+    /// The source with how often each line ran in front, like `gcov` does:
+    /// `-` for lines without code, `#####` for lines where none of it ran and
+    /// a `*` after the count where only some did.
+    pub fn listing(&self, file: &str) -> String {
+        let lines = self.lines();
+        let (run, total) = self.instructions();
+        let (entered, blocks) = self.blocks();
+        let mut out = String::new();
+        writeln!(out, "{:>9}:{:>5}:Source:{}", "-", 0, file).unwrap();
+        writeln!(
+            out,
+            "{:>9}:{:>5}:Instructions:{} of {} run",
+            "-", 0, run, total
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "{:>9}:{:>5}:Blocks:{} of {} entered",
+            "-", 0, entered, blocks
+        )
+        .unwrap();
+        for (n, text) in self.source.lines().enumerate() {
+            let count = match lines.get(&(n + 1)) {
+                None => "-".to_string(),
+                Some((0, _)) => "#####".to_string(),
+                Some((most, 0)) => format!("{}*", most),
+                Some((most, _)) => most.to_string(),
+            };
+            writeln!(out, "{:>9}:{:>5}:{}", count, n + 1, text).unwrap();
+        }
+        out
+    }
+
+    /// An lcov tracefile: every line with code, every loop as a branch that's
+    /// either entered or skipped, and every procedure as a function.
+    pub fn lcov(&self, file: &str) -> String {
+        let mut out = String::new();
+        writeln!(out, "TN:\nSF:{}", file).unwrap();
+        let procedures: Vec<_> = self
+            .blocks
+            .iter()
+            .filter(|(i, _)| self.tokens[*i].kind == TokenKind::ProcedureStart)
+            .collect();
+        for (i, _) in &procedures {
+            let (line, col) = self.line_col(*i);
+            writeln!(out, "FN:{},procedure@{}:{}", line, line, col).unwrap();
+        }
+        for (i, (_, calls)) in &procedures {
+            let (line, col) = self.line_col(*i);
+            writeln!(out, "FNDA:{},procedure@{}:{}", calls, line, col).unwrap();
+        }
+        writeln!(out, "FNF:{}", procedures.len()).unwrap();
+        let called = procedures.iter().filter(|(_, c)| c.1 > 0).count();
+        writeln!(out, "FNH:{}", called).unwrap();
+
+        let mut branches = 0;
+        let mut taken = 0;
+        for (n, (i, (reached, iterations))) in self.blocks.iter().enumerate() {
+            if self.tokens[*i].kind != TokenKind::LoopStart {
+                continue;
+            }
+            let line = self.line(*i);
+            // the body runs `iterations` times, and every time it's reached the loop is left once
+            for (branch, count) in [(0, iterations), (1, reached)] {
+                branches += 1;
+                if *reached == 0 {
+                    writeln!(out, "BRDA:{},{},{},-", line, n, branch).unwrap();
+                } else {
+                    writeln!(out, "BRDA:{},{},{},{}", line, n, branch, count).unwrap();
+                    taken += (*count > 0) as usize;
+                }
+            }
+        }
+        writeln!(out, "BRF:{}\nBRH:{}", branches, taken).unwrap();
+
+        let mut lines: Vec<_> = self.lines().into_iter().collect();
+        lines.sort_unstable();
+        for (line, (most, _)) in &lines {
+            writeln!(out, "DA:{},{}", line, most).unwrap();
+        }
+        let hit = lines.iter().filter(|(_, (most, _))| *most > 0).count();
+        writeln!(out, "LF:{}\nLH:{}\nend_of_record", lines.len(), hit).unwrap();
+        out
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, stderr, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{BFInstruction, OptimizedBFInstruction};
+
+/// A stage of the pipeline that can be dumped with `--dump`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// The parsed instruction tree
+    Ast,
+    /// The output of every optimizer pass
+    Opt,
+    /// Cranelift IR as emitted by the translator
+    Clif,
+    /// Cranelift IR after Cranelift's own passes ran on it
+    ClifOpt,
+    /// Final machine code
+    Asm,
+    /// Bytecode for the VM
+    Bytecode,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 6] = [
+        Stage::Ast,
+        Stage::Opt,
+        Stage::Clif,
+        Stage::ClifOpt,
+        Stage::Asm,
+        Stage::Bytecode,
+    ];
+
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "ast" => Some(Stage::Ast),
+            "opt" => Some(Stage::Opt),
+            "clif" => Some(Stage::Clif),
+            "clif-opt" => Some(Stage::ClifOpt),
+            "asm" => Some(Stage::Asm),
+            "bytecode" => Some(Stage::Bytecode),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum DumpTarget {
+    Stderr,
+    Dir(PathBuf),
+}
+
+/// Writes out the stages requested on the command line; does nothing by default.
+#[derive(Debug, Clone)]
+pub struct Dumper {
+    stages: Vec<Stage>,
+    target: DumpTarget,
+}
+
+impl Default for Dumper {
+    fn default() -> Self {
+        Self {
+            stages: Vec::new(),
+            target: DumpTarget::Stderr,
+        }
+    }
+}
+
+impl Dumper {
+    pub fn new(stages: Vec<Stage>, target: DumpTarget) -> Self {
+        Self { stages, target }
+    }
+
+    pub fn wants(&self, stage: Stage) -> bool {
+        self.stages.contains(&stage)
+    }
+
+    /// Creates the dump directory if there is one, so a bad `--dump-dir`
+    /// shows up before any work is done.
+    pub fn create_dir(&self) -> io::Result<()> {
+        match &self.target {
+            DumpTarget::Dir(dir) if !self.stages.is_empty() => {
+                fs::create_dir_all(dir).map_err(|e| in_path(dir, e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Dumps `contents` under `name` if `stage` was requested.
+    /// `contents` is only evaluated when needed since some of these are huge.
+    pub fn dump(
+        &self,
+        stage: Stage,
+        name: &str,
+        contents: impl FnOnce() -> String,
+    ) -> io::Result<()> {
+        if !self.wants(stage) {
+            return Ok(());
+        }
+        let contents = contents();
+        match &self.target {
+            DumpTarget::Stderr => {
+                let mut e = stderr();
+                writeln!(e, "=== {} ===", name)?;
+                e.write_all(contents.as_bytes())?;
+                if !contents.ends_with('\n') {
+                    writeln!(e)?;
+                }
+            }
+            DumpTarget::Dir(dir) => {
+                self.create_dir()?;
+                let path = dir.join(name);
+                File::create(&path)
+                    .and_then(|mut f| f.write_all(contents.as_bytes()))
+                    .map_err(|e| in_path(&path, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `e` with the path it happened on in the message
+fn in_path(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+/// Reports a dump that couldn't be written like any other error and exits.
+pub fn fail(e: io::Error) -> ! {
+    eprintln!("error: could not write dump: {}", e);
+    std::process::exit(1);
+}
+
+/// Renders the parsed tree as BF, one loop per indented block.
+pub fn format_ast(insns: &[BFInstruction]) -> String {
+    fn go(insns: &[BFInstruction], depth: usize, out: &mut String) {
+        let mut line = String::new();
+        for insn in insns {
+            match insn {
+                BFInstruction::WhileDataValueNonZero(inner) => {
+                    flush_line(&mut line, depth, out);
+                    block("[", "]", depth, out, |out| go(inner, depth + 1, out));
+                }
+                BFInstruction::DefineProcedure(inner) => {
+                    flush_line(&mut line, depth, out);
+                    block("(", ")", depth, out, |out| go(inner, depth + 1, out));
+                }
+                BFInstruction::CallProcedure => line.push(':'),
+                BFInstruction::Fork => line.push('Y'),
+                BFInstruction::DataPtrIncrement => line.push('>'),
+                BFInstruction::DataPtrDecrement => line.push('<'),
+                BFInstruction::DataValueIncrement => line.push('+'),
+                BFInstruction::DataValueDecrement => line.push('-'),
+                BFInstruction::DataValuePutchar => line.push('.'),
+                BFInstruction::DataValueScanchar => line.push(','),
+            }
+        }
+        flush_line(&mut line, depth, out);
+    }
+    let mut out = String::new();
+    go(insns, 0, &mut out);
+    out
+}
+
+/// Renders optimized code in a BF-like notation where runs carry their count,
+/// e.g. `+5 >2 -1 <2 [ ... ]`.
+pub fn format_optimized(insns: &[OptimizedBFInstruction]) -> String {
+    fn go(insns: &[OptimizedBFInstruction], depth: usize, out: &mut String) {
+        let mut line = String::new();
+        for insn in insns {
+            match insn {
+                OptimizedBFInstruction::WhileDataValueNonZero(inner) => {
+                    flush_line(&mut line, depth, out);
+                    block("[", "]", depth, out, |out| go(inner, depth + 1, out));
+                }
+                OptimizedBFInstruction::DefineProcedure(inner) => {
+                    flush_line(&mut line, depth, out);
+                    block("(", ")", depth, out, |out| go(inner, depth + 1, out));
+                }
+                OptimizedBFInstruction::CallProcedure => push_word(&mut line, ":"),
+                OptimizedBFInstruction::Fork => push_word(&mut line, "Y"),
+                OptimizedBFInstruction::DataPtrModify(x) => push_run(&mut line, '>', '<', *x),
+                OptimizedBFInstruction::DataValueModify(x) => push_run(&mut line, '+', '-', *x),
+                OptimizedBFInstruction::DataValuePutchar => push_word(&mut line, "."),
+                OptimizedBFInstruction::DataValueScanchar => push_word(&mut line, ","),
+            }
+        }
+        flush_line(&mut line, depth, out);
+    }
+    let mut out = String::new();
+    go(insns, 0, &mut out);
+    out
+}
+
+fn push_run(line: &mut String, pos: char, neg: char, x: i64) {
+    if !line.is_empty() {
+        line.push(' ');
+    }
+    let c = if x < 0 { neg } else { pos };
+    write!(line, "{}{}", c, x.unsigned_abs()).unwrap();
+}
+
+fn push_word(line: &mut String, w: &str) {
+    if !line.is_empty() {
+        line.push(' ');
+    }
+    line.push_str(w);
+}
+
+fn block(open: &str, close: &str, depth: usize, out: &mut String, inner: impl FnOnce(&mut String)) {
+    indent(depth, out);
+    out.push_str(open);
+    out.push('\n');
+    inner(out);
+    indent(depth, out);
+    out.push_str(close);
+    out.push('\n');
+}
+
+fn indent(depth: usize, out: &mut String) {
+    for _ in 0..depth {
+        out.push_str("  ");
+    }
+}
+
+fn flush_line(line: &mut String, depth: usize, out: &mut String) {
+    if !line.is_empty() {
+        indent(depth, out);
+        out.push_str(line);
+        out.push('\n');
+        line.clear();
+    }
+}
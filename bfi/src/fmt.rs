@@ -0,0 +1,380 @@
+//! `bfi fmt`: rewrites programs either minified or laid out by nesting.
+
+use crate::{
+    lexer::{Lexer, Token, TokenKind},
+    BFInstruction,
+};
+
+/// Writes `insns` back out as plain brainfuck without comments, merging
+/// adjacent `+`/`-` and `<`/`>` so pairs that cancel out disappear.
+pub fn minify(insns: &[BFInstruction]) -> String {
+    fn go(insns: &[BFInstruction], out: &mut String) {
+        // net value and pointer change of the current run, only one is ever nonzero
+        let (mut value, mut ptr) = (0i64, 0i64);
+        for insn in insns {
+            match insn {
+                BFInstruction::DataValueIncrement | BFInstruction::DataValueDecrement => {
+                    push_run(&mut ptr, '>', '<', out);
+                    value += if let BFInstruction::DataValueIncrement = insn {
+                        1
+                    } else {
+                        -1
+                    };
+                    continue;
+                }
+                BFInstruction::DataPtrIncrement | BFInstruction::DataPtrDecrement => {
+                    push_run(&mut value, '+', '-', out);
+                    ptr += if let BFInstruction::DataPtrIncrement = insn {
+                        1
+                    } else {
+                        -1
+                    };
+                    continue;
+                }
+                _ => {}
+            }
+            push_run(&mut value, '+', '-', out);
+            push_run(&mut ptr, '>', '<', out);
+            match insn {
+                BFInstruction::WhileDataValueNonZero(inner) => {
+                    out.push('[');
+                    go(inner, out);
+                    out.push(']');
+                }
+                BFInstruction::DefineProcedure(inner) => {
+                    out.push('(');
+                    go(inner, out);
+                    out.push(')');
+                }
+                BFInstruction::DataValuePutchar => out.push('.'),
+                BFInstruction::DataValueScanchar => out.push(','),
+                BFInstruction::CallProcedure => out.push(':'),
+                BFInstruction::Fork => out.push('Y'),
+                _ => unreachable!(),
+            }
+        }
+        push_run(&mut value, '+', '-', out);
+        push_run(&mut ptr, '>', '<', out);
+    }
+    let mut out = String::new();
+    go(insns, &mut out);
+    out.push('\n');
+    out
+}
+
+fn push_run(n: &mut i64, pos: char, neg: char, out: &mut String) {
+    let c = if *n < 0 { neg } else { pos };
+    for _ in 0..n.unsigned_abs() {
+        out.push(c);
+    }
+    *n = 0;
+}
+
+/// Checks that every bracket and parenthesis in `tokens`, lexed from `source`,
+/// is closed by one of the same kind, which `Parser::parse` relies on.
+pub fn check_brackets(source: &str, tokens: &[Token]) -> Result<(), String> {
+    let mut open = Vec::new();
+    for token in tokens {
+        let start = match token.kind {
+            TokenKind::LoopStart | TokenKind::ProcedureStart => {
+                open.push(token.kind);
+                continue;
+            }
+            TokenKind::LoopEnd => TokenKind::LoopStart,
+            TokenKind::ProcedureEnd => TokenKind::ProcedureStart,
+            _ => continue,
+        };
+        if open.pop() != Some(start) {
+            let text = &source[token.span.clone()];
+            return Err(format!("unmatched `{}` at byte {}", text, token.span.start));
+        }
+    }
+    if !open.is_empty() {
+        return Err("unmatched opening bracket".to_string());
+    }
+    Ok(())
+}
+
+/// Lays `source` out with every loop and procedure body indented on its own
+/// lines, filling lines up to `width` columns.
+///
+/// Comments are kept next to the commands they belong to: a comment that
+/// starts on the same line as a command stays at the end of that command's
+/// line, any other comment goes on its own lines before the next command.
+/// Commands keep their spelling, so this works for every dialect.
+pub fn format(source: &str, lexer: &dyn Lexer, width: usize) -> Result<String, String> {
+    let tokens = lexer.tokenize(source);
+    check_brackets(source, &tokens)?;
+    // dialects with longer spellings get spaces between commands
+    let sep = if tokens.iter().any(|t| t.span.len() > 1) {
+        " "
+    } else {
+        ""
+    };
+    let mut f = Formatter {
+        out: String::new(),
+        line: String::new(),
+        line_depth: 0,
+        depth: 0,
+        width,
+        sep,
+        bracket: false,
+    };
+    let mut prev_end = 0;
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        f.comment(&source[prev_end..token.span.start], prev_end == 0);
+        let text = &source[token.span.clone()];
+        match token.kind {
+            TokenKind::LoopStart | TokenKind::ProcedureStart => {
+                if let Some(end) = inline_block(source, &tokens[i..]) {
+                    // short innermost loops like [-] stay on one line
+                    let words: Vec<_> = tokens[i..=i + end]
+                        .iter()
+                        .map(|t| &source[t.span.clone()])
+                        .collect();
+                    f.word(&words.join(sep));
+                    i += end;
+                } else {
+                    f.bracket(text);
+                    f.depth += 1;
+                }
+            }
+            TokenKind::LoopEnd | TokenKind::ProcedureEnd => {
+                f.depth -= 1;
+                f.bracket(text);
+            }
+            _ => f.word(text),
+        }
+        prev_end = tokens[i].span.end;
+        i += 1;
+    }
+    f.comment(&source[prev_end..], prev_end == 0);
+    f.flush();
+    Ok(f.out)
+}
+
+/// Most commands a block can have and still be kept on one line
+const INLINE_BLOCK: usize = 8;
+
+/// If the block opened by `tokens[0]` has no blocks or comments inside and is
+/// short, returns the index of its closing token.
+fn inline_block(source: &str, tokens: &[Token]) -> Option<usize> {
+    for (i, pair) in tokens.windows(2).take(INLINE_BLOCK + 1).enumerate() {
+        if !source[pair[0].span.end..pair[1].span.start]
+            .trim()
+            .is_empty()
+        {
+            return None;
+        }
+        match pair[1].kind {
+            TokenKind::LoopEnd | TokenKind::ProcedureEnd => return Some(i + 1),
+            TokenKind::LoopStart | TokenKind::ProcedureStart => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+struct Formatter {
+    out: String,
+    /// The line being filled, without indentation
+    line: String,
+    line_depth: usize,
+    depth: usize,
+    width: usize,
+    /// Goes between commands on a line
+    sep: &'static str,
+    /// The line holds a bracket, which gets a line to itself
+    bracket: bool,
+}
+
+impl Formatter {
+    fn push(&mut self, text: &str) {
+        if self.line.is_empty() {
+            self.line_depth = self.depth;
+        }
+        self.line.push_str(text);
+    }
+
+    fn word(&mut self, text: &str) {
+        let used =
+            self.line_depth * 2 + self.line.chars().count() + self.sep.len() + text.chars().count();
+        if self.bracket || (!self.line.is_empty() && used > self.width) {
+            self.flush();
+        }
+        if !self.line.is_empty() {
+            self.push(self.sep);
+        }
+        self.push(text);
+    }
+
+    fn bracket(&mut self, text: &str) {
+        self.flush();
+        self.push(text);
+        self.bracket = true;
+    }
+
+    /// Places the text between two commands.
+    fn comment(&mut self, gap: &str, at_start: bool) {
+        let trailing_end = match gap.find('\n') {
+            _ if at_start => 0,
+            Some(i) => i,
+            None => gap.len(),
+        };
+        let (trailing, leading) = gap.split_at(trailing_end);
+        let trailing = trailing.trim();
+        if !trailing.is_empty() {
+            if !self.line.is_empty() {
+                self.push(" ");
+            }
+            self.push(trailing);
+            self.flush();
+        }
+        let lines: Vec<_> = leading.lines().map(str::trim).collect();
+        let first = lines.iter().position(|l| !l.is_empty());
+        let last = lines.iter().rposition(|l| !l.is_empty());
+        if let (Some(first), Some(last)) = (first, last) {
+            self.flush();
+            for l in &lines[first..=last] {
+                if l.is_empty() {
+                    self.out.push('\n');
+                } else {
+                    self.push(l);
+                    self.flush();
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.bracket = false;
+        if self.line.is_empty() {
+            return;
+        }
+        for _ in 0..self.line_depth {
+            self.out.push_str("  ");
+        }
+        self.out.push_str(&self.line);
+        self.out.push('\n');
+        self.line.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        lexer::{Brainfuck, Extensions, WordPairs},
+        Parser,
+    };
+
+    fn minified(source: &str, lexer: &dyn Lexer) -> String {
+        minify(&Parser::with_lexer(source, lexer).parse())
+    }
+
+    #[test]
+    fn minify_merges_runs() {
+        let bf = Brainfuck::default();
+        assert_eq!(minified("+-+ >><<< . a comment [-]", &bf), "+<.[-]\n");
+        assert_eq!(minified("+-><", &bf), "\n");
+        let pbrain = Brainfuck {
+            extensions: Extensions {
+                pbrain: true,
+                brainfork: true,
+            },
+        };
+        assert_eq!(minified("(+-+):Y", &pbrain), "(+):Y\n");
+    }
+
+    #[test]
+    fn layout() {
+        let source = "a program\n++[->+<]>[.[-]>+++++++++>] done\n";
+        assert_eq!(
+            format(source, &Brainfuck::default(), 80).unwrap(),
+            "a program\n++[->+<]>\n[\n  .[-]>+++++++++>\n] done\n"
+        );
+    }
+
+    #[test]
+    fn comments_stay_with_their_commands() {
+        let source = "+++ three\n[-] clear\n\n\nnext part\n  .   print\n";
+        assert_eq!(
+            format(source, &Brainfuck::default(), 80).unwrap(),
+            "+++ three\n[-] clear\nnext part\n. print\n"
+        );
+        // a comment on its own line ends the line before it
+        assert_eq!(
+            format("+\nplus one\n+", &Brainfuck::default(), 80).unwrap(),
+            "+\nplus one\n+\n"
+        );
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        let bf = Brainfuck::default();
+        assert_eq!(
+            format("+]", &bf, 80),
+            Err("unmatched `]` at byte 1".to_string())
+        );
+        assert_eq!(
+            format("[[-]", &bf, 80),
+            Err("unmatched opening bracket".to_string())
+        );
+        // a `)` can't close a `[`, even though the depth works out
+        let pbrain = Brainfuck {
+            extensions: Extensions {
+                pbrain: true,
+                brainfork: false,
+            },
+        };
+        let source = "[-)";
+        assert_eq!(
+            check_brackets(source, &pbrain.tokenize(source)),
+            Err("unmatched `)` at byte 2".to_string())
+        );
+        assert_eq!(check_brackets("([-]:)", &pbrain.tokenize("([-]:)")), Ok(()));
+    }
+
+    #[test]
+    fn idempotent() {
+        let programs = [
+            include_str!("../programs/hanoi.bf"),
+            include_str!("../programs/triangle.bf"),
+            include_str!("../programs/ryan-beer.bf"),
+            include_str!("../programs/mandelbrot.b"),
+            include_str!("../programs/pbrain.b"),
+        ];
+        let lexer = Brainfuck {
+            extensions: Extensions {
+                pbrain: true,
+                brainfork: false,
+            },
+        };
+        for source in programs.iter() {
+            for width in [20, 80] {
+                let once = format(source, &lexer, width).unwrap();
+                assert_eq!(format(&once, &lexer, width).unwrap(), once);
+                // only the layout changes
+                assert_eq!(minified(&once, &lexer), minified(source, &lexer));
+            }
+        }
+    }
+
+    #[test]
+    fn idempotent_with_words() {
+        let source =
+            "Ook. Ook? Ook. Ook. Ook! Ook? Ook! Ook! Ook? Ook. Ook. Ook. Ook? Ook. Ook? Ook!";
+        let once = format(source, &WordPairs::OOK, 30).unwrap();
+        assert_eq!(
+            once,
+            "Ook. Ook? Ook. Ook.\nOok! Ook? Ook! Ook! Ook? Ook. Ook. Ook. Ook? Ook. Ook? Ook!\n"
+        );
+        assert_eq!(format(&once, &WordPairs::OOK, 30).unwrap(), once);
+        assert_eq!(
+            minified(&once, &WordPairs::OOK),
+            minified(source, &WordPairs::OOK)
+        );
+    }
+}
@@ -0,0 +1,67 @@
+//! Reference interpreter for the parsed tree, one command at a time with no
+//! optimizations, to check the optimizer and the JIT against.
+
+use crate::{runtime, BFInstruction, CellWidth};
+
+/// Why the interpreter stopped before the end of the program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// Ran more commands than the budget allowed
+    OutOfSteps,
+    /// The pointer left the tape
+    OffTape,
+    /// pbrain and Brainfork commands aren't supported
+    Unsupported,
+}
+
+/// Runs `insns` with the data pointer at `tape[ptr]` for at most `steps`
+/// commands, with cells `width` bits wide, returning where the pointer ended up.
+pub fn run(
+    insns: &[BFInstruction],
+    tape: &mut [u64],
+    ptr: usize,
+    steps: u64,
+    width: CellWidth,
+) -> Result<usize, Stop> {
+    let mut steps = steps;
+    block(insns, tape, ptr, &mut steps, width.mask())
+}
+
+fn block(
+    insns: &[BFInstruction],
+    tape: &mut [u64],
+    mut ptr: usize,
+    steps: &mut u64,
+    mask: u64,
+) -> Result<usize, Stop> {
+    for insn in insns {
+        tick(steps)?;
+        match insn {
+            BFInstruction::DataPtrIncrement => ptr += 1,
+            BFInstruction::DataPtrDecrement => ptr = ptr.checked_sub(1).ok_or(Stop::OffTape)?,
+            BFInstruction::DataValueIncrement => tape[ptr] = tape[ptr].wrapping_add(1) & mask,
+            BFInstruction::DataValueDecrement => tape[ptr] = tape[ptr].wrapping_sub(1) & mask,
+            BFInstruction::DataValuePutchar => runtime::write_output(tape[ptr]),
+            BFInstruction::DataValueScanchar => tape[ptr] = runtime::read_input(&tape[ptr]) & mask,
+            BFInstruction::WhileDataValueNonZero(body) => {
+                while tape[ptr] != 0 {
+                    // the jump back counts too, so `[]` runs out of steps
+                    tick(steps)?;
+                    ptr = block(body, tape, ptr, steps, mask)?;
+                }
+            }
+            BFInstruction::DefineProcedure(_)
+            | BFInstruction::CallProcedure
+            | BFInstruction::Fork => return Err(Stop::Unsupported),
+        }
+        if ptr >= tape.len() {
+            return Err(Stop::OffTape);
+        }
+    }
+    Ok(ptr)
+}
+
+fn tick(steps: &mut u64) -> Result<(), Stop> {
+    *steps = steps.checked_sub(1).ok_or(Stop::OutOfSteps)?;
+    Ok(())
+}
@@ -0,0 +1,485 @@
+use std::{
+    collections::HashMap,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use crate::BFInstruction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    DataPtrIncrement,
+    DataPtrDecrement,
+    DataValueIncrement,
+    DataValueDecrement,
+    DataValuePutchar,
+    DataValueScanchar,
+    LoopStart,
+    LoopEnd,
+    ProcedureStart,
+    ProcedureEnd,
+    ProcedureCall,
+    Fork,
+}
+
+impl TokenKind {
+    pub fn from_char(c: char) -> Option<Self> {
+        match c {
+            '>' => Some(TokenKind::DataPtrIncrement),
+            '<' => Some(TokenKind::DataPtrDecrement),
+            '+' => Some(TokenKind::DataValueIncrement),
+            '-' => Some(TokenKind::DataValueDecrement),
+            '.' => Some(TokenKind::DataValuePutchar),
+            ',' => Some(TokenKind::DataValueScanchar),
+            '[' => Some(TokenKind::LoopStart),
+            ']' => Some(TokenKind::LoopEnd),
+            '(' => Some(TokenKind::ProcedureStart),
+            ')' => Some(TokenKind::ProcedureEnd),
+            ':' => Some(TokenKind::ProcedureCall),
+            'Y' => Some(TokenKind::Fork),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(self) -> char {
+        match self {
+            TokenKind::DataPtrIncrement => '>',
+            TokenKind::DataPtrDecrement => '<',
+            TokenKind::DataValueIncrement => '+',
+            TokenKind::DataValueDecrement => '-',
+            TokenKind::DataValuePutchar => '.',
+            TokenKind::DataValueScanchar => ',',
+            TokenKind::LoopStart => '[',
+            TokenKind::LoopEnd => ']',
+            TokenKind::ProcedureStart => '(',
+            TokenKind::ProcedureEnd => ')',
+            TokenKind::ProcedureCall => ':',
+            TokenKind::Fork => 'Y',
+        }
+    }
+
+    /// The instruction for a token that stands on its own, i.e. not a bracket.
+    pub fn instruction(self) -> Option<BFInstruction> {
+        BFInstruction::parse_char(self.to_char())
+    }
+}
+
+/// Commands beyond plain brainfuck, picked on the command line
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Extensions {
+    /// pbrain's `(`, `)` and `:`
+    pub pbrain: bool,
+    /// Brainfork's `Y`
+    pub brainfork: bool,
+}
+
+impl Extensions {
+    /// Whether `kind` is plain brainfuck or from an enabled extension
+    pub fn allows(self, kind: TokenKind) -> bool {
+        match kind {
+            TokenKind::ProcedureStart | TokenKind::ProcedureEnd | TokenKind::ProcedureCall => {
+                self.pbrain
+            }
+            TokenKind::Fork => self.brainfork,
+            _ => true,
+        }
+    }
+}
+
+/// Which lexer to use, as picked on the command line
+#[derive(Debug, Clone)]
+pub enum Dialect {
+    Brainfuck,
+    Ook,
+    Blub,
+    TokenMap(PathBuf),
+}
+
+impl Dialect {
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "brainfuck" | "bf" => Some(Dialect::Brainfuck),
+            "ook" => Some(Dialect::Ook),
+            "blub" => Some(Dialect::Blub),
+            _ => None,
+        }
+    }
+
+    /// `extensions` enables extra commands where the dialect has a spelling
+    /// for them, token maps can always use them.
+    pub fn lexer(&self, extensions: Extensions) -> Result<Box<dyn Lexer>, String> {
+        Ok(match self {
+            Dialect::Brainfuck => Box::new(Brainfuck { extensions }),
+            Dialect::Ook => Box::new(WordPairs::OOK),
+            Dialect::Blub => Box::new(WordPairs::BLUB),
+            Dialect::TokenMap(path) => Box::new(TokenMap::load(path)?),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    /// Byte range in the source the token was read from
+    pub span: Range<usize>,
+}
+
+/// Turns source text into brainfuck tokens, skipping anything that isn't one.
+pub trait Lexer {
+    fn tokenize(&self, source: &str) -> Vec<Token>;
+
+    /// Describes the dialect, different dialects must give different descriptions
+    /// since this ends up in the cache key.
+    fn describe(&self) -> String;
+}
+
+/// Splits off the program input that follows the first `!` outside of any
+/// loop or procedure, a common convention for bundling input with a program.
+/// A `!` that's part of a token, as in Ook!, doesn't count.
+pub fn split_embedded_input<'a>(lexer: &dyn Lexer, source: &'a str) -> (&'a str, Option<&'a str>) {
+    let mut tokens = lexer.tokenize(source).into_iter().peekable();
+    let mut depth = 0i64;
+    for (i, c) in source.char_indices() {
+        while let Some(token) = tokens.next_if(|t| t.span.end <= i) {
+            match token.kind {
+                TokenKind::LoopStart | TokenKind::ProcedureStart => depth += 1,
+                TokenKind::LoopEnd | TokenKind::ProcedureEnd => depth -= 1,
+                _ => {}
+            }
+        }
+        let in_token = tokens.peek().is_some_and(|t| t.span.start <= i);
+        if c == '!' && depth == 0 && !in_token {
+            return (&source[..i], Some(&source[i + 1..]));
+        }
+    }
+    (source, None)
+}
+
+/// Plain brainfuck, one character per command
+#[derive(Default)]
+pub struct Brainfuck {
+    pub extensions: Extensions,
+}
+
+impl Lexer for Brainfuck {
+    fn tokenize(&self, source: &str) -> Vec<Token> {
+        source
+            .char_indices()
+            .filter_map(|(i, c)| {
+                TokenKind::from_char(c)
+                    .filter(|k| self.extensions.allows(*k))
+                    .map(|kind| Token {
+                        kind,
+                        span: i..i + c.len_utf8(),
+                    })
+            })
+            .collect()
+    }
+
+    fn describe(&self) -> String {
+        let mut s = "brainfuck".to_string();
+        if self.extensions.pbrain {
+            s.push_str("+pbrain");
+        }
+        if self.extensions.brainfork {
+            s.push_str("+brainfork");
+        }
+        s
+    }
+}
+
+/// Dialects like Ook! and Blub where every command is a pair of words made
+/// of the same stem followed by `.`, `?` or `!`, e.g. `Ook. Ook?` for `>`.
+pub struct WordPairs {
+    stem: &'static str,
+}
+
+impl WordPairs {
+    pub const OOK: WordPairs = WordPairs { stem: "Ook" };
+    pub const BLUB: WordPairs = WordPairs { stem: "Blub" };
+
+    fn pair(a: char, b: char) -> Option<TokenKind> {
+        match (a, b) {
+            ('.', '?') => Some(TokenKind::DataPtrIncrement),
+            ('?', '.') => Some(TokenKind::DataPtrDecrement),
+            ('.', '.') => Some(TokenKind::DataValueIncrement),
+            ('!', '!') => Some(TokenKind::DataValueDecrement),
+            ('!', '.') => Some(TokenKind::DataValuePutchar),
+            ('.', '!') => Some(TokenKind::DataValueScanchar),
+            ('!', '?') => Some(TokenKind::LoopStart),
+            ('?', '!') => Some(TokenKind::LoopEnd),
+            _ => None,
+        }
+    }
+}
+
+impl Lexer for WordPairs {
+    fn tokenize(&self, source: &str) -> Vec<Token> {
+        // find every word first, then pair them up in order
+        let mut words = Vec::new();
+        let mut start = 0;
+        while let Some(i) = source[start..].find(self.stem) {
+            let at = start + i;
+            let end = at + self.stem.len();
+            match source[end..].chars().next() {
+                Some(p @ ('.' | '?' | '!')) => {
+                    words.push((at, p));
+                    start = end + 1;
+                }
+                _ => start = end,
+            }
+        }
+        words
+            .chunks_exact(2)
+            .filter_map(|w| {
+                Self::pair(w[0].1, w[1].1).map(|kind| Token {
+                    kind,
+                    span: w[0].0..w[1].0 + self.stem.len() + 1,
+                })
+            })
+            .collect()
+    }
+
+    fn describe(&self) -> String {
+        self.stem.to_lowercase()
+    }
+}
+
+/// User defined spellings for the commands, read from a TOML file like
+///
+/// ```toml
+/// [tokens]
+/// "meow" = "+"
+/// "purr" = "-"
+/// ```
+///
+/// Where spellings overlap the longest one wins.
+pub struct TokenMap {
+    // longest first
+    tokens: Vec<(String, TokenKind)>,
+}
+
+impl TokenMap {
+    pub fn new(tokens: HashMap<String, TokenKind>) -> Self {
+        let mut tokens: Vec<_> = tokens.into_iter().collect();
+        tokens.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+        Self { tokens }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        Self::from_toml(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        let value: toml::Value = text.parse().map_err(|e| format!("{}", e))?;
+        let table = value
+            .get("tokens")
+            .and_then(toml::Value::as_table)
+            .ok_or("expected a [tokens] table")?;
+        let mut tokens = HashMap::new();
+        for (spelling, command) in table {
+            if spelling.is_empty() {
+                return Err("tokens can't be empty".to_string());
+            }
+            let mut chars = command.as_str().unwrap_or("").chars();
+            let kind = match (chars.next().and_then(TokenKind::from_char), chars.next()) {
+                (Some(kind), None) => kind,
+                _ => {
+                    return Err(format!(
+                        "`{}` must map to one of > < + - . , [ ] ( ) : Y, not {}",
+                        spelling, command
+                    ))
+                }
+            };
+            tokens.insert(spelling.clone(), kind);
+        }
+        Ok(Self::new(tokens))
+    }
+}
+
+impl Lexer for TokenMap {
+    fn tokenize(&self, source: &str) -> Vec<Token> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < source.len() {
+            let rest = &source[i..];
+            match self
+                .tokens
+                .iter()
+                .find(|(s, _)| rest.starts_with(s.as_str()))
+            {
+                Some((s, kind)) => {
+                    out.push(Token {
+                        kind: *kind,
+                        span: i..i + s.len(),
+                    });
+                    i += s.len();
+                }
+                None => i += rest.chars().next().unwrap().len_utf8(),
+            }
+        }
+        out
+    }
+
+    fn describe(&self) -> String {
+        let pairs: Vec<_> = self
+            .tokens
+            .iter()
+            .map(|(s, k)| format!("{:?}={}", s, k.to_char()))
+            .collect();
+        format!("tokens {}", pairs.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TokenKind::*;
+
+    fn kinds(lexer: &dyn Lexer, source: &str) -> Vec<TokenKind> {
+        lexer.tokenize(source).into_iter().map(|t| t.kind).collect()
+    }
+
+    #[test]
+    fn brainfuck_extensions() {
+        let source = "+(-):Y x";
+        assert_eq!(
+            kinds(&Brainfuck::default(), source),
+            [DataValueIncrement, DataValueDecrement]
+        );
+        let all = Brainfuck {
+            extensions: Extensions {
+                pbrain: true,
+                brainfork: true,
+            },
+        };
+        assert_eq!(
+            kinds(&all, source),
+            [
+                DataValueIncrement,
+                ProcedureStart,
+                DataValueDecrement,
+                ProcedureEnd,
+                ProcedureCall,
+                Fork
+            ]
+        );
+        assert_ne!(all.describe(), Brainfuck::default().describe());
+    }
+
+    #[test]
+    fn ook_pairs() {
+        let source = "Ook. Ook? Ook? Ook. Ook. Ook. Ook! Ook! \
+                      Ook! Ook. Ook. Ook! Ook! Ook? Ook? Ook!";
+        assert_eq!(
+            kinds(&WordPairs::OOK, source),
+            [
+                DataPtrIncrement,
+                DataPtrDecrement,
+                DataValueIncrement,
+                DataValueDecrement,
+                DataValuePutchar,
+                DataValueScanchar,
+                LoopStart,
+                LoopEnd
+            ]
+        );
+        let tokens = WordPairs::OOK.tokenize("x Ook.\nOok? y");
+        assert_eq!(tokens[0].span, 2..11);
+    }
+
+    #[test]
+    fn ook_skips_unknown_words() {
+        // no punctuation after the stem, another stem, and a pair that isn't a command
+        assert_eq!(
+            kinds(&WordPairs::OOK, "Ookk Oak. Ook. Ook? Ook? Ook? Ook. Ook."),
+            [DataPtrIncrement, DataValueIncrement]
+        );
+        // a word without a partner at the end
+        assert_eq!(
+            kinds(&WordPairs::OOK, "Ook. Ook. Ook!"),
+            [DataValueIncrement]
+        );
+        assert_eq!(kinds(&WordPairs::OOK, "Blub. Blub."), []);
+    }
+
+    #[test]
+    fn blub_pairs() {
+        assert_eq!(
+            kinds(&WordPairs::BLUB, "Blub! Blub? Blub. Blub. Blub? Blub!"),
+            [LoopStart, DataValueIncrement, LoopEnd]
+        );
+        assert_ne!(WordPairs::OOK.describe(), WordPairs::BLUB.describe());
+    }
+
+    #[test]
+    fn token_map_longest_wins() {
+        let map = TokenMap::from_toml(
+            r#"
+            [tokens]
+            "m" = ">"
+            "meow" = "+"
+            "meowmeow" = "["
+            "purr" = "]"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            kinds(&map, "meowmeow meow m purr mew"),
+            [
+                LoopStart,
+                DataValueIncrement,
+                DataPtrIncrement,
+                LoopEnd,
+                DataPtrIncrement
+            ]
+        );
+        let tokens = map.tokenize("é meow");
+        assert_eq!(tokens[0].span, 3..7);
+    }
+
+    #[test]
+    fn token_map_errors() {
+        for (toml, error) in [
+            ("[tokens", "expected"),
+            ("tokens = 1", "expected a [tokens] table"),
+            ("[other]\n\"a\" = \"+\"", "expected a [tokens] table"),
+            ("[tokens]\n\"\" = \"+\"", "tokens can't be empty"),
+            ("[tokens]\n\"a\" = \"++\"", "`a` must map to one of"),
+            ("[tokens]\n\"a\" = \"x\"", "`a` must map to one of"),
+            ("[tokens]\n\"a\" = 1", "`a` must map to one of"),
+            ("[tokens]\n\"a\" = \"\"", "`a` must map to one of"),
+            ("[tokens]\n\"a\" = \"+\"\n\"a\" = \"-\"", "duplicate key"),
+        ] {
+            match TokenMap::from_toml(toml) {
+                Ok(_) => panic!("{:?} was accepted", toml),
+                Err(e) => assert!(e.contains(error), "{:?} gave {:?}", toml, e),
+            }
+        }
+    }
+
+    #[test]
+    fn token_maps_describe_their_spellings() {
+        let a = TokenMap::from_toml("[tokens]\n\"a\" = \"+\"").unwrap();
+        let b = TokenMap::from_toml("[tokens]\n\"a\" = \"-\"").unwrap();
+        assert_ne!(a.describe(), b.describe());
+    }
+
+    #[test]
+    fn embedded_input() {
+        let bf = Brainfuck::default();
+        assert_eq!(
+            split_embedded_input(&bf, "+[!]!in!put"),
+            ("+[!]", Some("in!put"))
+        );
+        assert_eq!(split_embedded_input(&bf, "+[-]"), ("+[-]", None));
+        // the `!` in Ook! words is part of a token
+        let ook = "Ook! Ook! Ook. Ook! !x";
+        assert_eq!(
+            split_embedded_input(&WordPairs::OOK, ook),
+            ("Ook! Ook! Ook. Ook! ", Some("x"))
+        );
+    }
+}
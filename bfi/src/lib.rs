@@ -0,0 +1,1594 @@
+pub mod bytecode;
+pub mod cache;
+pub mod cli;
+pub mod coverage;
+pub mod dump;
+pub mod fmt;
+pub mod interp;
+pub mod lexer;
+pub mod lint;
+mod outline;
+mod range;
+pub mod repl;
+pub mod replay;
+pub mod report;
+pub mod runtime;
+pub mod signal;
+pub mod snapshot;
+mod symbols;
+pub mod tiered;
+pub mod unroll;
+pub mod vm;
+
+use std::{
+    collections::HashMap,
+    io::{stdout, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use cranelift::{
+    codegen::{
+        binemit::{Addend, CodeOffset, Reloc, RelocSink},
+        ir::{self, AtomicRmwOp, FuncRef, Function, SigRef},
+        isa::TargetIsa,
+    },
+    frontend::{FunctionBuilder, FunctionBuilderContext},
+    prelude::*,
+};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{FuncId, Linkage, Module, RelocRecord};
+
+use dump::{Dumper, Stage};
+use lexer::{Brainfuck, Lexer, Token, TokenKind};
+use range::{Ranges, Window};
+
+/// Bits per cell. Cells are always stored as u64, narrower ones are kept masked
+/// to their width so they wrap around like the real thing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellWidth(u32);
+
+impl CellWidth {
+    pub const ALL: [CellWidth; 4] = [CellWidth(8), CellWidth(16), CellWidth(32), CellWidth(64)];
+
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        Self::ALL.iter().copied().find(|w| w.0 == bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn mask(self) -> u64 {
+        u64::MAX >> (64 - self.0)
+    }
+}
+
+impl Default for CellWidth {
+    fn default() -> Self {
+        CellWidth(64)
+    }
+}
+
+pub struct JIT {
+    builder_context: FunctionBuilderContext,
+    ctx: codegen::Context,
+    //data_ctx: DataContext,
+    module: JITModule,
+    dumper: Dumper,
+    /// Check that the pointer stays on the tape, see `runtime::set_tape`
+    checked: bool,
+    bounds_checks: usize,
+    cell_width: CellWidth,
+    /// Compile loops that appear more than once as shared functions, see `outline`
+    outline: bool,
+    outlined: usize,
+    /// Split instruction sequences longer than this into chunks, 0 for never
+    chunk_len: u64,
+    chunks: usize,
+    /// Threads to compile functions on
+    threads: usize,
+    code_size: usize,
+    /// Count how often loops and procedures run, see `coverage`
+    coverage: bool,
+    counters: Option<Arc<coverage::Counters>>,
+    /// Stop loops once `runtime::interrupt` is called, see `set_interruptible`
+    interruptible: bool,
+    /// Also list compiled functions in `/tmp/perf-<pid>.map`, see `symbols`
+    perf_map: bool,
+    /// Functions defined since the last `finalize_definitions`, with their size
+    unfinalized: Vec<(String, FuncId, usize)>,
+    /// Code registered with gdb, unregistered when it's freed
+    registrations: Vec<symbols::Registration>,
+    /// Time spent building Cranelift IR and compiling it, over every function so far
+    translate_time: Duration,
+    compile_time: Duration,
+}
+
+impl Default for JIT {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JIT {
+    pub fn new() -> Self {
+        Self::with_dumper(Dumper::default())
+    }
+
+    pub fn with_dumper(dumper: Dumper) -> Self {
+        let mut flag_builder = settings::builder();
+        // On at least AArch64, "colocated" calls use shorter-range relocations,
+        // which might not reach all definitions; we can't handle that here, so
+        // we require long-range relocation types.
+        flag_builder.set("use_colocated_libcalls", "false").unwrap();
+        flag_builder.set("is_pic", "true").unwrap();
+        // flag_builder.set("opt_level", "speed_and_size").unwrap();
+        flag_builder.set("opt_level", "none").unwrap();
+        flag_builder.set("enable_verifier", "false").unwrap();
+        let isa_builder = cranelift_native::builder().unwrap_or_else(|msg| {
+            panic!("host machine is not supported: {}", msg);
+        });
+        let isa = isa_builder.finish(settings::Flags::new(flag_builder));
+        let mut builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+        builder.symbols(runtime::symbols());
+        // let builder = JITBuilder::new(cranelift_module::default_libcall_names());
+        let module = JITModule::new(builder);
+        Self {
+            builder_context: FunctionBuilderContext::new(),
+            ctx: module.make_context(),
+            //data_ctx: DataContext::new(),
+            module,
+            dumper,
+            checked: false,
+            bounds_checks: 0,
+            cell_width: CellWidth::default(),
+            outline: true,
+            outlined: 0,
+            chunk_len: 0,
+            chunks: 0,
+            threads: 1,
+            code_size: 0,
+            coverage: false,
+            counters: None,
+            interruptible: false,
+            perf_map: false,
+            unfinalized: Vec::new(),
+            registrations: Vec::new(),
+            translate_time: Duration::ZERO,
+            compile_time: Duration::ZERO,
+        }
+    }
+
+    pub fn set_cell_width(&mut self, width: CellWidth) {
+        self.cell_width = width;
+    }
+
+    pub fn cell_width(&self) -> CellWidth {
+        self.cell_width
+    }
+
+    pub fn set_outline(&mut self, outline: bool) {
+        self.outline = outline;
+    }
+
+    /// Number of loops compiled into shared functions so far
+    pub fn outlined_loops(&self) -> usize {
+        self.outlined
+    }
+
+    pub fn set_chunk_len(&mut self, len: u64) {
+        self.chunk_len = len;
+    }
+
+    /// Number of chunks compiled into functions of their own so far
+    pub fn chunks(&self) -> usize {
+        self.chunks
+    }
+
+    /// Compiles independent functions on up to `threads` threads at once.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    /// Bytes of machine code generated so far
+    pub fn code_size(&self) -> usize {
+        self.code_size
+    }
+
+    /// Counts how often every loop and procedure in the program passed to
+    /// `jit` runs. Turns outlining off, each loop needs counters of its own.
+    pub fn set_coverage(&mut self, coverage: bool) {
+        self.coverage = coverage;
+    }
+
+    /// The coverage counters, once the program is compiled
+    pub fn counters(&self) -> Option<&Arc<coverage::Counters>> {
+        self.counters.as_ref()
+    }
+
+    /// Has every loop with a loop or procedure call in it check
+    /// `runtime::INTERRUPTED` on each iteration and hand its data pointer to
+    /// `runtime::interrupted` once it's set.
+    pub fn set_interruptible(&mut self, interruptible: bool) {
+        self.interruptible = interruptible;
+    }
+
+    pub fn set_perf_map(&mut self, perf_map: bool) {
+        self.perf_map = perf_map;
+    }
+
+    pub fn translate_time(&self) -> Duration {
+        self.translate_time
+    }
+
+    pub fn compile_time(&self) -> Duration {
+        self.compile_time
+    }
+
+    pub fn set_checked(&mut self, checked: bool) {
+        self.checked = checked;
+    }
+
+    /// Number of bounds checks emitted so far in checked mode
+    pub fn bounds_checks(&self) -> usize {
+        self.bounds_checks
+    }
+
+    pub fn translate(&mut self, insns: &[OptimizedBFInstruction]) {
+        self.translate_function("bf", insns, FunctionKind::Program);
+    }
+
+    /// Translates `insns` into `self.ctx`, after compiling every pbrain
+    /// procedure defined in them, every repeated loop and every chunk into a
+    /// function of its own.
+    fn translate_function(
+        &mut self,
+        name: &str,
+        insns: &[OptimizedBFInstruction],
+        kind: FunctionKind,
+    ) {
+        let mut bodies = Vec::new();
+        OptimizedBFInstruction::collect_procedures(insns, &mut bodies);
+        let int = self.module.target_config().pointer_type();
+        let mut sig = self.module.make_signature();
+        sig.params.push(AbiParam::new(int));
+        sig.returns.push(AbiParam::new(int));
+        let declare = |module: &mut JITModule, name: String| {
+            module
+                .declare_function(&name, Linkage::Export, &sig)
+                .unwrap()
+        };
+        // declare them all first since procedures can define other procedures
+        let mut procs = HashMap::new();
+        for (i, body) in bodies.iter().enumerate() {
+            let id = declare(&mut self.module, format!("{}_proc{}", name, i));
+            procs.insert(*body as *const _, id);
+        }
+        let outlines = if self.outline && self.counters.is_none() {
+            outline::find(insns)
+        } else {
+            outline::Outlines::default()
+        };
+        // forked threads resume in the function that forked, so those programs stay in one piece
+        let chunks = if self.chunk_len > 0 && OptimizedBFInstruction::count_forks(insns) == 0 {
+            outline::chunks(insns, self.chunk_len, &outlines)
+        } else {
+            Vec::new()
+        };
+        let shared: Vec<_> = (0..outlines.shared.len())
+            .map(|i| declare(&mut self.module, format!("{}_shared{}", name, i)))
+            .collect();
+        let mut calls = Calls::default();
+        for (site, i) in &outlines.sites {
+            calls.shared.insert(*site, shared[*i]);
+        }
+        for (i, chunk) in chunks.iter().enumerate() {
+            let id = declare(&mut self.module, format!("{}_chunk{}", name, i));
+            calls.chunks.insert(chunk.as_ptr(), (chunk.len(), id));
+        }
+
+        let mut functions = Vec::new();
+        for (i, body) in bodies.iter().enumerate() {
+            let counter = self.counters.as_ref().map(|c| c.procedure_calls(i));
+            self.translate_body(body, FunctionKind::Fragment, &procs, &calls, None, counter);
+            functions.push((format!("{}_proc{}", name, i), self.take_function()));
+        }
+        for (i, insn) in outlines.shared.iter().enumerate() {
+            let insn = std::slice::from_ref(*insn);
+            let root = Some(&insn[0] as *const _);
+            self.translate_body(insn, FunctionKind::Fragment, &procs, &calls, root, None);
+            functions.push((format!("{}_shared{}", name, i), self.take_function()));
+        }
+        for (i, chunk) in chunks.iter().enumerate() {
+            self.translate_body(
+                chunk,
+                FunctionKind::Fragment,
+                &procs,
+                &calls,
+                Some(&chunk[0]),
+                None,
+            );
+            functions.push((format!("{}_chunk{}", name, i), self.take_function()));
+        }
+        self.outlined += outlines.shared.len();
+        self.chunks += chunks.len();
+        self.compile_functions(functions);
+        self.translate_body(insns, kind, &procs, &calls, None, None);
+    }
+
+    /// `calls` is the code to call instead of translating, except for `root`,
+    /// the loop or chunk this function is compiled for. `counter` counts how
+    /// often the function is called, for coverage.
+    fn translate_body(
+        &mut self,
+        insns: &[OptimizedBFInstruction],
+        kind: FunctionKind,
+        procs: &HashMap<*const [OptimizedBFInstruction], FuncId>,
+        calls: &Calls,
+        root: Option<*const OptimizedBFInstruction>,
+        counter: Option<usize>,
+    ) {
+        let timer = Instant::now();
+        // i64
+        let int = self.module.target_config().pointer_type();
+        // forking programs also take where to resume, see `runtime::ForkFunction`
+        let forks = match kind {
+            FunctionKind::Program => OptimizedBFInstruction::count_forks(insns),
+            FunctionKind::Fragment => 0,
+        };
+
+        // take in data ptr
+        self.ctx.func.signature.params.push(AbiParam::new(int));
+        if forks > 0 {
+            self.ctx.func.signature.params.push(AbiParam::new(int));
+        }
+        self.ctx.func.signature.returns.push(AbiParam::new(int));
+
+        let mut builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_context);
+
+        // declare putchar
+        let mut putchar_sig = self.module.make_signature();
+        putchar_sig.params.push(AbiParam::new(int));
+        // putchar_sig.returns.push(AbiParam::new(int));
+        let putchar_id = self
+            .module
+            .declare_function("bf_write_output", Linkage::Import, &putchar_sig)
+            .unwrap();
+        let putchar = self.module.declare_func_in_func(putchar_id, builder.func);
+        // let putchar_sigr = builder.import_signature(putchar_sig);
+        // let putchar_extfd = ExtFuncData {
+        //     name: ExternalName::user(0, 0),
+        //     signature: putchar_sigr,
+        //     colocated: false,
+        // };
+        // let putchar = builder.import_function(putchar_extfd);
+
+        // // declare scanchar
+        // let mut scanchar_sig = self.module.make_signature();
+        // // scanchar_sig.params.push(AbiParam::new(int));
+        // scanchar_sig.returns.push(AbiParam::new(int));
+        // let scanchar_id = self
+        //     .module
+        //     .declare_function("scanchar", Linkage::Import, &scanchar_sig)
+        //     .unwrap();
+        // let scanchar = self
+        //     .module
+        //     .declare_func_in_func(scanchar_id, &mut builder.func);
+        let mut getchar_sig = self.module.make_signature();
+        getchar_sig.params.push(AbiParam::new(int));
+        getchar_sig.returns.push(AbiParam::new(int));
+        let getchar_id = self
+            .module
+            .declare_function("bf_read_input", Linkage::Import, &getchar_sig)
+            .unwrap();
+        let getchar = self.module.declare_func_in_func(getchar_id, builder.func);
+        let mut procedure_error_sig = self.module.make_signature();
+        procedure_error_sig.params.push(AbiParam::new(int));
+        let procedure_error_id = self
+            .module
+            .declare_function("bf_procedure_error", Linkage::Import, &procedure_error_sig)
+            .unwrap();
+        let procedure_error = self
+            .module
+            .declare_func_in_func(procedure_error_id, builder.func);
+        let mut procedures = HashMap::new();
+        for (body, id) in procs {
+            procedures.insert(*body, self.module.declare_func_in_func(*id, builder.func));
+        }
+        let mut bounds_error_sig = self.module.make_signature();
+        bounds_error_sig.params.push(AbiParam::new(int));
+        bounds_error_sig.params.push(AbiParam::new(int));
+        let bounds_error_id = self
+            .module
+            .declare_function("bf_bounds_error", Linkage::Import, &bounds_error_sig)
+            .unwrap();
+        let bounds_error = self
+            .module
+            .declare_func_in_func(bounds_error_id, builder.func);
+        let mut interrupted_sig = self.module.make_signature();
+        interrupted_sig.params.push(AbiParam::new(int));
+        let interrupted_id = self
+            .module
+            .declare_function("bf_interrupted", Linkage::Import, &interrupted_sig)
+            .unwrap();
+        let interrupted = self
+            .module
+            .declare_func_in_func(interrupted_id, builder.func);
+        let mut fork_sig = self.module.make_signature();
+        fork_sig.params.push(AbiParam::new(int));
+        fork_sig.params.push(AbiParam::new(int));
+        let fork_id = self
+            .module
+            .declare_function("bf_fork", Linkage::Import, &fork_sig)
+            .unwrap();
+        let fork = self.module.declare_func_in_func(fork_id, builder.func);
+        let mut procedure_sig = self.module.make_signature();
+        procedure_sig.params.push(AbiParam::new(int));
+        procedure_sig.returns.push(AbiParam::new(int));
+        let procedure_sig = builder.import_signature(procedure_sig);
+        // let scanchar_sigr = builder.import_signature(scanchar_sig);
+        // let scanchar_extfd = ExtFuncData {
+        //     name: ExternalName::user(0, 1),
+        //     signature: scanchar_sigr,
+        //     colocated: false,
+        // };
+        // let scanchar = builder.import_function(scanchar_extfd);
+
+        let entry_block = builder.create_block();
+        builder.append_block_params_for_function_params(entry_block);
+        builder.switch_to_block(entry_block);
+        builder.seal_block(entry_block);
+        let entry_ptr = builder.block_params(entry_block)[0];
+
+        // forked threads enter through the same function, then jump to just
+        // after the `Y` that started them
+        let resume_blocks: Vec<_> = (0..forks).map(|_| builder.create_block()).collect();
+        if forks > 0 {
+            let start_block = builder.create_block();
+            let mut table = JumpTableData::new();
+            table.push_entry(start_block);
+            for block in &resume_blocks {
+                table.push_entry(*block);
+            }
+            let table = builder.create_jump_table(table);
+            let resume = builder.block_params(entry_block)[1];
+            // x64 only lowers br_table on 32 bit indices
+            let resume = builder.ins().ireduce(types::I32, resume);
+            builder.ins().br_table(resume, start_block, table);
+            builder.switch_to_block(start_block);
+            builder.seal_block(start_block);
+            for block in &resume_blocks {
+                builder.seal_block(*block);
+            }
+        }
+
+        // let mut data_ptr =;
+        let data_ptr = Variable::new(0);
+        builder.declare_var(data_ptr, int);
+        builder.def_var(data_ptr, entry_ptr);
+
+        let mut trans = Translator {
+            // plusone: builder.ins().iconst(int, 1),
+            // minusone: builder.ins().iconst(int, -1),
+            // /** i64's are EIGHT BYTES */
+            // rightone: builder.ins().iconst(int, 8),
+            // leftone: builder.ins().iconst(int, -8),
+            builder,
+            int,
+            putchar,
+            // scanchar,
+            getchar,
+            data_ptr,
+            //module: &mut self.module,
+            procedures,
+            module: &mut self.module,
+            calls,
+            root,
+            imported: HashMap::new(),
+            procedure_sig,
+            procedure_error,
+            fork,
+            entry_ptr,
+            resume_blocks,
+            forks: 0,
+            checked: self.checked,
+            check_mode: self.checked,
+            touching: true,
+            ranges: Ranges::new(insns),
+            bounds_error,
+            bounds_checks: 0,
+            mask: self.cell_width.mask(),
+            counters: self.counters.as_deref(),
+            interruptible: self.interruptible,
+            interrupted,
+        };
+
+        if let Some(counter) = counter {
+            trans.count(counter);
+        }
+        trans.translate_insns(insns);
+        self.bounds_checks += trans.bounds_checks;
+
+        // emit the return
+        let v2 = trans.builder.use_var(data_ptr);
+        trans.builder.ins().return_(&[v2]);
+        trans.builder.finalize();
+        self.translate_time += timer.elapsed();
+    }
+
+    /// Frees the memory of every function compiled so far.
+    ///
+    /// # Safety
+    /// None of them may run afterwards.
+    pub unsafe fn free_memory(self) {
+        for r in self.registrations {
+            symbols::unregister(r);
+        }
+        self.module.free_memory();
+    }
+
+    pub fn jit(&mut self, insns: &[OptimizedBFInstruction]) -> *const u8 {
+        if self.coverage {
+            self.counters = Some(Arc::new(coverage::Counters::new(insns)));
+        }
+        self.translate(insns);
+        self.define("bf")
+    }
+
+    /// Compiles part of a program, like a single loop or a REPL line, into its
+    /// own function that takes the data pointer and returns where it ended up.
+    pub fn jit_fragment(
+        &mut self,
+        name: &str,
+        insns: &[OptimizedBFInstruction],
+    ) -> FragmentFunction {
+        self.translate_function(name, insns, FunctionKind::Fragment);
+        let ptr = self.define(name);
+        unsafe { std::mem::transmute::<*const u8, FragmentFunction>(ptr) }
+    }
+
+    /// Compiles the function just translated and returns its address.
+    fn define(&mut self, name: &str) -> *const u8 {
+        let func = self.take_function();
+        let id = self.compile_functions(vec![(name.to_string(), func)])[0];
+        self.module.finalize_definitions();
+        self.publish_symbols();
+        self.module.get_finalized_function(id)
+    }
+
+    /// Tells gdb, and perf if asked to, about the functions just finalized.
+    fn publish_symbols(&mut self) {
+        let symbols: Vec<_> = std::mem::take(&mut self.unfinalized)
+            .into_iter()
+            .map(|(name, id, size)| symbols::Symbol {
+                name,
+                addr: self.module.get_finalized_function(id) as usize,
+                size,
+            })
+            .collect();
+        if self.perf_map {
+            symbols::write_perf_map(&symbols);
+        }
+        self.registrations.extend(symbols::register(&symbols));
+    }
+
+    /// Takes the function just translated out of `self.ctx`.
+    fn take_function(&mut self) -> Function {
+        let func = std::mem::replace(&mut self.ctx.func, Function::new());
+        self.module.clear_context(&mut self.ctx);
+        func
+    }
+
+    /// Compiles `functions` on up to `self.threads` threads and defines them
+    /// in the module, returning their ids.
+    fn compile_functions(&mut self, functions: Vec<(String, Function)>) -> Vec<FuncId> {
+        for (name, func) in &functions {
+            self.dumper
+                .dump(
+                    Stage::Clif,
+                    &format!("clif{}.clif", dump_suffix(name)),
+                    || func.display(self.module.isa()).to_string(),
+                )
+                .unwrap_or_else(|e| dump::fail(e));
+        }
+        let timer = Instant::now();
+        let isa = self.module.isa();
+        let want_disasm = self.dumper.wants(Stage::Asm);
+        let threads = self.threads.min(functions.len());
+        let compiled: Vec<_> = if threads <= 1 {
+            functions
+                .into_iter()
+                .map(|(name, func)| (name, compile(isa, func, want_disasm)))
+                .collect()
+        } else {
+            // each worker takes the next function left until there are none
+            let count = functions.len();
+            let queue: Vec<_> = functions.into_iter().map(|f| Mutex::new(Some(f))).collect();
+            let next = AtomicUsize::new(0);
+            let mut done: Vec<_> = std::thread::scope(|s| {
+                let workers: Vec<_> = (0..threads)
+                    .map(|_| {
+                        s.spawn(|| {
+                            let mut done = Vec::new();
+                            loop {
+                                let i = next.fetch_add(1, Ordering::Relaxed);
+                                if i >= count {
+                                    return done;
+                                }
+                                let (name, func) = queue[i].lock().unwrap().take().unwrap();
+                                done.push((i, name, compile(isa, func, want_disasm)));
+                            }
+                        })
+                    })
+                    .collect();
+                workers
+                    .into_iter()
+                    .flat_map(|w| w.join().unwrap())
+                    .collect()
+            });
+            done.sort_by_key(|(i, _, _)| *i);
+            done.into_iter().map(|(_, name, c)| (name, c)).collect()
+        };
+
+        let mut ids = Vec::new();
+        for (name, c) in compiled {
+            let suffix = dump_suffix(&name);
+            self.dumper
+                .dump(Stage::ClifOpt, &format!("clif-opt{}.clif", suffix), || {
+                    c.func.display(self.module.isa()).to_string()
+                })
+                .unwrap_or_else(|e| dump::fail(e));
+            self.dumper
+                .dump(Stage::Asm, &format!("asm{}.s", suffix), || {
+                    c.disasm.clone().unwrap_or_else(|| {
+                        "; no disassembly available for this backend\n".to_string()
+                    })
+                })
+                .unwrap_or_else(|e| dump::fail(e));
+            let id = self
+                .module
+                .declare_function(&name, Linkage::Export, &c.func.signature)
+                .unwrap();
+            self.module
+                .define_function_bytes(id, &c.bytes, &c.relocs)
+                .unwrap();
+            self.code_size += c.bytes.len();
+            self.unfinalized.push((name, id, c.bytes.len()));
+            ids.push(id);
+        }
+        self.compile_time += timer.elapsed();
+        ids
+    }
+}
+
+/// The main function keeps the plain dump names, others get theirs appended.
+fn dump_suffix(name: &str) -> String {
+    if name == "bf" {
+        String::new()
+    } else {
+        format!("-{}", name)
+    }
+}
+
+/// Machine code for one function, not in the module yet
+struct Compiled {
+    /// The function after Cranelift's passes
+    func: Function,
+    bytes: Vec<u8>,
+    relocs: Vec<RelocRecord>,
+    disasm: Option<String>,
+}
+
+/// Runs Cranelift on `func`. Doesn't touch the module, so it can run on any thread.
+fn compile(isa: &dyn TargetIsa, func: Function, want_disasm: bool) -> Compiled {
+    let mut ctx = codegen::Context::for_function(func);
+    ctx.want_disasm = want_disasm;
+    let mut bytes = Vec::new();
+    let mut relocs = Relocs(Vec::new());
+    ctx.compile_and_emit(
+        isa,
+        &mut bytes,
+        &mut relocs,
+        &mut codegen::binemit::NullTrapSink {},
+        &mut codegen::binemit::NullStackMapSink {},
+    )
+    .unwrap_or_else(|e| {
+        println!("{:?}", e);
+        println!("{}", ctx.func.display(isa));
+        panic!()
+    });
+    Compiled {
+        disasm: ctx
+            .mach_compile_result
+            .as_ref()
+            .and_then(|r| r.disasm.clone()),
+        func: ctx.func,
+        bytes,
+        relocs: relocs.0,
+    }
+}
+
+/// Collects relocations for `define_function_bytes`, like the JIT module does
+/// for the functions it compiles itself.
+struct Relocs(Vec<RelocRecord>);
+
+impl RelocSink for Relocs {
+    fn reloc_external(
+        &mut self,
+        offset: CodeOffset,
+        _: ir::SourceLoc,
+        reloc: Reloc,
+        name: &ir::ExternalName,
+        addend: Addend,
+    ) {
+        self.0.push(RelocRecord {
+            offset,
+            reloc,
+            name: name.clone(),
+            addend,
+        });
+    }
+
+    // jump tables and constants are addressed relative to the code, nothing to do
+    fn reloc_jt(&mut self, _: CodeOffset, _: Reloc, _: ir::JumpTable) {}
+
+    fn reloc_constant(&mut self, _: CodeOffset, _: Reloc, _: ir::ConstantOffset) {}
+}
+
+/// Code compiled into functions of its own, which gets called instead of translated
+#[derive(Default)]
+struct Calls {
+    /// Repeated loops, keyed by address
+    shared: HashMap<*const OptimizedBFInstruction, FuncId>,
+    /// Chunks and their length, keyed by the address of their first instruction
+    chunks: HashMap<*const OptimizedBFInstruction, (usize, FuncId)>,
+}
+
+/// Either way the function returns the data pointer it stopped at.
+#[derive(Clone, Copy)]
+enum FunctionKind {
+    /// A whole program, which can fork
+    Program,
+    /// Part of a program, like a loop or a procedure
+    Fragment,
+}
+
+struct Translator<'a> {
+    int: Type,
+    builder: FunctionBuilder<'a>,
+    putchar: FuncRef,
+    // scanchar: FuncRef,
+    getchar: FuncRef,
+    data_ptr: Variable,
+    procedures: HashMap<*const [OptimizedBFInstruction], FuncRef>,
+    module: &'a mut JITModule,
+    calls: &'a Calls,
+    /// The loop or chunk being translated, which mustn't call itself
+    root: Option<*const OptimizedBFInstruction>,
+    /// Functions in `calls` declared in this one so far, there can be thousands
+    imported: HashMap<FuncId, FuncRef>,
+    procedure_sig: SigRef,
+    procedure_error: FuncRef,
+    fork: FuncRef,
+    entry_ptr: Value,
+    /// Where each `Y` continues in the thread it forks, in program order
+    resume_blocks: Vec<Block>,
+    forks: usize,
+    /// Emit bounds checks, turned off while translating code a check already covers
+    checked: bool,
+    /// Whether checked mode is on at all, for loops to check their body with
+    check_mode: bool,
+    /// Record the cells code touches for the report, turned off like `checked`
+    touching: bool,
+    ranges: Ranges,
+    bounds_error: FuncRef,
+    bounds_checks: usize,
+    /// `CellWidth::mask`, applied to every value stored in a cell
+    mask: u64,
+    counters: Option<&'a coverage::Counters>,
+    interruptible: bool,
+    interrupted: FuncRef,
+    // minusone: Value,
+    // plusone: Value,
+    // rightone: Value,
+    // leftone: Value,
+}
+
+impl<'a> Translator<'a> {
+    /// Translates `insns`. Every stretch of instructions with a known window
+    /// gets one bounds check in checked mode and one `touch` up front of what
+    /// it always accesses, instructions without one (loops that move the
+    /// pointer around, procedure calls, forks) check and touch for themselves.
+    /// Loop bodies that might not run are checked and touched by the loop,
+    /// see `body_window`.
+    fn translate_insns(&mut self, insns: &[OptimizedBFInstruction]) {
+        let mut rest = insns;
+        while let Some(insn) = rest.first() {
+            if let Some((len, id)) = self.chunk(insn) {
+                self.call(id);
+                rest = &rest[len..];
+                continue;
+            }
+            if !self.checked && !self.touching {
+                self.translate_insn(insn);
+                rest = &rest[1..];
+                continue;
+            }
+            let mut always = Window::EMPTY;
+            let mut n = 0;
+            while let Some(a) = rest
+                .get(n)
+                .filter(|i| self.chunk(i).is_none())
+                .and_then(|i| self.ranges.always(i))
+            {
+                always = always.then(a);
+                n += 1;
+            }
+            if let (Some((lo, hi)), true) = (always.accessed, self.checked) {
+                self.check_bounds(lo, hi);
+            }
+            if let (Some((lo, hi)), true) = (always.accessed, self.touching) {
+                self.touch(lo, hi);
+            }
+            let (checked, touching) = (self.checked, self.touching);
+            self.checked = false;
+            self.touching = false;
+            for insn in &rest[..n] {
+                self.translate_insn(insn);
+            }
+            self.checked = checked;
+            self.touching = touching;
+            if n == 0 {
+                self.translate_insn(insn);
+                n = 1;
+            }
+            rest = &rest[n..];
+        }
+    }
+
+    /// The chunk starting at `insn`, if there is one
+    fn chunk(&self, insn: &OptimizedBFInstruction) -> Option<(usize, FuncId)> {
+        let key = insn as *const _;
+        self.calls
+            .chunks
+            .get(&key)
+            .copied()
+            .filter(|_| Some(key) != self.root)
+    }
+
+    /// The window of what one iteration of a loop with `body` always
+    /// accesses, if it's known. Such a loop checks and touches it on the way
+    /// into the first iteration and, if it moves the pointer, touches it on
+    /// the way out of the last one, every iteration in between is in between.
+    fn body_window(&self, body: &[OptimizedBFInstruction]) -> Option<Window> {
+        body.iter()
+            .try_fold(Window::EMPTY, |w, insn| match self.chunk(insn) {
+                Some(_) => None,
+                None => Some(w.then(self.ranges.always(insn)?)),
+            })
+    }
+
+    /// The shared function for the loop `insn`, if it's repeated
+    fn shared(&self, insn: &OptimizedBFInstruction) -> Option<FuncId> {
+        let key = insn as *const _;
+        self.calls
+            .shared
+            .get(&key)
+            .copied()
+            .filter(|_| Some(key) != self.root)
+    }
+
+    /// Calls a function compiled from part of the program, which takes the
+    /// data pointer and returns where it ended up.
+    fn call(&mut self, id: FuncId) {
+        let f = match self.imported.get(&id) {
+            Some(f) => *f,
+            None => {
+                let f = self.module.declare_func_in_func(id, self.builder.func);
+                self.imported.insert(id, f);
+                f
+            }
+        };
+        let dptr = self.builder.use_var(self.data_ptr);
+        let c = self.builder.ins().call(f, &[dptr]);
+        let r = self.builder.inst_results(c)[0];
+        self.builder.def_var(self.data_ptr, r);
+    }
+
+    /// Adds one to the coverage counter at `addr`, atomically since forks
+    /// share the counters.
+    fn count(&mut self, addr: usize) {
+        let addr = self.builder.ins().iconst(self.int, addr as i64);
+        let one = self.builder.ins().iconst(types::I64, 1);
+        self.builder
+            .ins()
+            .atomic_rmw(types::I64, MemFlags::new(), AtomicRmwOp::Add, addr, one);
+    }
+
+    /// Bails out through `bf_interrupted` once `runtime::INTERRUPTED` is set.
+    fn check_interrupt(&mut self) {
+        let flag = self
+            .builder
+            .ins()
+            .iconst(self.int, runtime::INTERRUPTED.as_ptr() as i64);
+        let flag = self.builder.ins().load(types::I8, MemFlags::new(), flag, 0);
+        let stop_block = self.builder.create_block();
+        let go_block = self.builder.create_block();
+        self.builder.ins().brnz(flag, stop_block, &[]);
+        self.builder.ins().jump(go_block, &[]);
+
+        self.builder.switch_to_block(stop_block);
+        self.builder.seal_block(stop_block);
+        let dptr = self.builder.use_var(self.data_ptr);
+        self.builder.ins().call(self.interrupted, &[dptr]);
+        self.builder.ins().trap(TrapCode::UnreachableCodeReached);
+
+        self.builder.switch_to_block(go_block);
+        self.builder.seal_block(go_block);
+    }
+
+    /// Cuts `v` down to the cell width.
+    fn wrap(&mut self, v: Value) -> Value {
+        if self.mask == u64::MAX {
+            v
+        } else {
+            self.builder.ins().band_imm(v, self.mask as i64)
+        }
+    }
+
+    /// Bails out through `bf_bounds_error` unless the cells from `lo` to `hi`
+    /// relative to the data pointer are all on the tape.
+    fn check_bounds(&mut self, lo: i64, hi: i64) {
+        self.bounds_checks += 1;
+        let dptr = self.builder.use_var(self.data_ptr);
+        let lo = self.builder.ins().iadd_imm(dptr, 8 * lo);
+        let hi = self.builder.ins().iadd_imm(dptr, 8 * hi);
+        let start = self
+            .builder
+            .ins()
+            .iconst(self.int, runtime::TAPE_START.as_ptr() as i64);
+        let start = self.builder.ins().load(self.int, MemFlags::new(), start, 0);
+        let end = self
+            .builder
+            .ins()
+            .iconst(self.int, runtime::TAPE_END.as_ptr() as i64);
+        let end = self.builder.ins().load(self.int, MemFlags::new(), end, 0);
+
+        let error_block = self.builder.create_block();
+        let hi_block = self.builder.create_block();
+        let ok_block = self.builder.create_block();
+        let below = self.builder.ins().icmp(IntCC::UnsignedLessThan, lo, start);
+        self.builder.ins().brnz(below, error_block, &[]);
+        self.builder.ins().jump(hi_block, &[]);
+        self.builder.switch_to_block(hi_block);
+        self.builder.seal_block(hi_block);
+        let above = self
+            .builder
+            .ins()
+            .icmp(IntCC::UnsignedGreaterThanOrEqual, hi, end);
+        self.builder.ins().brnz(above, error_block, &[]);
+        self.builder.ins().jump(ok_block, &[]);
+
+        self.builder.switch_to_block(error_block);
+        self.builder.seal_block(error_block);
+        self.builder.ins().call(self.bounds_error, &[lo, hi]);
+        self.builder.ins().trap(TrapCode::HeapOutOfBounds);
+
+        self.builder.switch_to_block(ok_block);
+        self.builder.seal_block(ok_block);
+    }
+
+    /// Widens `runtime::TOUCHED_LO` and `TOUCHED_HI` to cover the cells from
+    /// `lo` to `hi` relative to the data pointer, like `runtime::touch`.
+    fn touch(&mut self, lo: i64, hi: i64) {
+        let dptr = self.builder.use_var(self.data_ptr);
+        let lo = self.builder.ins().iadd_imm(dptr, 8 * lo);
+        let hi = self.builder.ins().iadd_imm(dptr, 8 * hi);
+        let bounds = [
+            (
+                &runtime::TOUCHED_LO,
+                lo,
+                IntCC::UnsignedLessThan,
+                AtomicRmwOp::Umin,
+            ),
+            (
+                &runtime::TOUCHED_HI,
+                hi,
+                IntCC::UnsignedGreaterThan,
+                AtomicRmwOp::Umax,
+            ),
+        ];
+        for (bound, addr, cc, op) in bounds {
+            let bound = self.builder.ins().iconst(self.int, bound.as_ptr() as i64);
+            let old = self.builder.ins().load(self.int, MemFlags::new(), bound, 0);
+            let widen = self.builder.ins().icmp(cc, addr, old);
+            let widen_block = self.builder.create_block();
+            let done_block = self.builder.create_block();
+            self.builder.ins().brnz(widen, widen_block, &[]);
+            self.builder.ins().jump(done_block, &[]);
+
+            self.builder.switch_to_block(widen_block);
+            self.builder.seal_block(widen_block);
+            self.builder
+                .ins()
+                .atomic_rmw(self.int, MemFlags::new(), op, bound, addr);
+            self.builder.ins().jump(done_block, &[]);
+
+            self.builder.switch_to_block(done_block);
+            self.builder.seal_block(done_block);
+        }
+    }
+
+    fn translate_insn(&mut self, insn: &OptimizedBFInstruction) {
+        use OptimizedBFInstruction::*;
+        match insn {
+            // DataPtrDecrement => {
+            //     let dptr = self.builder.use_var(self.data_ptr);
+            //     // let c = self.builder.ins().iconst(self.int, -1i64);
+            //     // let r = self.builder.ins().iadd(dptr, c);
+            //     // let r = self.builder.ins().iadd_imm(dptr, -1);
+            //     let r = self.builder.ins().iadd(dptr, self.leftone);
+            //     self.builder.def_var(self.data_ptr, r);
+            // }
+            // DataPtrIncrement => {
+            //     let dptr = self.builder.use_var(self.data_ptr);
+            //     // let c = self.builder.ins().iconst(self.int, 1i64);
+            //     // let r = self.builder.ins().iadd(dptr, c);
+            //     // let r = self.builder.ins().iadd_imm(dptr, 1);
+            //     let r = self.builder.ins().iadd(dptr, self.rightone);
+            //     self.builder.def_var(self.data_ptr, r);
+            // }
+            // DataValueDecrement => {
+            //     let dptr = self.builder.use_var(self.data_ptr);
+            //     // let c = self.builder.ins().iconst(self.int, -1i64);
+            //     let l = self.builder.ins().load(self.int, MemFlags::new(), dptr, 0);
+            //     // let a = self.builder.ins().iadd(c, l);
+            //     // let a = self.builder.ins().iadd_imm(l, -1);
+            //     let a = self.builder.ins().iadd(l, self.minusone);
+            //     self.builder.ins().store(MemFlags::new(), a, dptr, 0);
+            //     // dptr
+            // }
+            // DataValueIncrement => {
+            //     let dptr = self.builder.use_var(self.data_ptr);
+            //     // let c = self.builder.ins().iconst(self.int, 1i64);
+            //     let l = self.builder.ins().load(self.int, MemFlags::new(), dptr, 0);
+            //     // let a = self.builder.ins().iadd(c, l);
+            //     // let a = self.builder.ins().iadd_imm(l, 1);
+            //     let a = self.builder.ins().iadd(l, self.plusone);
+            //     self.builder.ins().store(MemFlags::new(), a, dptr, 0);
+            //     // dptr
+            // }
+            DataPtrModify(x) => {
+                let dptr = self.builder.use_var(self.data_ptr);
+                let a = self.builder.ins().iadd_imm(dptr, 8 * x);
+                self.builder.def_var(self.data_ptr, a);
+            }
+            DataValueModify(x) => {
+                let dptr = self.builder.use_var(self.data_ptr);
+                let l = self.builder.ins().load(self.int, MemFlags::new(), dptr, 0);
+                let a = self.builder.ins().iadd_imm(l, *x);
+                let a = self.wrap(a);
+                self.builder.ins().store(MemFlags::new(), a, dptr, 0);
+            }
+            DataValuePutchar => {
+                let dptr = self.builder.use_var(self.data_ptr);
+                let l = self.builder.ins().load(self.int, MemFlags::new(), dptr, 0);
+                self.builder.ins().call(self.putchar, &[l]);
+                // dptr
+            }
+            DataValueScanchar => {
+                let dptr = self.builder.use_var(self.data_ptr);
+                // let c = self.builder.ins().iconst(self.int, 0);
+                let s = self.builder.ins().call(self.getchar, &[dptr]);
+                let r = self.builder.inst_results(s)[0];
+                let r = self.wrap(r);
+                self.builder.ins().store(MemFlags::new(), r, dptr, 0);
+                // dptr
+            }
+            WhileDataValueNonZero(_) if self.shared(insn).is_some() => {
+                let id = self.shared(insn).unwrap();
+                self.call(id);
+            }
+            WhileDataValueNonZero(insns) => {
+                let header_block = self.builder.create_block();
+                let body_block = self.builder.create_block();
+                let exit_block = self.builder.create_block();
+                // let mut dptr = dptr;
+                let counter = self.counters.and_then(|c| c.counter(insn));
+                if let Some(counter) = counter {
+                    self.count(counter);
+                }
+
+                // the first and last iteration get blocks of their own to
+                // check and touch the body from, entered only if the loop
+                // runs at all. A stretch the loop is part of has only
+                // checked its header, the body is up to the loop.
+                let body = self.body_window(insns);
+                let (checked, touching) = (self.checked, self.touching);
+                let check_body = self.check_mode && !checked;
+                let mut last_block = exit_block;
+                if let Some(body) = body {
+                    let (lo, hi) = body.accessed.unwrap_or((0, 0));
+                    if touching {
+                        self.touch(0, 0);
+                    }
+                    if (lo, hi) != (0, 0) || body.net != 0 {
+                        let first_block = self.builder.create_block();
+                        let dptr = self.builder.use_var(self.data_ptr);
+                        let l = self.builder.ins().load(self.int, MemFlags::new(), dptr, 0);
+                        self.builder.ins().brz(l, exit_block, &[]);
+                        self.builder.ins().jump(first_block, &[]);
+
+                        self.builder.switch_to_block(first_block);
+                        self.builder.seal_block(first_block);
+                        if (lo, hi) != (0, 0) {
+                            if check_body {
+                                self.check_bounds(lo, hi);
+                            }
+                            self.touch(lo, hi);
+                        }
+                    }
+                    if body.net != 0 {
+                        last_block = self.builder.create_block();
+                    }
+                    self.touching = false;
+                } else {
+                    // nothing covers a body with chunks in it, it checks and
+                    // touches for itself
+                    self.checked = self.check_mode;
+                    self.touching = true;
+                }
+
+                self.builder.ins().jump(header_block, &[]);
+                self.builder.switch_to_block(header_block);
+
+                if self.checked {
+                    self.check_bounds(0, 0);
+                }
+                if self.touching {
+                    self.touch(0, 0);
+                }
+                // innermost loops are the hot ones and always get back to
+                // one of these soon enough, unless they never end
+                let inner = insns
+                    .iter()
+                    .any(|i| matches!(i, WhileDataValueNonZero(_) | CallProcedure));
+                if self.interruptible && inner {
+                    self.check_interrupt();
+                }
+                let dptr2 = self.builder.use_var(self.data_ptr);
+                let l = self.builder.ins().load(self.int, MemFlags::new(), dptr2, 0);
+                self.builder.ins().brz(l, last_block, &[]);
+                self.builder.ins().jump(body_block, &[]);
+
+                self.builder.switch_to_block(body_block);
+                self.builder.seal_block(body_block);
+                if let Some(counter) = counter {
+                    self.count(counter + 8);
+                }
+
+                self.translate_insns(insns);
+                self.builder.ins().jump(header_block, &[]);
+                // let r = self.builder.use_var(self.data_ptr);
+
+                self.checked = checked;
+                self.touching = touching;
+                if let Some(body) = body.filter(|b| b.net != 0) {
+                    let (lo, hi) = body.accessed.unwrap_or((0, 0));
+                    self.builder.switch_to_block(last_block);
+                    self.builder.seal_block(last_block);
+                    self.touch((lo - body.net).min(0), (hi - body.net).max(0));
+                    self.builder.ins().jump(exit_block, &[]);
+                }
+
+                self.builder.switch_to_block(exit_block);
+                // self.builder.append_block_param(exit_block, self.int);
+                // self.builder.append_block_param(exit_block, self.int);
+                // let r = self.builder.block_params(exit_block);
+                // let (r1, r2) = (r[0], r[1]);
+                // self.builder.def_var(self.data_ptr, r1);
+                // self.builder.ins().store(MemFlags::new(), r2, r1, 0);
+                self.builder.seal_block(header_block);
+                // let r = self.builder.use_var(self.data_ptr);
+                // self.builder.def_var(self.data_ptr, r);
+                self.builder.seal_block(exit_block);
+
+                // dptr
+            }
+            DefineProcedure(body) => {
+                if let Some(counter) = self.counters.and_then(|c| c.counter(insn)) {
+                    self.count(counter);
+                }
+                let dptr = self.builder.use_var(self.data_ptr);
+                let slot = self.procedure_slot(dptr);
+                let f = self.procedures[&(body.as_slice() as *const _)];
+                let addr = self.builder.ins().func_addr(self.int, f);
+                self.builder.ins().store(MemFlags::new(), addr, slot, 0);
+            }
+            CallProcedure => {
+                if self.checked {
+                    self.check_bounds(0, 0);
+                }
+                if self.touching {
+                    self.touch(0, 0);
+                }
+                let dptr = self.builder.use_var(self.data_ptr);
+                let slot = self.procedure_slot(dptr);
+                let f = self.builder.ins().load(self.int, MemFlags::new(), slot, 0);
+                let call_block = self.builder.create_block();
+                let error_block = self.builder.create_block();
+                self.builder.ins().brz(f, error_block, &[]);
+                self.builder.ins().jump(call_block, &[]);
+
+                self.builder.switch_to_block(error_block);
+                self.builder.seal_block(error_block);
+                let id = self.builder.ins().load(self.int, MemFlags::new(), dptr, 0);
+                self.builder.ins().call(self.procedure_error, &[id]);
+                self.builder.ins().trap(TrapCode::UnreachableCodeReached);
+
+                self.builder.switch_to_block(call_block);
+                self.builder.seal_block(call_block);
+                let c = self
+                    .builder
+                    .ins()
+                    .call_indirect(self.procedure_sig, f, &[dptr]);
+                let r = self.builder.inst_results(c)[0];
+                self.builder.def_var(self.data_ptr, r);
+            }
+            Fork => {
+                if self.checked {
+                    self.check_bounds(0, 1);
+                }
+                if self.touching {
+                    self.touch(0, 1);
+                }
+                let resume_block = *self
+                    .resume_blocks
+                    .get(self.forks)
+                    .expect("`Y` can only be used in the main program");
+                self.forks += 1;
+                let dptr = self.builder.use_var(self.data_ptr);
+                let resume = self.builder.ins().iconst(self.int, self.forks as i64);
+                self.builder.ins().call(self.fork, &[dptr, resume]);
+                let continue_block = self.builder.create_block();
+                self.builder.ins().jump(continue_block, &[]);
+
+                // the child gets here with its own data pointer
+                self.builder.switch_to_block(resume_block);
+                self.builder.def_var(self.data_ptr, self.entry_ptr);
+                self.builder.ins().jump(continue_block, &[]);
+
+                self.builder.switch_to_block(continue_block);
+                self.builder.seal_block(continue_block);
+            } // _ => todo!(),
+        }
+    }
+
+    /// Address of the procedure table entry for the id under `dptr`,
+    /// bailing out through `bf_procedure_error` if there is no such entry.
+    fn procedure_slot(&mut self, dptr: Value) -> Value {
+        let id = self.builder.ins().load(self.int, MemFlags::new(), dptr, 0);
+        let ok_block = self.builder.create_block();
+        let error_block = self.builder.create_block();
+        let in_range = self.builder.ins().icmp_imm(
+            IntCC::UnsignedLessThan,
+            id,
+            runtime::PROCEDURE_COUNT as i64,
+        );
+        self.builder.ins().brz(in_range, error_block, &[]);
+        self.builder.ins().jump(ok_block, &[]);
+
+        self.builder.switch_to_block(error_block);
+        self.builder.seal_block(error_block);
+        self.builder.ins().call(self.procedure_error, &[id]);
+        self.builder.ins().trap(TrapCode::UnreachableCodeReached);
+
+        self.builder.switch_to_block(ok_block);
+        self.builder.seal_block(ok_block);
+        let table = self
+            .builder
+            .ins()
+            .iconst(self.int, runtime::PROCEDURES.as_ptr() as i64);
+        let offset = self.builder.ins().ishl_imm(id, 3);
+        self.builder.ins().iadd(table, offset)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BFInstruction {
+    DataPtrIncrement,
+    DataPtrDecrement,
+    DataValueIncrement,
+    DataValueDecrement,
+    DataValuePutchar,
+    DataValueScanchar,
+    WhileDataValueNonZero(Vec<BFInstruction>),
+    /// pbrain `(...)`, defines a procedure numbered by the current cell
+    DefineProcedure(Vec<BFInstruction>),
+    /// pbrain `:`, calls the procedure numbered by the current cell
+    CallProcedure,
+    /// Brainfork `Y`, forks a thread that starts on the next cell
+    Fork,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OptimizedBFInstruction {
+    DataPtrModify(i64),
+    DataValueModify(i64),
+    DataValuePutchar,
+    DataValueScanchar,
+    WhileDataValueNonZero(Vec<OptimizedBFInstruction>),
+    DefineProcedure(Vec<OptimizedBFInstruction>),
+    CallProcedure,
+    Fork,
+}
+
+impl OptimizedBFInstruction {
+    /// Merges runs of `+-` and `<>`. Progress is printed every 1000 of the `l`
+    /// instructions in total, unless `l` is 0.
+    pub fn optimize(
+        insns: Vec<BFInstruction>,
+        i: &mut u64,
+        l: u64,
+        top: bool,
+    ) -> Vec<OptimizedBFInstruction> {
+        let mut insns2 = Vec::new();
+        let mut counter = 0;
+        let mut ty = 0; // 1 = data ptr, 2 = value
+        for insn in insns {
+            *i += 1;
+            if l > 0 && i.is_multiple_of(1000) {
+                print!("\rInfo: optimized instruction {}/{}", i, l);
+                stdout().flush().unwrap();
+            }
+            match insn {
+                BFInstruction::DataPtrDecrement => {
+                    if ty == 0 {
+                        ty = 1;
+                        counter = 0;
+                    } else if ty != 1 {
+                        insns2.push(OptimizedBFInstruction::DataValueModify(counter));
+                        counter = 0;
+                        ty = 1;
+                    }
+                    counter -= 1;
+                }
+                BFInstruction::DataPtrIncrement => {
+                    if ty == 0 {
+                        ty = 1;
+                        counter = 0;
+                    } else if ty != 1 {
+                        insns2.push(OptimizedBFInstruction::DataValueModify(counter));
+                        counter = 0;
+                        ty = 1;
+                    }
+                    counter += 1;
+                }
+                BFInstruction::DataValueDecrement => {
+                    if ty == 0 {
+                        ty = 2;
+                        counter = 0;
+                    } else if ty != 2 {
+                        insns2.push(OptimizedBFInstruction::DataPtrModify(counter));
+                        counter = 0;
+                        ty = 2;
+                    }
+                    counter -= 1;
+                }
+                BFInstruction::DataValueIncrement => {
+                    if ty == 0 {
+                        ty = 2;
+                        counter = 0;
+                    } else if ty != 2 {
+                        insns2.push(OptimizedBFInstruction::DataPtrModify(counter));
+                        counter = 0;
+                        ty = 2;
+                    }
+                    counter += 1;
+                }
+                x => {
+                    match ty {
+                        1 => insns2.push(OptimizedBFInstruction::DataPtrModify(counter)),
+                        2 => insns2.push(OptimizedBFInstruction::DataValueModify(counter)),
+                        _ => {}
+                    }
+                    ty = 0;
+                    insns2.push(match x {
+                        BFInstruction::DataValuePutchar => OptimizedBFInstruction::DataValuePutchar,
+                        BFInstruction::DataValueScanchar => {
+                            OptimizedBFInstruction::DataValueScanchar
+                        }
+                        BFInstruction::WhileDataValueNonZero(inner) => {
+                            OptimizedBFInstruction::WhileDataValueNonZero(Self::optimize(
+                                inner, i, l, false,
+                            ))
+                        }
+                        BFInstruction::DefineProcedure(inner) => {
+                            OptimizedBFInstruction::DefineProcedure(Self::optimize(
+                                inner, i, l, false,
+                            ))
+                        }
+                        BFInstruction::CallProcedure => OptimizedBFInstruction::CallProcedure,
+                        BFInstruction::Fork => OptimizedBFInstruction::Fork,
+                        _ => unreachable!(),
+                    })
+                }
+            }
+        }
+        match ty {
+            1 => insns2.push(OptimizedBFInstruction::DataPtrModify(counter)),
+            2 => insns2.push(OptimizedBFInstruction::DataValueModify(counter)),
+            _ => {}
+        }
+        if top {
+            print!("\rInfo: optimized instruction {}/{}", i, l);
+            stdout().flush().unwrap();
+        }
+        insns2
+    }
+
+    pub fn walk_len(v: &[Self]) -> u64 {
+        let mut len = 0;
+        for x in v {
+            match x {
+                Self::WhileDataValueNonZero(ref v) | Self::DefineProcedure(ref v) => {
+                    len += 1 + Self::walk_len(v.as_slice())
+                }
+                _ => len += 1,
+            }
+        }
+        len
+    }
+
+    /// Appends the body of every procedure defined in `insns`, nested ones included.
+    pub fn collect_procedures<'a>(insns: &'a [Self], out: &mut Vec<&'a [Self]>) {
+        for insn in insns {
+            match insn {
+                Self::WhileDataValueNonZero(v) => Self::collect_procedures(v, out),
+                Self::DefineProcedure(v) => {
+                    out.push(v);
+                    Self::collect_procedures(v, out);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Number of forks in `insns`, not counting the ones in procedures.
+    pub fn count_forks(insns: &[Self]) -> usize {
+        insns
+            .iter()
+            .map(|insn| match insn {
+                Self::WhileDataValueNonZero(v) => Self::count_forks(v),
+                Self::Fork => 1,
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+impl BFInstruction {
+    pub fn parse_char(c: char) -> Option<Self> {
+        match c {
+            '>' => Some(BFInstruction::DataPtrIncrement),
+            '<' => Some(BFInstruction::DataPtrDecrement),
+            '+' => Some(BFInstruction::DataValueIncrement),
+            '-' => Some(BFInstruction::DataValueDecrement),
+            '.' => Some(BFInstruction::DataValuePutchar),
+            ',' => Some(BFInstruction::DataValueScanchar),
+            ':' => Some(BFInstruction::CallProcedure),
+            'Y' => Some(BFInstruction::Fork),
+            _ => None,
+        }
+    }
+
+    pub fn walk_len(v: &[Self]) -> u64 {
+        let mut len = 0;
+        for x in v {
+            match x {
+                Self::WhileDataValueNonZero(ref v) | Self::DefineProcedure(ref v) => {
+                    len += 1 + Self::walk_len(v.as_slice())
+                }
+                _ => len += 1,
+            }
+        }
+        len
+    }
+}
+
+// pub struct ParserNestingState(Vec<BFInstruction>, Option<Box<ParserNestingState>>);
+
+#[derive(Debug)]
+pub struct Parser {
+    // nesting: Option<ParserNestingState>,
+    insns: Vec<BFInstruction>,
+    tokens: Vec<Token>,
+    ptr: usize,
+}
+
+impl Parser {
+    pub fn new(s: &str) -> Self {
+        Self::with_lexer(s, &Brainfuck::default())
+    }
+
+    pub fn with_lexer(s: &str, lexer: &dyn Lexer) -> Self {
+        Self {
+            // nesting: None,
+            insns: Vec::new(),
+            tokens: lexer.tokenize(s),
+            ptr: 0,
+        }
+    }
+
+    pub fn parse(&mut self) -> Vec<BFInstruction> {
+        while self.ptr < self.tokens.len() {
+            self.parse_step();
+        }
+        std::mem::take(&mut self.insns)
+    }
+
+    fn parse_step(&mut self) {
+        match self.tokens[self.ptr].kind {
+            TokenKind::LoopStart => {
+                let body = self.parse_block(TokenKind::LoopEnd, "bracket");
+                self.insns.push(BFInstruction::WhileDataValueNonZero(body));
+            }
+            TokenKind::ProcedureStart => {
+                let body = self.parse_block(TokenKind::ProcedureEnd, "parenthesis");
+                self.insns.push(BFInstruction::DefineProcedure(body));
+            }
+            // ']' omitted since parsed above^
+            TokenKind::LoopEnd => panic!("Unmatched close bracket, parser state = {:?}", self),
+            TokenKind::ProcedureEnd => {
+                panic!("Unmatched close parenthesis, parser state = {:?}", self)
+            }
+            kind => {
+                self.ptr += 1;
+                self.insns.push(kind.instruction().unwrap());
+            }
+        }
+    }
+
+    /// Parses from an opening token up to its matching `end`.
+    fn parse_block(&mut self, end: TokenKind, what: &str) -> Vec<BFInstruction> {
+        self.ptr += 1;
+        let saved_insns = std::mem::take(&mut self.insns);
+        while self.ptr < self.tokens.len() && self.tokens[self.ptr].kind != end {
+            self.parse_step();
+        }
+        let err = self.ptr >= self.tokens.len();
+        self.ptr += 1; // account for the closing token
+        if err {
+            panic!("Unmatched open {}, parser state = {:?}", what, self);
+        }
+        std::mem::replace(&mut self.insns, saved_insns)
+    }
+}
+
+// // JIT bindings
+// #[link(name = "c")]
+// extern "C" {
+//     pub fn putchar(c: u64);
+//     pub fn getchar() -> u64;
+// }
+
+// #[no_mangle]
+// pub extern "C" fn putchar(c: u64) {
+//     print!("{}", unsafe { char::from_u32_unchecked(c as _) });
+//     stdout().flush().unwrap();
+// }
+
+// #[no_mangle]
+// pub extern "C" fn scanchar() -> u64 {
+//     let mut arr = [0; 1];
+//     stdin().read_exact(&mut arr).unwrap();
+//     arr[0] as _
+// }
+
+pub type BFJitFunction = extern "C" fn(*mut u64) -> *mut u64;
+/// Runs part of a program, returning the data pointer it stopped at
+pub type FragmentFunction = extern "C" fn(*mut u64) -> *mut u64;
@@ -0,0 +1,537 @@
+//! `bfi lint`: finds code that probably doesn't do what its author meant.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    ops::Range,
+};
+
+use crate::{lexer::Token, BFInstruction};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lint {
+    /// A loop that can't stop once entered
+    InfiniteLoop,
+    /// A loop that never runs since its cell is always 0 there
+    Unreachable,
+    /// The pointer moves left of the cell it started on
+    TapeUnderflow,
+    /// Code after the last thing that could affect the output
+    UnusedTrailing,
+    /// Code that only works with cells that wrap around at 8 bits
+    Wraparound,
+}
+
+impl Lint {
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::InfiniteLoop => "infinite-loop",
+            Lint::Unreachable => "unreachable",
+            Lint::TapeUnderflow => "tape-underflow",
+            Lint::UnusedTrailing => "unused-trailing",
+            Lint::Wraparound => "wraparound",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Warning {
+    pub lint: Lint,
+    pub message: String,
+    /// Byte range in the source of the code the warning is about
+    pub span: Range<usize>,
+}
+
+/// Lints `insns`, parsed from `tokens`.
+pub fn lint(insns: &[BFInstruction], tokens: &[Token]) -> Vec<Warning> {
+    let mut l = Linter {
+        tokens,
+        next: 0,
+        warnings: Vec::new(),
+    };
+    l.trailing(insns);
+    // the program starts on a tape of zeros
+    let mut state = State {
+        ptr: Some(0),
+        cells: HashMap::new(),
+        rest_zero: true,
+    };
+    l.block(insns, &mut state);
+    l.warnings.sort_by_key(|w| w.span.start);
+    l.warnings
+}
+
+/// What's known about the tape at some point in the program
+#[derive(Clone)]
+struct State {
+    /// Pointer relative to the start cell, `None` once it depends on the input.
+    /// While unknown, `cells` is keyed relative to the pointer when it got lost.
+    ptr: Option<i64>,
+    /// Offset used as the key for `cells` while `ptr` is `None`
+    cells: HashMap<i64, Option<i64>>,
+    /// Cells missing from `cells` are 0, otherwise they're unknown
+    rest_zero: bool,
+}
+
+impl State {
+    fn unknown() -> Self {
+        Self {
+            ptr: None,
+            cells: HashMap::new(),
+            rest_zero: false,
+        }
+    }
+
+    fn key(&self, rel: i64) -> i64 {
+        self.ptr.unwrap_or(0) + rel
+    }
+
+    fn get(&self, key: i64) -> Option<i64> {
+        match self.cells.get(&key) {
+            Some(v) => *v,
+            None if self.rest_zero => Some(0),
+            None => None,
+        }
+    }
+}
+
+struct Linter<'a> {
+    tokens: &'a [Token],
+    /// Token of the next instruction visited, instructions map onto tokens one
+    /// to one, with blocks taking one token at each end
+    next: usize,
+    warnings: Vec<Warning>,
+}
+
+impl<'a> Linter<'a> {
+    fn warn(&mut self, lint: Lint, span: Range<usize>, message: String) {
+        self.warnings.push(Warning {
+            lint,
+            message,
+            span,
+        });
+    }
+
+    fn take(&mut self) -> Range<usize> {
+        self.next += 1;
+        self.tokens[self.next - 1].span.clone()
+    }
+
+    /// Skips the tokens of `insns` without looking at them.
+    fn skip(&mut self, insns: &[BFInstruction]) {
+        self.next += BFInstruction::walk_len(insns) as usize + count_blocks(insns);
+    }
+
+    /// Walks `insns`, keeping track of what's known about the tape in `state`.
+    /// The pointer offset in `rel` is relative to `state`'s base.
+    fn block(&mut self, insns: &[BFInstruction], state: &mut State) {
+        let mut rel = 0;
+        for insn in insns {
+            match insn {
+                BFInstruction::DataPtrIncrement | BFInstruction::DataPtrDecrement => {
+                    let span = self.take();
+                    let was = state.ptr.map(|p| p + rel);
+                    rel += if let BFInstruction::DataPtrIncrement = insn {
+                        1
+                    } else {
+                        -1
+                    };
+                    if was == Some(0) && state.ptr.map(|p| p + rel) == Some(-1) {
+                        self.warn(
+                            Lint::TapeUnderflow,
+                            span,
+                            "pointer moves left of the cell it started on, most implementations \
+                             have no cells there"
+                                .to_string(),
+                        );
+                    }
+                }
+                BFInstruction::DataValueIncrement | BFInstruction::DataValueDecrement => {
+                    let span = self.take();
+                    let key = state.key(rel);
+                    if let Some(v) = state.get(key) {
+                        let inc = matches!(insn, BFInstruction::DataValueIncrement);
+                        let new = if inc { v + 1 } else { v - 1 };
+                        if v == 0 && !inc {
+                            self.warn(
+                                Lint::Wraparound,
+                                span,
+                                "decrements a cell that is 0, which relies on the cell wrapping \
+                                 around to 255"
+                                    .to_string(),
+                            );
+                        } else if v == 255 && inc {
+                            self.warn(
+                                Lint::Wraparound,
+                                span,
+                                "increments a cell past 255, which relies on the cell wrapping \
+                                 around to 0"
+                                    .to_string(),
+                            );
+                        }
+                        state.cells.insert(key, Some(new));
+                    }
+                }
+                BFInstruction::DataValuePutchar => {
+                    self.take();
+                }
+                BFInstruction::DataValueScanchar => {
+                    self.take();
+                    state.cells.insert(state.key(rel), None);
+                }
+                BFInstruction::CallProcedure => {
+                    self.take();
+                    // the procedure can do anything
+                    *state = State::unknown();
+                    rel = 0;
+                }
+                BFInstruction::Fork => {
+                    self.take();
+                    // other threads can write anywhere from now on
+                    *state = State::unknown();
+                    rel = 0;
+                }
+                BFInstruction::DefineProcedure(body) => {
+                    self.take();
+                    self.block(body, &mut State::unknown());
+                    self.take();
+                }
+                BFInstruction::WhileDataValueNonZero(body) => {
+                    let key = state.key(rel);
+                    if state.get(key) == Some(0) {
+                        let open = self.take();
+                        self.skip(body);
+                        let close = self.take();
+                        // clearing a cell that's already clear is harmless
+                        let clear = matches!(
+                            body.as_slice(),
+                            [BFInstruction::DataValueDecrement]
+                                | [BFInstruction::DataValueIncrement]
+                        );
+                        if clear {
+                            continue;
+                        }
+                        self.warn(
+                            Lint::Unreachable,
+                            open.start..close.end,
+                            "loop never runs, its cell is always 0 here".to_string(),
+                        );
+                        continue;
+                    }
+                    let open = self.take();
+                    self.check_counter(body, open.clone());
+                    match written(body) {
+                        Some(offsets) => {
+                            // balanced: the pointer ends where it started and only
+                            // the cells the body writes change
+                            let mut inner = state.clone();
+                            for off in &offsets {
+                                inner.cells.insert(key + off, None);
+                            }
+                            if let Some(p) = inner.ptr.as_mut() {
+                                *p += rel;
+                            } else {
+                                inner.cells =
+                                    inner.cells.into_iter().map(|(k, v)| (k - rel, v)).collect();
+                            }
+                            self.block(body, &mut inner);
+                            for off in offsets {
+                                state.cells.insert(key + off, None);
+                            }
+                        }
+                        None => {
+                            self.block(body, &mut State::unknown());
+                            *state = State::unknown();
+                            rel = 0;
+                        }
+                    }
+                    let key = state.key(rel);
+                    state.cells.insert(key, Some(0));
+                    self.take();
+                }
+            }
+        }
+    }
+
+    /// Looks at what a simple loop body does to its counter cell.
+    fn check_counter(&mut self, body: &[BFInstruction], span: Range<usize>) {
+        let mut rel = 0;
+        let mut delta = 0;
+        for insn in body {
+            match insn {
+                BFInstruction::DataPtrIncrement => rel += 1,
+                BFInstruction::DataPtrDecrement => rel -= 1,
+                BFInstruction::DataValueIncrement if rel == 0 => delta += 1,
+                BFInstruction::DataValueDecrement if rel == 0 => delta -= 1,
+                BFInstruction::DataValueScanchar if rel == 0 => return,
+                BFInstruction::DataValueIncrement
+                | BFInstruction::DataValueDecrement
+                | BFInstruction::DataValueScanchar
+                | BFInstruction::DataValuePutchar => {}
+                _ => return,
+            }
+        }
+        if rel != 0 {
+            return;
+        }
+        if delta == 0 {
+            let message = if body.is_empty() {
+                "empty loop never ends if its cell isn't 0"
+            } else {
+                "loop never changes its cell, so it never ends if the cell isn't 0"
+            };
+            self.warn(Lint::InfiniteLoop, span, message.to_string());
+        } else if delta > 0 {
+            self.warn(
+                Lint::Wraparound,
+                span,
+                "loop only increments its cell, so it only ends once the cell wraps around"
+                    .to_string(),
+            );
+        }
+    }
+
+    /// Warns about code after the last command that could affect the output.
+    fn trailing(&mut self, insns: &[BFInstruction]) {
+        let last = insns.iter().rposition(has_effect);
+        let first = last.map_or(0, |i| i + 1);
+        let rest = &insns[first..];
+        if rest.is_empty() {
+            return;
+        }
+        let tokens = BFInstruction::walk_len(rest) as usize + count_blocks(rest);
+        let end = self.tokens.len();
+        let span = self.tokens[end - tokens].span.start..self.tokens[end - 1].span.end;
+        self.warn(
+            Lint::UnusedTrailing,
+            span,
+            format!("the last {} commands can't affect the output", tokens),
+        );
+    }
+}
+
+/// Whether running `insn` can be seen from outside, not counting the tape
+/// the program leaves behind.
+fn has_effect(insn: &BFInstruction) -> bool {
+    match insn {
+        BFInstruction::DataValuePutchar
+        | BFInstruction::DataValueScanchar
+        | BFInstruction::CallProcedure
+        | BFInstruction::Fork => true,
+        BFInstruction::WhileDataValueNonZero(body) => body.iter().any(has_effect),
+        _ => false,
+    }
+}
+
+/// Number of blocks in `insns`, nested ones included. Each has a closing
+/// token that `walk_len` doesn't count.
+fn count_blocks(insns: &[BFInstruction]) -> usize {
+    insns
+        .iter()
+        .map(|i| match i {
+            BFInstruction::WhileDataValueNonZero(v) | BFInstruction::DefineProcedure(v) => {
+                1 + count_blocks(v)
+            }
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Offsets of the cells a loop body writes, if it always ends where it started
+/// and doesn't call anything.
+fn written(body: &[BFInstruction]) -> Option<HashSet<i64>> {
+    let mut rel = 0;
+    let mut out = HashSet::new();
+    for insn in body {
+        match insn {
+            BFInstruction::DataPtrIncrement => rel += 1,
+            BFInstruction::DataPtrDecrement => rel -= 1,
+            BFInstruction::DataValueIncrement
+            | BFInstruction::DataValueDecrement
+            | BFInstruction::DataValueScanchar => {
+                out.insert(rel);
+            }
+            BFInstruction::DataValuePutchar | BFInstruction::DefineProcedure(_) => {}
+            BFInstruction::WhileDataValueNonZero(inner) => {
+                out.extend(written(inner)?.into_iter().map(|o| o + rel));
+            }
+            BFInstruction::CallProcedure | BFInstruction::Fork => return None,
+        }
+    }
+    if rel == 0 {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+/// `file:line:column: warning: message [lint]`, one per line.
+pub fn format_human(file: &str, source: &str, warnings: &[Warning]) -> String {
+    let mut out = String::new();
+    for w in warnings {
+        let (line, col) = line_col(source, w.span.start);
+        writeln!(
+            out,
+            "{}:{}:{}: warning: {} [{}]",
+            file,
+            line,
+            col,
+            w.message,
+            w.lint.name()
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// A JSON array of `{"lint", "message", "line", "column", "start", "end"}`,
+/// where `start` and `end` are byte offsets.
+pub fn format_json(source: &str, warnings: &[Warning]) -> String {
+    let items: Vec<_> = warnings
+        .iter()
+        .map(|w| {
+            let (line, col) = line_col(source, w.span.start);
+            format!(
+                "{{\"lint\":\"{}\",\"message\":{},\"line\":{},\"column\":{},\"start\":{},\"end\":{}}}",
+                w.lint.name(),
+                json_string(&w.message),
+                line,
+                col,
+                w.span.start,
+                w.span.end
+            )
+        })
+        .collect();
+    format!("[{}]\n", items.join(","))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// 1-based line and column (in characters) of byte `offset`.
+fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let col = before.rsplit('\n').next().unwrap().chars().count() + 1;
+    (line, col)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        lexer::{Brainfuck, Extensions, Lexer},
+        Parser,
+    };
+
+    /// Every warning for `source` with the code it points at
+    fn lints(source: &str) -> Vec<(Lint, &str)> {
+        let lexer = Brainfuck {
+            extensions: Extensions {
+                pbrain: true,
+                brainfork: true,
+            },
+        };
+        let insns = Parser::with_lexer(source, &lexer).parse();
+        lint(&insns, &lexer.tokenize(source))
+            .into_iter()
+            .map(|w| (w.lint, &source[w.span]))
+            .collect()
+    }
+
+    #[test]
+    fn clean_programs() {
+        assert_eq!(lints(""), []);
+        assert_eq!(lints("++[->+<]>."), []);
+        // clearing a cell that's already 0 is common and harmless
+        assert_eq!(lints("[-]+."), []);
+    }
+
+    #[test]
+    fn infinite_loops() {
+        assert_eq!(lints("+[]."), [(Lint::InfiniteLoop, "[")]);
+        assert_eq!(lints("+[>+<]."), [(Lint::InfiniteLoop, "[")]);
+        // the counter moves, or changes through input
+        assert_eq!(lints("+[>]."), []);
+        assert_eq!(lints("+[,]."), []);
+    }
+
+    #[test]
+    fn unreachable_loops() {
+        assert_eq!(lints("[.]."), [(Lint::Unreachable, "[.]")]);
+        // known to be 0 again after a loop ends
+        assert_eq!(
+            lints("+[-]>[.]<[.]"),
+            [(Lint::Unreachable, "[.]"), (Lint::Unreachable, "[.]")]
+        );
+        // input or a call could have set it
+        assert_eq!(lints(",[-.]"), []);
+        assert_eq!(lints("+:[-]>[-.]"), []);
+    }
+
+    #[test]
+    fn tape_underflow() {
+        assert_eq!(lints("<+."), [(Lint::TapeUnderflow, "<")]);
+        assert_eq!(lints(">+<<+."), [(Lint::TapeUnderflow, "<")]);
+        assert_eq!(lints(">+<+."), []);
+        // only where it crosses, not every move left of the start
+        assert_eq!(lints("<<+."), [(Lint::TapeUnderflow, "<")]);
+    }
+
+    #[test]
+    fn unused_trailing() {
+        assert_eq!(lints("+.>+[-]"), [(Lint::UnusedTrailing, ">+[-]")]);
+        assert_eq!(lints("+.>+[-.]"), []);
+        let source = "+.>+";
+        let warnings = lint(
+            &Parser::new(source).parse(),
+            &Brainfuck::default().tokenize(source),
+        );
+        assert_eq!(
+            warnings[0].message,
+            "the last 2 commands can't affect the output"
+        );
+    }
+
+    #[test]
+    fn wraparound() {
+        assert_eq!(lints("-."), [(Lint::Wraparound, "-")]);
+        assert_eq!(lints(&("+".repeat(256) + ".")), [(Lint::Wraparound, "+")]);
+        assert_eq!(lints("+[+]."), [(Lint::Wraparound, "[")]);
+        // a value that isn't known can't be said to wrap
+        assert_eq!(lints(",-."), []);
+    }
+
+    #[test]
+    fn output_formats() {
+        let source = "+\n <+.";
+        let warnings = lint(
+            &Parser::new(source).parse(),
+            &Brainfuck::default().tokenize(source),
+        );
+        assert_eq!(
+            format_human("x.bf", source, &warnings),
+            "x.bf:2:2: warning: pointer moves left of the cell it started on, most \
+             implementations have no cells there [tape-underflow]\n"
+        );
+        assert_eq!(
+            format_json(source, &warnings),
+            "[{\"lint\":\"tape-underflow\",\"message\":\"pointer moves left of the cell it \
+             started on, most implementations have no cells there\",\"line\":2,\"column\":2,\
+             \"start\":3,\"end\":4}]\n"
+        );
+        assert_eq!(format_json(source, &[]), "[]\n");
+        assert_eq!(json_string("a\"b\\c\nd"), "\"a\\\"b\\\\c\\u000ad\"");
+    }
+}
@@ -1,542 +1,346 @@
 use std::{
-    fs::File,
-    io::{stdin, stdout, Write},
+    io::{stdin, stdout, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
 };
 
-use cranelift::{
-    codegen::ir::FuncRef,
-    frontend::{FunctionBuilder, FunctionBuilderContext},
-    prelude::*,
+use bfi::{
+    bytecode::{self, Op},
+    cache::{self, Cache},
+    cli::{Command, Engine, Options, USAGE},
+    coverage::{Counters, Coverage},
+    dump::{self, Stage},
+    fmt,
+    lexer::{self, Lexer, Token},
+    lint, repl, replay,
+    report::{self, RunResult, Timings},
+    runtime, signal,
+    snapshot::{self, Snapshot},
+    tiered::Tiered,
+    unroll,
+    vm::{self, Position, Vm},
+    BFInstruction, BFJitFunction, CellWidth, OptimizedBFInstruction, Parser, JIT,
 };
-use cranelift_jit::{JITBuilder, JITModule};
-use cranelift_module::{Linkage, Module};
 
-pub struct JIT {
-    builder_context: FunctionBuilderContext,
-    ctx: codegen::Context,
-    //data_ctx: DataContext,
-    module: JITModule,
-}
-
-impl JIT {
-    pub fn new() -> Self {
-        let mut flag_builder = settings::builder();
-        // On at least AArch64, "colocated" calls use shorter-range relocations,
-        // which might not reach all definitions; we can't handle that here, so
-        // we require long-range relocation types.
-        flag_builder.set("use_colocated_libcalls", "false").unwrap();
-        flag_builder.set("is_pic", "true").unwrap();
-        // flag_builder.set("opt_level", "speed_and_size").unwrap();
-        flag_builder.set("opt_level", "none").unwrap();
-        flag_builder.set("enable_verifier", "false").unwrap();
-        let isa_builder = cranelift_native::builder().unwrap_or_else(|msg| {
-            panic!("host machine is not supported: {}", msg);
-        });
-        let isa = isa_builder.finish(settings::Flags::new(flag_builder));
-        let builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
-        // let builder = JITBuilder::new(cranelift_module::default_libcall_names());
-        let module = JITModule::new(builder);
-        Self {
-            builder_context: FunctionBuilderContext::new(),
-            ctx: module.make_context(),
-            //data_ctx: DataContext::new(),
-            module,
-        }
-    }
-
-    pub fn translate(&mut self, insns: &[OptimizedBFInstruction]) {
-        // i64
-        let int = self.module.target_config().pointer_type();
-
-        // take in data ptr
-        self.ctx.func.signature.params.push(AbiParam::new(int));
-        self.ctx.func.signature.returns.push(AbiParam::new(int));
-
-        let mut builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_context);
-
-        // declare putchar
-        let mut putchar_sig = self.module.make_signature();
-        putchar_sig.params.push(AbiParam::new(int));
-        // putchar_sig.returns.push(AbiParam::new(int));
-        let putchar_id = self
-            .module
-            .declare_function("putchar", Linkage::Import, &putchar_sig)
-            .unwrap();
-        let putchar = self
-            .module
-            .declare_func_in_func(putchar_id, &mut builder.func);
-        // let putchar_sigr = builder.import_signature(putchar_sig);
-        // let putchar_extfd = ExtFuncData {
-        //     name: ExternalName::user(0, 0),
-        //     signature: putchar_sigr,
-        //     colocated: false,
-        // };
-        // let putchar = builder.import_function(putchar_extfd);
-
-        // // declare scanchar
-        // let mut scanchar_sig = self.module.make_signature();
-        // // scanchar_sig.params.push(AbiParam::new(int));
-        // scanchar_sig.returns.push(AbiParam::new(int));
-        // let scanchar_id = self
-        //     .module
-        //     .declare_function("scanchar", Linkage::Import, &scanchar_sig)
-        //     .unwrap();
-        // let scanchar = self
-        //     .module
-        //     .declare_func_in_func(scanchar_id, &mut builder.func);
-        let mut getchar_sig = self.module.make_signature();
-        getchar_sig.returns.push(AbiParam::new(int));
-        let getchar_id = self
-            .module
-            .declare_function("getchar", Linkage::Import, &getchar_sig)
-            .unwrap();
-        let getchar = self
-            .module
-            .declare_func_in_func(getchar_id, &mut builder.func);
-        // let scanchar_sigr = builder.import_signature(scanchar_sig);
-        // let scanchar_extfd = ExtFuncData {
-        //     name: ExternalName::user(0, 1),
-        //     signature: scanchar_sigr,
-        //     colocated: false,
-        // };
-        // let scanchar = builder.import_function(scanchar_extfd);
-
-        let entry_block = builder.create_block();
-        builder.append_block_params_for_function_params(entry_block);
-        builder.switch_to_block(entry_block);
-        builder.seal_block(entry_block);
-
-        // let mut data_ptr =;
-        let data_ptr = Variable::new(0);
-        builder.declare_var(data_ptr, int);
-        builder.def_var(data_ptr, builder.block_params(entry_block)[0]);
-
-        let mut trans = Translator {
-            // plusone: builder.ins().iconst(int, 1),
-            // minusone: builder.ins().iconst(int, -1),
-            // /** i64's are EIGHT BYTES */
-            // rightone: builder.ins().iconst(int, 8),
-            // leftone: builder.ins().iconst(int, -8),
-            builder,
-            int,
-            putchar,
-            // scanchar,
-            getchar,
-            data_ptr,
-            //module: &mut self.module,
-        };
-
-        for insn in insns {
-            trans.translate_insn(insn);
-            // let v = trans.translate_insn(insn);
-            // trans.builder.def_var(data_ptr, v);
+/// Prints progress on stdout, unless --quiet was given.
+macro_rules! chatter {
+    ($opts:expr, $($arg:tt)*) => {
+        if !$opts.quiet {
+            print!($($arg)*);
+            stdout().flush().unwrap();
         }
+    };
+}
 
-        // emit the return
-        let v2 = trans.builder.use_var(data_ptr);
-        let l = trans.builder.ins().load(int, MemFlags::new(), v2, 0);
-
-        trans.builder.ins().return_(&[l]);
-        trans.builder.finalize();
-    }
-
-    pub fn jit(&mut self, insns: &[OptimizedBFInstruction]) -> *const u8 {
-        self.translate(insns);
-        println!("Translation done");
+/// Everything that changes what `parse_and_optimize` produces, part of the cache key
+fn optimizer_options(lexer: &dyn Lexer, opts: &Options) -> String {
+    let passes = if opts.unroll {
+        format!("merge, unroll {}-bit", opts.cell_width.bits())
+    } else {
+        "merge".to_string()
+    };
+    format!("{}, {}", passes, lexer.describe())
+}
 
-        let id = self
-            .module
-            .declare_function("bf", Linkage::Export, &self.ctx.func.signature)
-            .unwrap();
-        self.module
-            .define_function(
-                id,
-                &mut self.ctx,
-                &mut codegen::binemit::NullTrapSink {},
-                &mut codegen::binemit::NullStackMapSink {},
-            )
-            .unwrap_or_else(|e| {
-                println!("{:?}", e);
-                println!("{}", self.ctx.func.display(self.module.isa()));
-                // let mut s = String::new();
-                // codegen::write_function(
-                //     &mut s,
-                //     &self.ctx.func,
-                //     &DisplayFunctionAnnotations::default(),
-                // )
-                // .unwrap();
-                panic!()
-            });
-        // println!("Debug function value:");
-        // println!("{}", self.ctx.func.display(self.module.isa()));
-        {
-            let mut f = File::create("dis").unwrap();
-            write!(f, "{}", self.ctx.func.display(self.module.isa())).unwrap();
-        }
-        println!("Written IR to dis");
-        self.module.clear_context(&mut self.ctx);
-        self.module.finalize_definitions();
-        let code = self.module.get_finalized_function(id);
-        code
+fn parse_and_optimize(
+    code: &str,
+    lexer: &dyn Lexer,
+    opts: &Options,
+    timings: &mut Timings,
+) -> Vec<OptimizedBFInstruction> {
+    let dumper = &opts.dumper;
+    let timer = Instant::now();
+    let mut parser = Parser::with_lexer(code, lexer);
+    let insns = parser.parse();
+    timings.parse = Some(timer.elapsed());
+    chatter!(opts, "Parsing done\n");
+    dumper
+        .dump(Stage::Ast, "ast.bf", || dump::format_ast(&insns))
+        .unwrap_or_else(|e| dump::fail(e));
+    chatter!(opts, "Walking length of unoptimized code...");
+    let l = BFInstruction::walk_len(&insns);
+    chatter!(opts, " {} instructions\n", l);
+    let mut i = 0;
+    let progress = if opts.quiet { 0 } else { l };
+    let timer = Instant::now();
+    let opt = OptimizedBFInstruction::optimize(insns, &mut i, progress, !opts.quiet);
+    timings.merge = Some(timer.elapsed());
+    chatter!(opts, "\nOptimizing done\n");
+    dumper
+        .dump(Stage::Opt, "opt-1-merge.bf", || {
+            dump::format_optimized(&opt)
+        })
+        .unwrap_or_else(|e| dump::fail(e));
+    // constants can't be tracked across threads
+    if !opts.unroll || OptimizedBFInstruction::count_forks(&opt) > 0 {
+        return opt;
     }
+    let timer = Instant::now();
+    let (opt, stats) = unroll::unroll(opt, opts.cell_width.mask());
+    timings.unroll = Some(timer.elapsed());
+    chatter!(
+        opts,
+        "Unrolling done: removed {} dead loops, folded {}, unrolled {}\n",
+        stats.dead,
+        stats.folded,
+        stats.unrolled
+    );
+    dumper
+        .dump(Stage::Opt, "opt-2-unroll.bf", || {
+            dump::format_optimized(&opt)
+        })
+        .unwrap_or_else(|e| dump::fail(e));
+    opt
 }
 
-struct Translator<'a> {
-    int: Type,
-    builder: FunctionBuilder<'a>,
-    //module: &'a mut JITModule,
-    putchar: FuncRef,
-    // scanchar: FuncRef,
-    getchar: FuncRef,
-    data_ptr: Variable,
-    // minusone: Value,
-    // plusone: Value,
-    // rightone: Value,
-    // leftone: Value,
+/// A JIT configured by the command line
+fn make_jit(opts: &Options) -> JIT {
+    let mut jit = JIT::with_dumper(opts.dumper.clone());
+    jit.set_checked(opts.checked);
+    jit.set_cell_width(opts.cell_width);
+    jit.set_outline(opts.outline);
+    jit.set_chunk_len(opts.chunk_size);
+    jit.set_threads(opts.compile_threads);
+    jit.set_perf_map(opts.perf_map);
+    jit.set_coverage(opts.coverage);
+    jit.set_interruptible(true);
+    jit
 }
 
-impl<'a> Translator<'a> {
-    fn translate_insn(&mut self, insn: &OptimizedBFInstruction) {
-        use OptimizedBFInstruction::*;
-        match insn {
-            // DataPtrDecrement => {
-            //     let dptr = self.builder.use_var(self.data_ptr);
-            //     // let c = self.builder.ins().iconst(self.int, -1i64);
-            //     // let r = self.builder.ins().iadd(dptr, c);
-            //     // let r = self.builder.ins().iadd_imm(dptr, -1);
-            //     let r = self.builder.ins().iadd(dptr, self.leftone);
-            //     self.builder.def_var(self.data_ptr, r);
-            // }
-            // DataPtrIncrement => {
-            //     let dptr = self.builder.use_var(self.data_ptr);
-            //     // let c = self.builder.ins().iconst(self.int, 1i64);
-            //     // let r = self.builder.ins().iadd(dptr, c);
-            //     // let r = self.builder.ins().iadd_imm(dptr, 1);
-            //     let r = self.builder.ins().iadd(dptr, self.rightone);
-            //     self.builder.def_var(self.data_ptr, r);
-            // }
-            // DataValueDecrement => {
-            //     let dptr = self.builder.use_var(self.data_ptr);
-            //     // let c = self.builder.ins().iconst(self.int, -1i64);
-            //     let l = self.builder.ins().load(self.int, MemFlags::new(), dptr, 0);
-            //     // let a = self.builder.ins().iadd(c, l);
-            //     // let a = self.builder.ins().iadd_imm(l, -1);
-            //     let a = self.builder.ins().iadd(l, self.minusone);
-            //     self.builder.ins().store(MemFlags::new(), a, dptr, 0);
-            //     // dptr
-            // }
-            // DataValueIncrement => {
-            //     let dptr = self.builder.use_var(self.data_ptr);
-            //     // let c = self.builder.ins().iconst(self.int, 1i64);
-            //     let l = self.builder.ins().load(self.int, MemFlags::new(), dptr, 0);
-            //     // let a = self.builder.ins().iadd(c, l);
-            //     // let a = self.builder.ins().iadd_imm(l, 1);
-            //     let a = self.builder.ins().iadd(l, self.plusone);
-            //     self.builder.ins().store(MemFlags::new(), a, dptr, 0);
-            //     // dptr
-            // }
-            DataPtrModify(x) => {
-                let dptr = self.builder.use_var(self.data_ptr);
-                let a = self.builder.ins().iadd_imm(dptr, 8 * x);
-                self.builder.def_var(self.data_ptr, a);
-            }
-            DataValueModify(x) => {
-                let dptr = self.builder.use_var(self.data_ptr);
-                let l = self.builder.ins().load(self.int, MemFlags::new(), dptr, 0);
-                let a = self.builder.ins().iadd_imm(l, *x);
-                self.builder.ins().store(MemFlags::new(), a, dptr, 0);
-            }
-            DataValuePutchar => {
-                let dptr = self.builder.use_var(self.data_ptr);
-                let l = self.builder.ins().load(self.int, MemFlags::new(), dptr, 0);
-                self.builder.ins().call(self.putchar, &[l]);
-                // dptr
-            }
-            DataValueScanchar => {
-                let dptr = self.builder.use_var(self.data_ptr);
-                // let c = self.builder.ins().iconst(self.int, 0);
-                let s = self.builder.ins().call(self.getchar, &[]);
-                let r = self.builder.inst_results(s)[0];
-                self.builder.ins().store(MemFlags::new(), r, dptr, 0);
-                // dptr
-            }
-            WhileDataValueNonZero(insns) => {
-                let header_block = self.builder.create_block();
-                let body_block = self.builder.create_block();
-                let exit_block = self.builder.create_block();
-                // let mut dptr = dptr;
-
-                self.builder.ins().jump(header_block, &[]);
-                self.builder.switch_to_block(header_block);
-
-                let dptr2 = self.builder.use_var(self.data_ptr);
-                let l = self.builder.ins().load(self.int, MemFlags::new(), dptr2, 0);
-                self.builder.ins().brz(l, exit_block, &[]);
-                self.builder.ins().jump(body_block, &[]);
+/// Where the data pointer starts on the tape
+const START: usize = 9000000;
 
-                self.builder.switch_to_block(body_block);
-                self.builder.seal_block(body_block);
-
-                for insn in insns {
-                    self.translate_insn(insn);
-                    // let v = self.translate_insn(insn);
-                    // self.builder.def_var(self.data_ptr, v);
-                }
-                self.builder.ins().jump(header_block, &[]);
-                // let r = self.builder.use_var(self.data_ptr);
-
-                self.builder.switch_to_block(exit_block);
-                // self.builder.append_block_param(exit_block, self.int);
-                // self.builder.append_block_param(exit_block, self.int);
-                // let r = self.builder.block_params(exit_block);
-                // let (r1, r2) = (r[0], r[1]);
-                // self.builder.def_var(self.data_ptr, r1);
-                // self.builder.ins().store(MemFlags::new(), r2, r1, 0);
-                self.builder.seal_block(header_block);
-                // let r = self.builder.use_var(self.data_ptr);
-                // self.builder.def_var(self.data_ptr, r);
-                self.builder.seal_block(exit_block);
+/// A fresh tape, registered with the runtime
+fn tape() -> Vec<u64> {
+    let mut data = vec![0u64; 134217728]; // 1mib = 1048576, 128mib * 8 = 1gib
+    runtime::set_tape(&mut data, START);
+    data
+}
 
-                // dptr
-            } // _ => todo!(),
-        }
-    }
+extern "C" fn on_sigusr1(_: i32) {
+    vm::request_pause();
 }
 
-#[derive(Debug, Clone)]
-pub enum BFInstruction {
-    DataPtrIncrement,
-    DataPtrDecrement,
-    DataValueIncrement,
-    DataValueDecrement,
-    DataValuePutchar,
-    DataValueScanchar,
-    WhileDataValueNonZero(Vec<BFInstruction>),
+extern "C" fn on_sigint(_: i32) {
+    runtime::interrupt();
+    // the VM only stops when it pauses
+    vm::request_pause();
 }
 
-#[derive(Debug)]
-pub enum OptimizedBFInstruction {
-    DataPtrModify(i64),
-    DataValueModify(i64),
-    DataValuePutchar,
-    DataValueScanchar,
-    WhileDataValueNonZero(Vec<OptimizedBFInstruction>),
+/// Has Ctrl-C stop the run from here on and report where it got to, then
+/// run `also`, see `runtime::interrupted`.
+fn handle_interrupts(opts: &Options, also: impl Fn() + Send + 'static) {
+    let timer = Instant::now();
+    let dump_tape = opts.dump_tape;
+    runtime::on_interrupt(move |data, ptr| {
+        let mut result = RunResult::new(data, START, ptr, timer.elapsed());
+        result.interrupted = true;
+        eprint!("\n{}", result);
+        eprint!("{}", report::format_window(data, START, ptr, 8));
+        also();
+        if let Some(radix) = dump_tape {
+            eprint!("{}", report::format_tape(data, START, radix));
+        }
+    });
+    signal::on(signal::SIGINT, on_sigint);
+    signal::interrupt_reads(signal::SIGINT);
 }
 
-impl OptimizedBFInstruction {
-    pub fn optimize(
-        mut insns: Vec<BFInstruction>,
-        i: &mut u64,
-        l: u64,
-        top: bool,
-    ) -> Vec<OptimizedBFInstruction> {
-        let mut insns2 = Vec::new();
-        let mut counter = 0;
-        let mut ty = 0; // 1 = data ptr, 2 = value
-        while insns.len() > 0 {
-            let insn = insns.remove(0);
-            *i += 1;
-            if *i % 1000 == 0 {
-                print!("\rInfo: optimized instruction {}/{}", i, l);
-                stdout().flush().unwrap();
+/// Runs `ops` from `at`, writing a snapshot on every SIGUSR1 with --snapshot.
+fn run_vm(
+    ops: &[Op],
+    width: CellWidth,
+    data: &mut [u64],
+    at: Position,
+    opts: &Options,
+    timings: &mut Timings,
+) -> (usize, Duration) {
+    opts.dumper
+        .dump(Stage::Bytecode, "bytecode.txt", || bytecode::format(ops))
+        .unwrap_or_else(|e| dump::fail(e));
+    if opts.snapshot.is_some() {
+        match signal::SIGUSR1 {
+            Some(sigusr1) => signal::on(sigusr1, on_sigusr1),
+            None => {
+                eprintln!("error: --snapshot isn't supported on this platform");
+                std::process::exit(2);
             }
-            match insn {
-                BFInstruction::DataPtrDecrement => {
-                    if ty == 0 {
-                        ty = 1;
-                        counter = 0;
-                    } else if ty != 1 {
-                        insns2.push(OptimizedBFInstruction::DataValueModify(counter));
-                        counter = 0;
-                        ty = 1;
-                    }
-                    counter -= 1;
-                }
-                BFInstruction::DataPtrIncrement => {
-                    if ty == 0 {
-                        ty = 1;
-                        counter = 0;
-                    } else if ty != 1 {
-                        insns2.push(OptimizedBFInstruction::DataValueModify(counter));
-                        counter = 0;
-                        ty = 1;
-                    }
-                    counter += 1;
-                }
-                BFInstruction::DataValueDecrement => {
-                    if ty == 0 {
-                        ty = 2;
-                        counter = 0;
-                    } else if ty != 2 {
-                        insns2.push(OptimizedBFInstruction::DataPtrModify(counter));
-                        counter = 0;
-                        ty = 2;
-                    }
-                    counter -= 1;
-                }
-                BFInstruction::DataValueIncrement => {
-                    if ty == 0 {
-                        ty = 2;
-                        counter = 0;
-                    } else if ty != 2 {
-                        insns2.push(OptimizedBFInstruction::DataPtrModify(counter));
-                        counter = 0;
-                        ty = 2;
-                    }
-                    counter += 1;
-                }
-                x => {
-                    match ty {
-                        1 => insns2.push(OptimizedBFInstruction::DataPtrModify(counter)),
-                        2 => insns2.push(OptimizedBFInstruction::DataValueModify(counter)),
-                        _ => {}
+        }
+    }
+    handle_interrupts(opts, || {});
+    chatter!(opts, "Running VM, {} ops...\n", ops.len());
+    // bytecode and snapshots bring their own width
+    runtime::set_cell_width(width);
+    let vm = Vm::new(ops, width);
+    let timer = Instant::now();
+    let mut at = at;
+    let end = loop {
+        match vm.resume(data, at) {
+            Ok(end) => break end,
+            Err(paused) if runtime::INTERRUPTED.load(Ordering::Relaxed) => {
+                runtime::interrupted(&data[paused.ptr])
+            }
+            Err(paused) => {
+                if let Some(path) = &opts.snapshot {
+                    let input = runtime::unread_embedded_input();
+                    let snapshot = Snapshot::new(ops, width, data, paused.clone(), input);
+                    runtime::flush_output();
+                    match std::fs::write(path, snapshot.write()) {
+                        Ok(()) => chatter!(opts, "\nWrote snapshot to {}\n", path.display()),
+                        Err(e) => eprintln!("error: could not write {}: {}", path.display(), e),
                     }
-                    ty = 0;
-                    insns2.push(match x {
-                        BFInstruction::DataValuePutchar => OptimizedBFInstruction::DataValuePutchar,
-                        BFInstruction::DataValueScanchar => {
-                            OptimizedBFInstruction::DataValueScanchar
-                        }
-                        BFInstruction::WhileDataValueNonZero(inner) => {
-                            OptimizedBFInstruction::WhileDataValueNonZero(Self::optimize(
-                                inner, i, l, false,
-                            ))
-                        }
-                        _ => unreachable!(),
-                    })
                 }
+                at = paused;
             }
         }
-        match ty {
-            1 => insns2.push(OptimizedBFInstruction::DataPtrModify(counter)),
-            2 => insns2.push(OptimizedBFInstruction::DataValueModify(counter)),
-            _ => {}
-        }
-        if top {
-            print!("\rInfo: optimized instruction {}/{}", i, l);
-            stdout().flush().unwrap();
-        }
-        insns2
+    };
+    timings.run = timer.elapsed();
+    (end, timings.run)
+}
+
+/// Runs a program written by `bfi compile`, with the cell width it was compiled for.
+fn run_bytecode(fname: &str, source: &[u8], opts: &Options) {
+    let (ops, width) = bytecode::read(source).unwrap_or_else(|e| {
+        eprintln!("error: {}: {}", fname, e);
+        std::process::exit(1);
+    });
+    chatter!(opts, "Loaded bytecode for {}-bit cells\n", width.bits());
+    let mut timings = Timings::default();
+    let mut data = tape();
+    let (end, elapsed) = run_vm(
+        &ops,
+        width,
+        &mut data,
+        Position::start(START),
+        opts,
+        &mut timings,
+    );
+    finish(&data, end, elapsed, &timings, opts);
+}
+
+/// Resumes a run from a snapshot written by --snapshot.
+fn run_snapshot(fname: &str, source: &[u8], opts: &Options) {
+    let mut data = tape();
+    let snapshot = Snapshot::read(source, data.len()).unwrap_or_else(|e| {
+        eprintln!("error: {}: {}", fname, e);
+        std::process::exit(1);
+    });
+    runtime::set_embedded_input(snapshot.input.clone(), snapshot.then_stdin);
+    let restored = &mut data[snapshot.first..snapshot.first + snapshot.cells.len()];
+    restored.copy_from_slice(&snapshot.cells);
+    // the run so far used them, before the snapshot
+    if let (Some(first), Some(last)) = (restored.first(), restored.last()) {
+        runtime::touch(first as *const u64 as usize, last as *const u64 as usize);
     }
+    chatter!(
+        opts,
+        "Resuming snapshot at op {} of {}\n",
+        snapshot.at.pc,
+        snapshot.ops.len()
+    );
+    let mut timings = Timings::default();
+    let (end, elapsed) = run_vm(
+        &snapshot.ops,
+        snapshot.width,
+        &mut data,
+        snapshot.at,
+        opts,
+        &mut timings,
+    );
+    finish(&data, end, elapsed, &timings, opts);
+}
 
-    pub fn walk_len(v: &[Self]) -> u64 {
-        let mut len = 0;
-        for x in v {
-            match x {
-                Self::WhileDataValueNonZero(ref v) => len += 1 + Self::walk_len(v.as_slice()),
-                _ => len += 1,
-            }
-        }
-        len
+/// Where --coverage writes its listing and its lcov tracefile, next to the
+/// program. A program named like one of them would be overwritten, so that's
+/// an error before anything runs.
+fn coverage_paths(fname: &str) -> (PathBuf, PathBuf) {
+    let listing = Path::new(fname).with_extension("cov");
+    let lcov = Path::new(fname).with_extension("info");
+    if listing == Path::new(fname) || lcov == Path::new(fname) {
+        eprintln!(
+            "error: --coverage would write over {} itself, rename it first",
+            fname
+        );
+        std::process::exit(2);
     }
+    (listing, lcov)
 }
 
-impl BFInstruction {
-    pub fn parse_char(c: char) -> Option<Self> {
-        match c {
-            '>' => Some(BFInstruction::DataPtrIncrement),
-            '<' => Some(BFInstruction::DataPtrDecrement),
-            '+' => Some(BFInstruction::DataValueIncrement),
-            '-' => Some(BFInstruction::DataValueDecrement),
-            '.' => Some(BFInstruction::DataValuePutchar),
-            ',' => Some(BFInstruction::DataValueScanchar),
-            _ => None,
+/// Writes what `counters` counted next to `fname`, as a listing and for lcov,
+/// returning a summary.
+fn write_coverage(fname: &str, code: &str, tokens: &[Token], counters: &Counters) -> String {
+    let coverage = Coverage::new(code, tokens, &counters.counts());
+    let (listing, lcov) = coverage_paths(fname);
+    for (path, contents) in [
+        (&listing, coverage.listing(fname)),
+        (&lcov, coverage.lcov(fname)),
+    ] {
+        if let Err(e) = std::fs::write(path, contents) {
+            eprintln!("error: could not write {}: {}", path.display(), e);
+            std::process::exit(1);
         }
     }
+    let (run, instructions) = coverage.instructions();
+    let (entered, blocks) = coverage.blocks();
+    format!(
+        "{} of {} instructions run, {} of {} loops and procedures entered, written to {} and {}",
+        run,
+        instructions,
+        entered,
+        blocks,
+        listing.display(),
+        lcov.display()
+    )
+}
 
-    pub fn walk_len(v: &[Self]) -> u64 {
-        let mut len = 0;
-        for x in v {
-            match x {
-                Self::WhileDataValueNonZero(ref v) => len += 1 + Self::walk_len(v.as_slice()),
-                _ => len += 1,
+/// Sets up --replay and --record, in that order so a replay can be recorded again.
+fn setup_input(opts: &Options) {
+    if let Some(path) = &opts.replay {
+        let bytes = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| replay::parse(&text));
+        match bytes {
+            Ok(bytes) => runtime::replay_input(bytes),
+            Err(e) => {
+                eprintln!("error: {}: {}", path.display(), e);
+                std::process::exit(1);
             }
         }
-        len
+    }
+    if let Some(path) = &opts.record {
+        if let Err(e) = runtime::record_input(path) {
+            eprintln!("error: could not write {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
     }
 }
 
-// pub struct ParserNestingState(Vec<BFInstruction>, Option<Box<ParserNestingState>>);
-
-#[derive(Debug)]
-pub struct Parser {
-    // nesting: Option<ParserNestingState>,
-    insns: Vec<BFInstruction>,
-    s: Vec<char>,
-    ptr: usize,
+/// Reads the program `fname` for `fmt` and `lint`, exiting with `status` if
+/// it can't.
+fn read_source(fname: &str, status: i32) -> String {
+    std::fs::read_to_string(fname).unwrap_or_else(|e| {
+        eprintln!("error: could not read {}: {}", fname, e);
+        std::process::exit(status);
+    })
 }
 
-impl Parser {
-    pub fn from_str(s: &str) -> Self {
-        Self {
-            // nesting: None,
-            insns: Vec::new(),
-            s: s.chars().collect(),
-            ptr: 0,
-        }
+/// Prints the report once the program is done.
+fn finish(data: &[u64], end: usize, elapsed: Duration, timings: &Timings, opts: &Options) {
+    runtime::flush_output();
+    let result = RunResult::new(data, START, end, elapsed);
+    eprint!("{}", result);
+    if opts.timings {
+        eprint!("{}", timings);
     }
-
-    pub fn parse(&mut self) -> Vec<BFInstruction> {
-        while self.ptr < self.s.len() {
-            self.parse_step();
-        }
-        std::mem::take(&mut self.insns)
+    if let Some(radix) = opts.dump_tape {
+        eprint!("{}", report::format_tape(data, START, radix));
     }
-
-    fn parse_step(&mut self) {
-        match self.s[self.ptr] {
-            c @ ('>' | '<' | '+' | '-' | '.' | ',') => {
-                self.ptr += 1;
-                self.insns.push(BFInstruction::parse_char(c).unwrap());
-            }
-            '[' => {
-                self.ptr += 1;
-                let mut saved_insns = std::mem::take(&mut self.insns);
-                while self.s[self.ptr] != ']' && self.ptr < self.s.len() {
-                    self.parse_step();
-                    // insns.push(self.s[self.ptr]);
-                    // self.ptr += 1;
-                }
-                let err = self.ptr >= self.s.len();
-                self.ptr += 1; // account for ']'
-                if err {
-                    panic!("Unmatched open bracket, parser state = {:?}", self);
-                }
-                // self.ptr += 1;
-                saved_insns.push(BFInstruction::WhileDataValueNonZero(std::mem::take(
-                    &mut self.insns,
-                )));
-                self.insns = saved_insns;
-            }
-            // ']' omitted since parsed above^
-            ']' => panic!("Unmatched close bracket, parser state = {:?}", self),
-            _ => self.ptr += 1,
-        }
+    if opts.exit_code {
+        std::process::exit(result.value as i32);
     }
 }
 
-// // JIT bindings
-// #[link(name = "c")]
-// extern "C" {
-//     pub fn putchar(c: u64);
-//     pub fn getchar() -> u64;
-// }
-
-// #[no_mangle]
-// pub extern "C" fn putchar(c: u64) {
-//     print!("{}", unsafe { char::from_u32_unchecked(c as _) });
-//     stdout().flush().unwrap();
-// }
-
-// #[no_mangle]
-// pub extern "C" fn scanchar() -> u64 {
-//     let mut arr = [0; 1];
-//     stdin().read_exact(&mut arr).unwrap();
-//     arr[0] as _
-// }
-
-type BFJitFunction = extern "C" fn(*mut u64) -> u64;
-
 // fn yes() {
 //     let c = scanchar();
 //     println!("char = {}", c);
@@ -550,11 +354,110 @@ fn main() {
     // let code = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
     // let code = "";
     // let mut code = String::new();
-    print!("Filename to load: ");
-    stdout().flush().unwrap();
-    let mut fname = String::new();
-    stdin().read_line(&mut fname).unwrap();
-    let code = std::fs::read_to_string(fname.trim()).unwrap();
+    let opts = match Options::parse(std::env::args().skip(1)) {
+        Ok(opts) => opts,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("error: {}", e);
+            }
+            eprint!("{}", USAGE);
+            std::process::exit(if e.is_empty() { 0 } else { 2 });
+        }
+    };
+    let lexer = opts.dialect.lexer(opts.extensions).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        std::process::exit(2);
+    });
+    if let Err(e) = opts.dumper.create_dir() {
+        eprintln!("error: could not create dump directory: {}", e);
+        std::process::exit(1);
+    }
+    runtime::set_io_modes(opts.input_mode, opts.output_mode);
+    runtime::set_cell_width(opts.cell_width);
+    if opts.command == Command::Repl {
+        repl::Repl::new(make_jit(&opts), lexer).run();
+        return;
+    }
+    if opts.command == Command::Fmt {
+        let mut code = String::new();
+        match &opts.file {
+            Some(f) => code = read_source(f, 1),
+            None => {
+                stdin().read_to_string(&mut code).unwrap();
+            }
+        }
+        let out = if opts.minify {
+            fmt::check_brackets(&code, &lexer.tokenize(&code))
+                .map(|()| fmt::minify(&Parser::with_lexer(&code, &*lexer).parse()))
+        } else {
+            fmt::format(&code, &*lexer, opts.width)
+        };
+        match out {
+            Ok(out) => print!("{}", out),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if opts.command == Command::Lint {
+        let fname = opts.file.clone().unwrap_or_else(|| {
+            eprintln!("error: bfi lint needs a file");
+            std::process::exit(2);
+        });
+        // 1 means there were warnings
+        let code = read_source(&fname, 2);
+        let tokens = lexer.tokenize(&code);
+        if let Err(e) = fmt::check_brackets(&code, &tokens) {
+            eprintln!("error: {}: {}", fname, e);
+            std::process::exit(2);
+        }
+        let insns = Parser::with_lexer(&code, &*lexer).parse();
+        let warnings = lint::lint(&insns, &tokens);
+        if opts.json {
+            print!("{}", lint::format_json(&code, &warnings));
+        } else {
+            print!("{}", lint::format_human(&fname, &code, &warnings));
+        }
+        std::process::exit(if warnings.is_empty() { 0 } else { 1 });
+    }
+    let fname = match opts.file.clone() {
+        Some(f) => f,
+        None => {
+            print!("Filename to load: ");
+            stdout().flush().unwrap();
+            let mut fname = String::new();
+            stdin().read_line(&mut fname).unwrap();
+            fname.trim().to_string()
+        }
+    };
+    let source = std::fs::read(&fname).unwrap();
+    if opts.command == Command::Run {
+        setup_input(&opts);
+    }
+    if source.starts_with(bytecode::MAGIC) {
+        if opts.command == Command::Compile {
+            eprintln!("error: {} is bytecode already", fname);
+            std::process::exit(2);
+        }
+        run_bytecode(&fname, &source, &opts);
+        return;
+    }
+    if source.starts_with(snapshot::MAGIC) {
+        if opts.command == Command::Compile {
+            eprintln!("error: {} is a snapshot", fname);
+            std::process::exit(2);
+        }
+        run_snapshot(&fname, &source, &opts);
+        return;
+    }
+    let mut code = String::from_utf8(source).unwrap();
+    if let Some(then_stdin) = opts.bang_input {
+        let (program, input) = lexer::split_embedded_input(&*lexer, &code);
+        runtime::set_embedded_input(input.unwrap_or("").as_bytes().to_vec(), then_stdin);
+        code = program.to_string();
+    }
     // let code = ",.";
     // let code = "+[-->-[>>+>-----<<]<--<---]>-.>>>+.>>..+++[.>]<<<<.+++.------.<<-.>>>>+.";
     // let code = "
@@ -568,33 +471,176 @@ fn main() {
     // let code = "+++++";
     // let code = "+>+>,-[-[->+<]<<[->>>>+>+<<<<<]>[->>>>>+>+<<<<<<]>>>[-<<<<+>>>>]>[-<<<+>>>]>[-<<<<<+>>>>>]>[-<<<<<+>>>>>]<<<<]";
     // let code = "++++[-][-]+++++[-]+++++++";
-    let mut parser = Parser::from_str(&code);
-    let insns = parser.parse();
-    println!("Parsing done");
-    print!("Walking length of unoptimized code...");
-    stdout().flush().unwrap();
-    let l = BFInstruction::walk_len(&insns);
-    println!(" {} instructions", l);
-    let mut i = 0;
-    let opt = OptimizedBFInstruction::optimize(insns, &mut i, l, true);
-    println!();
-    println!("Optimizing done");
-    print!("Walking length of optimized code...");
-    stdout().flush().unwrap();
+    let cache = if opts.no_cache { None } else { Cache::open() };
+    let key = cache::Key::new(&code, &optimizer_options(&*lexer, &opts));
+    // the AST and optimizer dumps need the real thing
+    let mut timings = Timings::default();
+    let timer = Instant::now();
+    let cached = cache
+        .as_ref()
+        .filter(|_| !opts.dumper.wants(Stage::Ast) && !opts.dumper.wants(Stage::Opt))
+        .and_then(|c| c.load(&key));
+    if cached.is_some() {
+        timings.cache_load = Some(timer.elapsed());
+    }
+    let opt = match cached {
+        Some(opt) => {
+            chatter!(opts, "Loaded optimized code from cache\n");
+            opt
+        }
+        None => {
+            let opt = parse_and_optimize(&code, &*lexer, &opts, &mut timings);
+            if let Some(c) = &cache {
+                c.store(&key, &opt);
+            }
+            opt
+        }
+    };
+    chatter!(opts, "Walking length of optimized code...");
     let l = OptimizedBFInstruction::walk_len(&opt);
-    println!(" {} instructions", l);
+    chatter!(opts, " {} instructions\n", l);
+    let forks = OptimizedBFInstruction::count_forks(&opt);
+    let mut procedures = Vec::new();
+    OptimizedBFInstruction::collect_procedures(&opt, &mut procedures);
+    if procedures
+        .iter()
+        .any(|p| OptimizedBFInstruction::count_forks(p) > 0)
+    {
+        eprintln!("error: `Y` can't be used inside a procedure");
+        std::process::exit(2);
+    }
+    if opts.command == Command::Compile {
+        if forks > 0 {
+            eprintln!("error: `Y` can't be compiled to bytecode");
+            std::process::exit(2);
+        }
+        let ops = bytecode::compile(&opt);
+        opts.dumper
+            .dump(Stage::Bytecode, "bytecode.txt", || bytecode::format(&ops))
+            .unwrap_or_else(|e| dump::fail(e));
+        let out = opts
+            .out
+            .clone()
+            .unwrap_or_else(|| Path::new(&fname).with_extension("bfc"));
+        if let Err(e) = std::fs::write(&out, bytecode::write(&ops, opts.cell_width)) {
+            eprintln!("error: could not write {}: {}", out.display(), e);
+            std::process::exit(1);
+        }
+        chatter!(opts, "Wrote {} ops to {}\n", ops.len(), out.display());
+        return;
+    }
+    if forks > 0 && opts.engine != Engine::Jit {
+        eprintln!("error: `Y` needs --engine jit");
+        std::process::exit(2);
+    }
+    if opts.snapshot.is_some() && opts.engine != Engine::Vm {
+        eprintln!("error: --snapshot needs --engine vm");
+        std::process::exit(2);
+    }
+    if opts.coverage && opts.engine != Engine::Jit {
+        eprintln!("error: --coverage needs --engine jit");
+        std::process::exit(2);
+    }
+    if opts.coverage {
+        coverage_paths(&fname);
+    }
     // yes();
     // println!("{:?}", insns);
-    println!("Running JIT...");
-    let mut jit = JIT::new();
-    let ptr = jit.jit(&opt);
-    println!("JIT'ed into {:x?}", ptr);
-    // println!("Attach to me!")
-    // stdin().read_line(&mut String::new()).unwrap();
-    let mut data = vec![0u64; 134217728]; // 1mib = 1048576, 128mib * 8 = 1gib
-    let f = unsafe { std::mem::transmute::<_, BFJitFunction>(ptr) };
-    println!("All engines go!");
-    let res = f(unsafe { data.as_mut_ptr().add(9000000) });
-    println!("Wew done running, got {}", res);
+    let mut data = tape();
+    let (end, elapsed) = match opts.engine {
+        Engine::Jit => {
+            chatter!(opts, "Running JIT...\n");
+            let mut jit = make_jit(&opts);
+            let ptr = jit.jit(&opt);
+            chatter!(opts, "Translation done\n");
+            chatter!(opts, "JIT'ed into {:x?}\n", ptr);
+            chatter!(
+                opts,
+                "Generated {} bytes of machine code, {} loops outlined, {} chunks\n",
+                jit.code_size(),
+                jit.outlined_loops(),
+                jit.chunks()
+            );
+            if opts.checked {
+                chatter!(opts, "Emitted {} bounds checks\n", jit.bounds_checks());
+            }
+            // println!("Attach to me!")
+            // stdin().read_line(&mut String::new()).unwrap();
+            // only needed to map coverage back onto the source
+            let tokens = match jit.counters() {
+                Some(_) => lexer.tokenize(&code),
+                None => Vec::new(),
+            };
+            match jit.counters() {
+                Some(counters) => {
+                    let (fname, code, tokens) = (fname.clone(), code.clone(), tokens.clone());
+                    let counters = counters.clone();
+                    handle_interrupts(&opts, move || {
+                        let summary = write_coverage(&fname, &code, &tokens, &counters);
+                        eprintln!("  coverage:  {}", summary);
+                    });
+                }
+                None => handle_interrupts(&opts, || {}),
+            }
+            chatter!(opts, "All engines go!\n");
+            let base = data.as_mut_ptr();
+            let start = unsafe { base.add(START) };
+            let timer = Instant::now();
+            let end = if forks > 0 {
+                let f = unsafe { std::mem::transmute::<*const u8, runtime::ForkFunction>(ptr) };
+                runtime::run_forking(f, start, opts.schedule)
+            } else {
+                let f = unsafe { std::mem::transmute::<*const u8, BFJitFunction>(ptr) };
+                f(start)
+            };
+            timings.translate = jit.translate_time();
+            timings.compile = jit.compile_time();
+            timings.run = timer.elapsed();
+            if let Some(counters) = jit.counters() {
+                let summary = write_coverage(&fname, &code, &tokens, counters);
+                runtime::flush_output();
+                chatter!(opts, "Coverage: {}\n", summary);
+            }
+            (unsafe { end.offset_from(base) as usize }, timings.run)
+        }
+        Engine::Tiered => {
+            chatter!(
+                opts,
+                "Running tiered, JIT threshold {}...\n",
+                opts.jit_threshold
+            );
+            let mut tiered = Tiered::new(make_jit(&opts), opts.jit_threshold);
+            handle_interrupts(&opts, || {});
+            let timer = Instant::now();
+            let ptr = tiered.run(&opt, &mut data, START);
+            let elapsed = timer.elapsed();
+            chatter!(
+                opts,
+                "Tiered: compiled {} of {} loops entered\n",
+                tiered.compiled_loops(),
+                tiered.seen_loops()
+            );
+            timings.translate = tiered.jit().translate_time();
+            timings.compile = tiered.jit().compile_time();
+            timings.run = elapsed
+                .saturating_sub(timings.translate)
+                .saturating_sub(timings.compile);
+            (ptr, elapsed)
+        }
+        Engine::Vm => {
+            let timer = Instant::now();
+            let ops = bytecode::compile(&opt);
+            timings.translate = timer.elapsed();
+            run_vm(
+                &ops,
+                opts.cell_width,
+                &mut data,
+                Position::start(START),
+                &opts,
+                &mut timings,
+            )
+        }
+    };
+    finish(&data, end, elapsed, &timings, &opts);
     // println!("Data: {:?}", data);
 }
@@ -0,0 +1,173 @@
+//! Moves code into functions of its own so the JIT compiles several small
+//! functions instead of one huge one.
+//!
+//! Loops that show up more than once are found by hash-consing the instruction
+//! tree, so identical subtrees get the same id. Each is compiled once and
+//! called from every place it appears. Long instruction sequences are split
+//! into chunks, which are called one after the other.
+
+use std::collections::HashMap;
+
+use crate::OptimizedBFInstruction;
+
+/// Loops shorter than this stay inline, the call would cost more than it saves
+const MIN_LEN: u64 = 8;
+
+/// One subtree, with its children replaced by their ids
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum Node {
+    Ptr(i64),
+    Value(i64),
+    Putchar,
+    Scanchar,
+    Loop(Vec<usize>),
+    Define(Vec<usize>),
+    Call,
+    Fork,
+}
+
+#[derive(Default)]
+pub struct Outlines<'a> {
+    /// One copy of every repeated loop
+    pub shared: Vec<&'a OptimizedBFInstruction>,
+    /// Which of `shared` each copy is, keyed by address
+    pub sites: HashMap<*const OptimizedBFInstruction, usize>,
+}
+
+/// Finds the repeated loops in `insns`, procedure bodies included. Loops that
+/// define procedures or fork are left alone, both need the function they're in.
+pub fn find(insns: &[OptimizedBFInstruction]) -> Outlines<'_> {
+    let mut interner = Interner::default();
+    interner.sequence(insns);
+    let mut counts = HashMap::new();
+    for (id, _) in &interner.loops {
+        *counts.entry(*id).or_insert(0) += 1;
+    }
+    let mut outlines = Outlines::default();
+    let mut index = HashMap::new();
+    for (id, insn) in interner.loops {
+        if counts[&id] < 2 {
+            continue;
+        }
+        let i = *index.entry(id).or_insert_with(|| {
+            outlines.shared.push(insn);
+            outlines.shared.len() - 1
+        });
+        outlines.sites.insert(insn, i);
+    }
+    outlines
+}
+
+/// Splits every instruction sequence longer than `limit` into chunks of at
+/// most `limit` instructions. Loops bigger than that stay where they are and
+/// have their bodies split in turn. Repeated loops are only split once, in the
+/// copy in `outlines.shared`.
+pub fn chunks<'a>(
+    insns: &'a [OptimizedBFInstruction],
+    limit: u64,
+    outlines: &Outlines,
+) -> Vec<&'a [OptimizedBFInstruction]> {
+    let mut out = Vec::new();
+    split(insns, limit, outlines, &mut out);
+    out
+}
+
+fn split<'a>(
+    insns: &'a [OptimizedBFInstruction],
+    limit: u64,
+    outlines: &Outlines,
+    out: &mut Vec<&'a [OptimizedBFInstruction]>,
+) {
+    if OptimizedBFInstruction::walk_len(insns) <= limit {
+        return;
+    }
+    // chunks much smaller than the limit aren't worth a call, empty ones
+    // can't be called at all
+    let flush = |chunk, len, out: &mut Vec<_>| {
+        if len > 0 && len >= limit / 8 {
+            out.push(chunk);
+        }
+    };
+    let (mut start, mut len) = (0, 0);
+    for (i, insn) in insns.iter().enumerate() {
+        let l = OptimizedBFInstruction::walk_len(std::slice::from_ref(insn));
+        if l > limit {
+            flush(&insns[start..i], len, out);
+            let copy = match outlines.sites.get(&(insn as *const _)) {
+                Some(&shared) => !std::ptr::eq(outlines.shared[shared], insn),
+                None => false,
+            };
+            match insn {
+                OptimizedBFInstruction::WhileDataValueNonZero(body)
+                | OptimizedBFInstruction::DefineProcedure(body)
+                    if !copy =>
+                {
+                    split(body, limit, outlines, out)
+                }
+                _ => {}
+            }
+            start = i + 1;
+            len = 0;
+            continue;
+        }
+        if len + l > limit {
+            flush(&insns[start..i], len, out);
+            start = i;
+            len = 0;
+        }
+        len += l;
+    }
+    flush(&insns[start..], len, out);
+}
+
+#[derive(Default)]
+struct Interner<'a> {
+    ids: HashMap<Node, usize>,
+    /// Every loop worth outlining with its id, in program order
+    loops: Vec<(usize, &'a OptimizedBFInstruction)>,
+}
+
+impl<'a> Interner<'a> {
+    /// Interns every instruction. Returns their ids, whether all of them can be
+    /// moved into another function and how many instructions they add up to.
+    fn sequence(&mut self, insns: &'a [OptimizedBFInstruction]) -> (Vec<usize>, bool, u64) {
+        let mut movable = true;
+        let mut len = 0;
+        let ids = insns
+            .iter()
+            .map(|insn| {
+                let (id, m, l) = self.insn(insn);
+                movable &= m;
+                len += l;
+                id
+            })
+            .collect();
+        (ids, movable, len)
+    }
+
+    fn insn(&mut self, insn: &'a OptimizedBFInstruction) -> (usize, bool, u64) {
+        use OptimizedBFInstruction::*;
+        let (node, movable, len) = match insn {
+            DataPtrModify(x) => (Node::Ptr(*x), true, 1),
+            DataValueModify(x) => (Node::Value(*x), true, 1),
+            DataValuePutchar => (Node::Putchar, true, 1),
+            DataValueScanchar => (Node::Scanchar, true, 1),
+            WhileDataValueNonZero(body) => {
+                let (ids, movable, len) = self.sequence(body);
+                (Node::Loop(ids), movable, len + 1)
+            }
+            DefineProcedure(body) => {
+                let (ids, _, len) = self.sequence(body);
+                (Node::Define(ids), false, len + 1)
+            }
+            CallProcedure => (Node::Call, true, 1),
+            Fork => (Node::Fork, false, 1),
+        };
+        let next = self.ids.len();
+        let id = *self.ids.entry(node).or_insert(next);
+        if matches!(insn, WhileDataValueNonZero(_)) && movable && len >= MIN_LEN {
+            self.loops.push((id, insn));
+        }
+        (id, movable, len)
+    }
+}
@@ -0,0 +1,112 @@
+//! Works out which cells code touches relative to where it starts, so checked
+//! mode can check a whole window of the tape once instead of every access.
+
+use std::collections::HashMap;
+
+use crate::OptimizedBFInstruction;
+
+/// Cells touched by some code, as offsets from the cell it starts on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    /// Lowest and highest offset accessed, `None` if nothing is
+    pub accessed: Option<(i64, i64)>,
+    /// Where the pointer ends up
+    pub net: i64,
+}
+
+impl Window {
+    pub const EMPTY: Window = Window {
+        accessed: None,
+        net: 0,
+    };
+
+    fn access(&mut self, offset: i64) {
+        self.accessed = Some(match self.accessed {
+            Some((lo, hi)) => (lo.min(offset), hi.max(offset)),
+            None => (offset, offset),
+        });
+    }
+
+    /// Extends `self` with `other` running after it.
+    pub fn then(mut self, other: Window) -> Window {
+        if let Some((lo, hi)) = other.accessed {
+            self.access(self.net + lo);
+            self.access(self.net + hi);
+        }
+        self.net += other.net;
+        self
+    }
+}
+
+/// The window of every loop whose window is known: ones that end on the cell
+/// they started on every iteration, and only contain such loops.
+/// Loops that move the pointer by a variable amount, call a procedure or fork
+/// have no window.
+pub struct Ranges {
+    loops: HashMap<*const OptimizedBFInstruction, Window>,
+}
+
+impl Ranges {
+    pub fn new(insns: &[OptimizedBFInstruction]) -> Self {
+        let mut r = Self {
+            loops: HashMap::new(),
+        };
+        r.sequence(insns);
+        r
+    }
+
+    /// Window of a single instruction, `None` if it isn't known.
+    pub fn insn(&self, insn: &OptimizedBFInstruction) -> Option<Window> {
+        let mut w = Window::EMPTY;
+        match insn {
+            OptimizedBFInstruction::DataPtrModify(x) => w.net = *x,
+            OptimizedBFInstruction::DataValueModify(_)
+            | OptimizedBFInstruction::DataValuePutchar
+            | OptimizedBFInstruction::DataValueScanchar
+            | OptimizedBFInstruction::DefineProcedure(_) => w.access(0),
+            OptimizedBFInstruction::WhileDataValueNonZero(_) => {
+                return self.loops.get(&(insn as *const _)).copied()
+            }
+            // the child carries on from here with a pointer of its own
+            OptimizedBFInstruction::CallProcedure | OptimizedBFInstruction::Fork => return None,
+        }
+        Some(w)
+    }
+
+    /// Window of the cells `insn` accesses whenever it runs: for a loop only
+    /// its header, since the body might not run. `None` if it isn't known.
+    pub fn always(&self, insn: &OptimizedBFInstruction) -> Option<Window> {
+        let w = self.insn(insn)?;
+        match insn {
+            OptimizedBFInstruction::WhileDataValueNonZero(_) => Some(Window {
+                accessed: Some((0, 0)),
+                net: 0,
+            }),
+            _ => Some(w),
+        }
+    }
+
+    /// Records the windows of all loops in `insns` and returns the window of
+    /// `insns` itself, if known.
+    fn sequence(&mut self, insns: &[OptimizedBFInstruction]) -> Option<Window> {
+        let mut w = Some(Window::EMPTY);
+        for insn in insns {
+            match insn {
+                OptimizedBFInstruction::WhileDataValueNonZero(body) => {
+                    let body = self.sequence(body);
+                    if let Some(body) = body.filter(|b| b.net == 0) {
+                        let mut header = Window::EMPTY;
+                        header.access(0);
+                        self.loops.insert(insn, header.then(body));
+                    }
+                }
+                OptimizedBFInstruction::DefineProcedure(body) => {
+                    self.sequence(body);
+                }
+                _ => {}
+            }
+            w = w.and_then(|w| Some(w.then(self.insn(insn)?)));
+        }
+        w
+    }
+}
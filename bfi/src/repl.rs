@@ -0,0 +1,272 @@
+use std::io::{stdout, Write};
+
+use crate::{
+    lexer::{Lexer, Token, TokenKind},
+    runtime, OptimizedBFInstruction, Parser, JIT,
+};
+
+extern "C" {
+    fn getchar() -> i32;
+}
+
+const HELP: &str = "\
+Each line of brainfuck runs against a tape that persists between lines.
+Lines with unclosed brackets continue on the next line.
+
+Commands:
+    :tape [N]    show N cells either side of the pointer (default 8)
+    :ptr N       move the pointer to cell N (relative to the start cell)
+    :reset       clear the tape and move the pointer back to the start
+    :load FILE   run the contents of FILE
+    :help        show this message
+    :quit        exit, as does end of input
+";
+
+const TAPE_LEN: usize = 1 << 20;
+const START: usize = TAPE_LEN / 2;
+
+/// `bfi repl`: every line is parsed, optimized and compiled into its own
+/// function, which then runs on the shared tape.
+pub struct Repl {
+    jit: JIT,
+    lexer: Box<dyn Lexer>,
+    tape: Vec<u64>,
+    ptr: usize,
+    snippets: usize,
+}
+
+impl Repl {
+    pub fn new(jit: JIT, lexer: Box<dyn Lexer>) -> Self {
+        let mut tape = vec![0; TAPE_LEN];
+        runtime::set_tape(&mut tape, START);
+        Self {
+            jit,
+            lexer,
+            tape,
+            ptr: START,
+            snippets: 0,
+        }
+    }
+
+    pub fn run(&mut self) {
+        println!("bfi repl, :help for commands");
+        while let Some(mut code) = prompt("bf> ") {
+            if let Some(cmd) = code.trim().strip_prefix(':') {
+                if !self.command(cmd) {
+                    break;
+                }
+                continue;
+            }
+            while let Brackets::Open = brackets(&*self.lexer, &code) {
+                match prompt("... ") {
+                    Some(line) => code.push_str(&line),
+                    None => return,
+                }
+            }
+            self.exec(&code);
+        }
+    }
+
+    /// Returns false when the REPL should exit.
+    fn command(&mut self, cmd: &str) -> bool {
+        let mut words = cmd.split_whitespace();
+        match (words.next().unwrap_or(""), words.next()) {
+            ("quit" | "q", _) => return false,
+            ("help" | "h", _) => print!("{}", HELP),
+            ("tape" | "t", n) => match n.map_or(Ok(8), str::parse) {
+                Ok(n) => self.show_tape(n),
+                Err(e) => println!("error: bad cell count: {}", e),
+            },
+            ("ptr" | "p", Some(n)) => match n.parse::<i64>() {
+                Ok(n) if (START as i64 + n) >= 0 && ((START as i64 + n) as usize) < TAPE_LEN => {
+                    self.ptr = (START as i64 + n) as usize
+                }
+                Ok(_) => println!("error: cell {} is off the tape", n),
+                Err(e) => println!("error: bad cell: {}", e),
+            },
+            ("reset" | "r", _) => {
+                self.tape.iter_mut().for_each(|c| *c = 0);
+                self.ptr = START;
+            }
+            ("load" | "l", Some(_)) => {
+                let path = cmd.split_once(char::is_whitespace).unwrap().1.trim();
+                match std::fs::read_to_string(path) {
+                    Ok(code) => self.exec(&code),
+                    Err(e) => println!("error: could not read {}: {}", path, e),
+                }
+            }
+            _ => println!("error: unknown command `:{}`, see :help", cmd),
+        }
+        true
+    }
+
+    fn exec(&mut self, code: &str) {
+        match brackets(&*self.lexer, code) {
+            Brackets::Balanced => {}
+            Brackets::Open => {
+                println!("error: unclosed brackets");
+                return;
+            }
+            Brackets::Mismatch(e) => {
+                println!("error: {}", e);
+                return;
+            }
+        }
+        let insns = Parser::with_lexer(code, &*self.lexer).parse();
+        if insns.is_empty() {
+            return;
+        }
+        let l = crate::BFInstruction::walk_len(&insns);
+        let opt = OptimizedBFInstruction::optimize(insns, &mut 0, l, false);
+        if OptimizedBFInstruction::count_forks(&opt) > 0 {
+            println!("error: `Y` can only be used when running a file");
+            return;
+        }
+        let f = self
+            .jit
+            .jit_fragment(&format!("snippet{}", self.snippets), &opt);
+        self.snippets += 1;
+
+        let base = self.tape.as_mut_ptr();
+        let end = f(unsafe { base.add(self.ptr) });
+        self.ptr = unsafe { end.offset_from(base) } as usize;
+        runtime::flush_output();
+    }
+
+    fn show_tape(&self, n: usize) {
+        let lo = self.ptr.saturating_sub(n);
+        let hi = (self.ptr + n).min(TAPE_LEN - 1);
+        let (mut idx, mut val, mut mark) = (String::new(), String::new(), String::new());
+        for i in lo..=hi {
+            let v = self.tape[i].to_string();
+            let w = v.len().max(4);
+            idx.push_str(&format!(" {:>w$}", i as i64 - START as i64, w = w));
+            val.push_str(&format!(" {:>w$}", v, w = w));
+            let m = if i == self.ptr { "^" } else { "" };
+            mark.push_str(&format!(" {:>w$}", m, w = w));
+        }
+        println!("{}\n{}\n{}", idx, val, mark.trim_end());
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Brackets {
+    Balanced,
+    /// Some are still open, the code goes on on the next line
+    Open,
+    /// A closing bracket without a matching opening one
+    Mismatch(String),
+}
+
+/// Matches up loop brackets and pbrain parentheses in `code`, which the
+/// parser can't take unbalanced.
+fn brackets(lexer: &dyn Lexer, code: &str) -> Brackets {
+    let mut open = Vec::new();
+    for token in lexer.tokenize(code) {
+        let opener = match token.kind {
+            TokenKind::LoopStart | TokenKind::ProcedureStart => {
+                open.push(token);
+                continue;
+            }
+            TokenKind::LoopEnd => TokenKind::LoopStart,
+            TokenKind::ProcedureEnd => TokenKind::ProcedureStart,
+            _ => continue,
+        };
+        let text = |t: &Token| code[t.span.clone()].to_string();
+        match open.pop() {
+            Some(o) if o.kind == opener => {}
+            Some(o) => {
+                return Brackets::Mismatch(format!("`{}` closes `{}`", text(&token), text(&o)))
+            }
+            None => return Brackets::Mismatch(format!("unmatched `{}`", text(&token))),
+        }
+    }
+    if open.is_empty() {
+        Brackets::Balanced
+    } else {
+        Brackets::Open
+    }
+}
+
+/// Reads a line through libc, so `,` in a snippet and the REPL itself
+/// consume the same buffered stdin.
+fn prompt(p: &str) -> Option<String> {
+    // JIT'd code writes through libc's stdout, which needs flushing before our prompt
+    runtime::flush_output();
+    print!("{}", p);
+    stdout().flush().unwrap();
+    let mut line = Vec::new();
+    loop {
+        match unsafe { getchar() } {
+            -1 if line.is_empty() => {
+                println!();
+                return None;
+            }
+            -1 => break,
+            c => {
+                line.push(c as u8);
+                if c == b'\n' as i32 {
+                    break;
+                }
+            }
+        }
+    }
+    Some(String::from_utf8_lossy(&line).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{Dialect, Extensions};
+
+    fn check(code: &str) -> Brackets {
+        let extensions = Extensions {
+            pbrain: true,
+            ..Extensions::default()
+        };
+        brackets(&*Dialect::Brainfuck.lexer(extensions).unwrap(), code)
+    }
+
+    #[test]
+    fn balanced_and_open() {
+        assert_eq!(check("+[->+<]"), Brackets::Balanced);
+        assert_eq!(check("(+[-]):"), Brackets::Balanced);
+        assert_eq!(check("[(["), Brackets::Open);
+        assert_eq!(check("[[-]"), Brackets::Open);
+    }
+
+    #[test]
+    fn mismatches() {
+        assert_eq!(
+            check("[)"),
+            Brackets::Mismatch("`)` closes `[`".to_string())
+        );
+        assert_eq!(
+            check("(]"),
+            Brackets::Mismatch("`]` closes `(`".to_string())
+        );
+        assert_eq!(
+            check("([)]"),
+            Brackets::Mismatch("`)` closes `[`".to_string())
+        );
+        assert_eq!(
+            check("+]["),
+            Brackets::Mismatch("unmatched `]`".to_string())
+        );
+        // a mismatch counts even while other brackets are still open
+        assert_eq!(
+            check("[[)"),
+            Brackets::Mismatch("`)` closes `[`".to_string())
+        );
+    }
+
+    #[test]
+    fn other_dialects() {
+        let ook = Dialect::Ook.lexer(Extensions::default()).unwrap();
+        assert_eq!(brackets(&*ook, "Ook! Ook? Ook? Ook!"), Brackets::Balanced);
+        assert_eq!(
+            brackets(&*ook, "Ook? Ook!"),
+            Brackets::Mismatch("unmatched `Ook? Ook!`".to_string())
+        );
+    }
+}
@@ -0,0 +1,55 @@
+//! Recordings of the input `,` read from stdin, written by `--record` and fed
+//! back by `--replay`.
+//!
+//! A recording is text with one byte per line: seconds since recording started
+//! when it was read, the byte's value, and the byte quoted for reading along.
+//! Only the value matters when replaying, so recordings can be written or
+//! edited by hand, and they work as test input in `tests/golden` too.
+//! Lines starting with `#` are comments.
+//!
+//! ```text
+//! # bfi input recording: seconds, byte
+//! 1.204311 108 'l'
+//! 1.204317 10 '\n'
+//! ```
+
+use std::{fmt::Write as _, time::Duration};
+
+pub const HEADER: &str = "# bfi input recording: seconds, byte\n";
+
+/// The line for `byte`, read `elapsed` after recording started
+pub fn line(elapsed: Duration, byte: u8) -> String {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{:.6} {} '{}'",
+        elapsed.as_secs_f64(),
+        byte,
+        (byte as char).escape_default()
+    )
+    .unwrap();
+    out
+}
+
+/// The bytes in a recording, in the order they were read.
+pub fn parse(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let time = fields.next().unwrap();
+        time.parse::<f64>()
+            .map_err(|_| format!("line {}: bad time `{}`", n + 1, time))?;
+        let byte = fields
+            .next()
+            .ok_or_else(|| format!("line {}: no byte after the time", n + 1))?;
+        bytes.push(
+            byte.parse()
+                .map_err(|_| format!("line {}: bad byte `{}`", n + 1, byte))?,
+        );
+    }
+    Ok(bytes)
+}
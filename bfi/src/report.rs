@@ -0,0 +1,158 @@
+use std::{fmt, ops::RangeInclusive, sync::atomic::Ordering, time::Duration};
+
+use crate::runtime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Radix {
+    Hex,
+    Dec,
+}
+
+/// What a program left behind, printed after it finishes.
+/// Cell numbers are relative to the cell the program started on.
+pub struct RunResult {
+    pub pointer: i64,
+    /// The cell under the pointer
+    pub value: u64,
+    /// Every cell the run touched, plus the start and final pointer
+    pub used: RangeInclusive<i64>,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub elapsed: Duration,
+    /// Stopped by Ctrl-C rather than finished
+    pub interrupted: bool,
+}
+
+impl RunResult {
+    pub fn new(tape: &[u64], start: usize, end: usize, elapsed: Duration) -> Self {
+        let (lo, hi) = match runtime::touched() {
+            Some(r) => (*r.start().min(&start), *r.end().max(&start)),
+            None => (start, start),
+        };
+        let rel = |i: usize| i as i64 - start as i64;
+        Self {
+            pointer: rel(end),
+            value: tape[end],
+            used: rel(lo.min(end))..=rel(hi.max(end)),
+            bytes_read: runtime::BYTES_READ.load(Ordering::Relaxed),
+            bytes_written: runtime::BYTES_WRITTEN.load(Ordering::Relaxed),
+            elapsed,
+            interrupted: false,
+        }
+    }
+}
+
+impl fmt::Display for RunResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let how = if self.interrupted {
+            "Interrupted after"
+        } else {
+            "Finished in"
+        };
+        writeln!(f, "{} {:.3?}", how, self.elapsed)?;
+        writeln!(f, "  pointer:   cell {} = {}", self.pointer, self.value)?;
+        writeln!(
+            f,
+            "  tape used: cells {} to {}",
+            self.used.start(),
+            self.used.end()
+        )?;
+        writeln!(f, "  read:      {} bytes", self.bytes_read)?;
+        writeln!(f, "  written:   {} bytes", self.bytes_written)
+    }
+}
+
+/// How long each phase of a run took, printed with `--timings`.
+/// Phases that were skipped, like parsing when the cache had the program, are `None`.
+#[derive(Debug, Default)]
+pub struct Timings {
+    pub cache_load: Option<Duration>,
+    pub parse: Option<Duration>,
+    pub merge: Option<Duration>,
+    pub unroll: Option<Duration>,
+    /// Building Cranelift IR, for tiered runs only of the loops that got hot.
+    /// For the VM, building its bytecode.
+    pub translate: Duration,
+    pub compile: Duration,
+    /// Running the program, without the time tiered runs spent compiling
+    pub run: Duration,
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Timings:")?;
+        let phases = [
+            ("cache load", self.cache_load),
+            ("parse", self.parse),
+            ("merge", self.merge),
+            ("unroll", self.unroll),
+            ("translate", Some(self.translate)),
+            ("compile", Some(self.compile)),
+            ("run", Some(self.run)),
+        ];
+        for (name, time) in phases.iter() {
+            if let Some(time) = time {
+                writeln!(f, "  {:<12}{:.3?}", format!("{}:", name), time)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Indices of the first and last nonzero cells, only looking at the cells
+/// the run touched since the rest are still 0.
+fn nonzero_range(tape: &[u64]) -> Option<RangeInclusive<usize>> {
+    let touched = runtime::touched()?;
+    let cells = &tape[touched.clone()];
+    let first = cells.iter().position(|&c| c != 0)?;
+    let last = cells.iter().rposition(|&c| c != 0)?;
+    Some(touched.start() + first..=touched.start() + last)
+}
+
+/// Lists the cells from the first nonzero one to the last, 8 to a row.
+pub fn format_tape(tape: &[u64], start: usize, radix: Radix) -> String {
+    let range = match nonzero_range(tape) {
+        Some(r) => r,
+        None => return "Tape is all zeros\n".to_string(),
+    };
+    let cells = &tape[range.clone()];
+    let width = cells
+        .iter()
+        .map(|c| match radix {
+            Radix::Hex => format!("{:x}", c).len(),
+            Radix::Dec => c.to_string().len(),
+        })
+        .max()
+        .unwrap();
+    let mut out = String::new();
+    for (row, chunk) in cells.chunks(8).enumerate() {
+        let first = (range.start() + row * 8) as i64 - start as i64;
+        out.push_str(&format!("{:>8}:", first));
+        for c in chunk {
+            match radix {
+                Radix::Hex => out.push_str(&format!(" {:0w$x}", c, w = width)),
+                Radix::Dec => out.push_str(&format!(" {:>w$}", c, w = width)),
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// The `radius` cells either side of the pointer at `ptr`, with the one under
+/// it in brackets.
+pub fn format_window(tape: &[u64], start: usize, ptr: usize, radius: usize) -> String {
+    let lo = ptr.saturating_sub(radius);
+    let hi = (ptr + radius + 1).min(tape.len());
+    let rel = |i: usize| i as i64 - start as i64;
+    let mut out = format!("  near it:   cells {} to {}:", rel(lo), rel(hi - 1));
+    for (i, c) in tape.iter().enumerate().take(hi).skip(lo) {
+        if i == ptr {
+            out.push_str(&format!(" [{}]", c));
+        } else {
+            out.push_str(&format!(" {}", c));
+        }
+    }
+    out.push('\n');
+    out
+}
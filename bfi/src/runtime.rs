@@ -0,0 +1,559 @@
+//! Functions and data that JIT'd code calls into.
+
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    fs::File,
+    io::{self, Write},
+    ops::RangeInclusive,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Condvar, Mutex, MutexGuard,
+    },
+    thread,
+    time::Instant,
+};
+
+use crate::{replay, CellWidth};
+
+extern "C" {
+    fn putchar(c: i32) -> i32;
+    fn getchar() -> i32;
+    fn fflush(stream: *mut std::ffi::c_void) -> i32;
+    fn _exit(status: i32) -> !;
+}
+
+/// Bytes `,` read and `.` wrote, for the report at the end of a run
+pub static BYTES_READ: AtomicU64 = AtomicU64::new(0);
+pub static BYTES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+
+/// How `.` writes a cell and `,` reads one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoMode {
+    /// A single byte, `.` writes the low 8 bits
+    Byte,
+    /// A Unicode scalar value, UTF-8 encoded
+    Utf8,
+    /// A signed decimal number, `.` ends it with a newline
+    Decimal,
+}
+
+impl IoMode {
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "byte" => Some(IoMode::Byte),
+            "utf8" => Some(IoMode::Utf8),
+            "decimal" => Some(IoMode::Decimal),
+            _ => None,
+        }
+    }
+}
+
+static INPUT_MODE: AtomicU8 = AtomicU8::new(IoMode::Byte as u8);
+static OUTPUT_MODE: AtomicU8 = AtomicU8::new(IoMode::Byte as u8);
+
+pub fn set_io_modes(input: IoMode, output: IoMode) {
+    INPUT_MODE.store(input as u8, Ordering::Relaxed);
+    OUTPUT_MODE.store(output as u8, Ordering::Relaxed);
+}
+
+/// Bits per cell, for `.` to print decimal numbers with the right sign
+static CELL_BITS: AtomicU32 = AtomicU32::new(64);
+
+pub fn set_cell_width(width: CellWidth) {
+    CELL_BITS.store(width.bits(), Ordering::Relaxed);
+}
+
+/// Reads a cell `bits` wide as a two's complement number.
+fn signed(c: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((c << shift) as i64) >> shift
+}
+
+fn load_mode(mode: &AtomicU8) -> IoMode {
+    match mode.load(Ordering::Relaxed) {
+        0 => IoMode::Byte,
+        1 => IoMode::Utf8,
+        _ => IoMode::Decimal,
+    }
+}
+
+/// Output collected instead of written to stdout, see `capture_output`
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+static CAPTURED: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+/// Makes `.` collect its output for `take_output` instead of writing it.
+pub fn capture_output() {
+    CAPTURING.store(true, Ordering::Relaxed);
+}
+
+/// Returns the output captured so far and starts over.
+pub fn take_output() -> Vec<u8> {
+    std::mem::take(&mut *CAPTURED.lock().unwrap())
+}
+
+fn write_bytes(bytes: &[u8]) {
+    if CAPTURING.load(Ordering::Relaxed) {
+        CAPTURED.lock().unwrap().extend_from_slice(bytes);
+    } else {
+        for &b in bytes {
+            unsafe { putchar(b as i32) };
+        }
+    }
+    BYTES_WRITTEN.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+}
+
+/// `.` for both the JIT'd code and the interpreter. Output goes through libc's
+/// stdout, use `flush_output` before writing anything else to it.
+pub extern "C" fn write_output(c: u64) {
+    match load_mode(&OUTPUT_MODE) {
+        IoMode::Byte => write_bytes(&[c as u8]),
+        IoMode::Utf8 => {
+            // cells that aren't scalar values come out as U+FFFD
+            let ch = u32::try_from(c)
+                .ok()
+                .and_then(char::from_u32)
+                .unwrap_or(char::REPLACEMENT_CHARACTER);
+            write_bytes(ch.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+        IoMode::Decimal => {
+            let n = signed(c, CELL_BITS.load(Ordering::Relaxed));
+            write_bytes(format!("{}\n", n).as_bytes())
+        }
+    }
+}
+
+pub fn flush_output() {
+    unsafe { fflush(std::ptr::null_mut()) };
+}
+
+/// Where `,` reads from: input embedded in the program after `!` first, then
+/// stdin or a recording of it
+struct Input {
+    embedded: Vec<u8>,
+    pos: usize,
+    /// Carry on with stdin once `embedded` runs out, instead of giving EOF
+    then_stdin: bool,
+    /// Read from this instead of stdin, see `replay`
+    replay: Option<VecDeque<u8>>,
+    /// Where to write down every byte read from stdin, and since when
+    record: Option<(File, Instant)>,
+}
+
+static INPUT: Mutex<Input> = Mutex::new(Input {
+    embedded: Vec::new(),
+    pos: 0,
+    then_stdin: true,
+    replay: None,
+    record: None,
+});
+
+/// Makes `,` read `embedded` first, then stdin if `then_stdin` or EOF otherwise.
+pub fn set_embedded_input(embedded: Vec<u8>, then_stdin: bool) {
+    let mut input = INPUT.lock().unwrap();
+    input.embedded = embedded;
+    input.pos = 0;
+    input.then_stdin = then_stdin;
+}
+
+/// The embedded input `,` hasn't read yet, and whether stdin comes after it,
+/// for a snapshot to carry on from.
+pub fn unread_embedded_input() -> (Vec<u8>, bool) {
+    let input = INPUT.lock().unwrap();
+    (input.embedded[input.pos..].to_vec(), input.then_stdin)
+}
+
+/// Makes `,` read `bytes` instead of stdin, then EOF.
+pub fn replay_input(bytes: Vec<u8>) {
+    INPUT.lock().unwrap().replay = Some(bytes.into());
+}
+
+/// Writes every byte `,` reads from stdin (or its replay) to `path` as it's
+/// read, in the format of `replay`.
+pub fn record_input(path: &Path) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(replay::HEADER.as_bytes())?;
+    INPUT.lock().unwrap().record = Some((file, Instant::now()));
+    Ok(())
+}
+
+/// EOF as `,` stores it: -1 as a u32, like the zero extended result of libc's
+/// getchar that `,` always stored.
+const EOF: u64 = u32::MAX as u64;
+
+impl Input {
+    fn byte(&mut self) -> Option<u8> {
+        let c = match self.embedded.get(self.pos) {
+            Some(&b) => {
+                self.pos += 1;
+                b as i32
+            }
+            None if self.then_stdin => self.stdin_byte(),
+            None => -1,
+        };
+        if c < 0 {
+            return None;
+        }
+        BYTES_READ.fetch_add(1, Ordering::Relaxed);
+        Some(c as u8)
+    }
+
+    fn stdin_byte(&mut self) -> i32 {
+        let c = match &mut self.replay {
+            Some(bytes) => bytes.pop_front().map_or(-1, |b| b as i32),
+            None => unsafe { getchar() },
+        };
+        if let (Some((file, start)), Ok(b)) = (&mut self.record, u8::try_from(c)) {
+            // written right away so the recording survives the program crashing
+            if let Err(e) = file.write_all(replay::line(start.elapsed(), b).as_bytes()) {
+                eprintln!("error: could not record input: {}", e);
+                self.record = None;
+            }
+        }
+        c
+    }
+
+    /// Decodes one UTF-8 sequence, invalid ones read as U+FFFD.
+    fn utf8(&mut self) -> Option<u64> {
+        let first = self.byte()?;
+        let len = match first {
+            0x00..=0x7f => return Some(first as u64),
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return Some(char::REPLACEMENT_CHARACTER as u64),
+        };
+        let mut bytes = vec![first];
+        for _ in 1..len {
+            match self.byte() {
+                Some(b) => bytes.push(b),
+                None => break,
+            }
+        }
+        let ch = std::str::from_utf8(&bytes)
+            .ok()
+            .and_then(|s| s.chars().next())
+            .unwrap_or(char::REPLACEMENT_CHARACTER);
+        Some(ch as u64)
+    }
+
+    /// Reads a signed decimal number, skipping anything before it. The byte
+    /// that ends the number is consumed too.
+    fn decimal(&mut self) -> Option<u64> {
+        let mut negative = false;
+        loop {
+            let mut b = self.byte()?;
+            // a `-` only counts right before the digits, so `--5` is -5 and
+            // the `x` in `-x` is skipped like anything else
+            if b == b'-' {
+                negative = true;
+                continue;
+            }
+            if !b.is_ascii_digit() {
+                negative = false;
+                continue;
+            }
+            let mut n = 0u64;
+            while b.is_ascii_digit() {
+                n = n.wrapping_mul(10).wrapping_add((b - b'0') as u64);
+                b = match self.byte() {
+                    Some(b) => b,
+                    None => break,
+                };
+            }
+            return Some(if negative { n.wrapping_neg() } else { n });
+        }
+    }
+}
+
+/// `,` for both the JIT'd code and the interpreter, with the data pointer at
+/// `ptr`. A Ctrl-C while it waits for stdin ends the run from here.
+pub extern "C" fn read_input(ptr: *const u64) -> u64 {
+    let mut input = INPUT.lock().unwrap();
+    let c = match load_mode(&INPUT_MODE) {
+        IoMode::Byte => input.byte().map(|b| b as u64),
+        IoMode::Utf8 => input.utf8(),
+        IoMode::Decimal => input.decimal(),
+    };
+    drop(input);
+    if INTERRUPTED.load(Ordering::Relaxed) {
+        interrupted(ptr);
+    }
+    c.unwrap_or(EOF)
+}
+
+/// Set by `interrupt`. JIT'd loops read this directly when the JIT is
+/// interruptible.
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// What a run interrupted with Ctrl-C exits with, 128 + SIGINT like a shell
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+type InterruptHook = Box<dyn Fn(&[u64], usize) + Send>;
+
+static ON_INTERRUPT: Mutex<Option<InterruptHook>> = Mutex::new(None);
+
+/// Asks the running program to stop at the next loop iteration or `,`. Only
+/// touches an atomic, so signal handlers can call it. Asking again exits
+/// right away, for loops that never check.
+pub fn interrupt() {
+    if INTERRUPTED.swap(true, Ordering::Relaxed) {
+        unsafe { _exit(INTERRUPTED_EXIT_CODE) };
+    }
+}
+
+/// Makes `interrupted` call `hook` with the tape set by `set_tape` and the
+/// index of the cell under the pointer before exiting, to report where the
+/// run got to.
+pub fn on_interrupt(hook: impl Fn(&[u64], usize) + Send + 'static) {
+    *ON_INTERRUPT.lock().unwrap() = Some(Box::new(hook));
+}
+
+/// Called once a run has stopped for `interrupt`, with the data pointer at
+/// `ptr`. Flushes the output, runs the hook and exits.
+pub extern "C" fn interrupted(ptr: *const u64) -> ! {
+    flush_output();
+    let start = TAPE_START.load(Ordering::Relaxed);
+    let end = TAPE_END.load(Ordering::Relaxed);
+    if let Some(hook) = &*ON_INTERRUPT.lock().unwrap() {
+        // other threads may still be writing to it, but a racy look at the
+        // cells is good enough for a report
+        let tape = unsafe { std::slice::from_raw_parts(start as *const u64, (end - start) / 8) };
+        hook(tape, (ptr as usize - start) / 8);
+    }
+    std::process::exit(INTERRUPTED_EXIT_CODE);
+}
+
+/// The tape for checked mode and the report: its first cell, one past its last
+/// cell and the cell that counts as cell 0 in error messages, all as addresses.
+/// JIT'd code reads the first two directly.
+pub static TAPE_START: AtomicUsize = AtomicUsize::new(0);
+pub static TAPE_END: AtomicUsize = AtomicUsize::new(0);
+static TAPE_ORIGIN: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_tape(tape: &mut [u64], origin: usize) {
+    let range = tape.as_mut_ptr_range();
+    TAPE_START.store(range.start as usize, Ordering::Relaxed);
+    TAPE_END.store(range.end as usize, Ordering::Relaxed);
+    TAPE_ORIGIN.store(range.start as usize + origin * 8, Ordering::Relaxed);
+    TOUCHED_LO.store(usize::MAX, Ordering::Relaxed);
+    TOUCHED_HI.store(0, Ordering::Relaxed);
+}
+
+/// The lowest and highest address of any cell the run has touched, for the
+/// report. JIT'd code reads these directly and widens them with atomics.
+pub static TOUCHED_LO: AtomicUsize = AtomicUsize::new(usize::MAX);
+pub static TOUCHED_HI: AtomicUsize = AtomicUsize::new(0);
+
+/// Widens the touched range to cover the cells from address `lo` to `hi`.
+/// Only touches the atomics when the range grows, which is rarely.
+pub fn touch(lo: usize, hi: usize) {
+    if lo < TOUCHED_LO.load(Ordering::Relaxed) {
+        TOUCHED_LO.fetch_min(lo, Ordering::Relaxed);
+    }
+    if hi > TOUCHED_HI.load(Ordering::Relaxed) {
+        TOUCHED_HI.fetch_max(hi, Ordering::Relaxed);
+    }
+}
+
+/// The cells touched so far as indices into the tape given to `set_tape`,
+/// `None` if there aren't any.
+pub fn touched() -> Option<RangeInclusive<usize>> {
+    let start = TAPE_START.load(Ordering::Relaxed);
+    let lo = TOUCHED_LO.load(Ordering::Relaxed);
+    let hi = TOUCHED_HI.load(Ordering::Relaxed);
+    if lo > hi {
+        return None;
+    }
+    Some((lo - start) / 8..=(hi - start) / 8)
+}
+
+/// Called by JIT'd code in checked mode when the cells from address `lo` to
+/// `hi` aren't all on the tape.
+pub extern "C" fn bounds_error(lo: usize, hi: usize) -> ! {
+    let start = TAPE_START.load(Ordering::Relaxed);
+    let end = TAPE_END.load(Ordering::Relaxed);
+    let origin = TAPE_ORIGIN.load(Ordering::Relaxed) as i64;
+    let cell = |addr: usize| (addr as i64 - origin) / 8;
+    let bad = if lo < start { lo } else { hi };
+    eprintln!(
+        "error: pointer moved off the tape to cell {}, the tape has cells {} to {}",
+        cell(bad),
+        cell(start),
+        cell(end) - 1
+    );
+    std::process::exit(1);
+}
+
+/// Number of pbrain procedure ids, ids are cell values in `0..PROCEDURE_COUNT`
+pub const PROCEDURE_COUNT: usize = 1 << 16;
+
+/// pbrain dispatch table, entry `n` is the address of procedure `n` or 0 if
+/// it's not defined yet. JIT'd code reads and writes this directly.
+pub static PROCEDURES: [AtomicUsize; PROCEDURE_COUNT] =
+    [const { AtomicUsize::new(0) }; PROCEDURE_COUNT];
+
+/// Called by JIT'd code on `:` with an undefined procedure, or on `(`/`:`
+/// with an id outside the table.
+pub extern "C" fn procedure_error(id: i64) -> ! {
+    if id < 0 || id as usize >= PROCEDURE_COUNT {
+        eprintln!(
+            "error: procedure id {} out of range, ids go from 0 to {}",
+            id,
+            PROCEDURE_COUNT - 1
+        );
+    } else {
+        eprintln!("error: call to undefined procedure {}", id);
+    }
+    std::process::exit(1);
+}
+
+/// How the threads forked by Brainfork's `Y` get run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// On a thread pool of at most this many workers, 0 for no limit
+    Threads(usize),
+    /// One after another on the main thread once it finishes, in the order
+    /// they were forked. Threads that wait on each other never finish.
+    Deterministic,
+}
+
+/// Programs that fork take the data pointer and the fork to resume after,
+/// 0 for the start of the program.
+pub type ForkFunction = extern "C" fn(*mut u64, i64) -> *mut u64;
+
+struct Forks {
+    entry: Option<ForkFunction>,
+    schedule: Schedule,
+    /// Threads waiting to start, as their data pointer and resume point
+    queue: VecDeque<(usize, i64)>,
+    workers: usize,
+    idle: usize,
+    /// Threads forked that haven't finished yet
+    pending: usize,
+}
+
+static FORKS: Mutex<Forks> = Mutex::new(Forks {
+    entry: None,
+    schedule: Schedule::Deterministic,
+    queue: VecDeque::new(),
+    workers: 0,
+    idle: 0,
+    pending: 0,
+});
+static FORKS_CHANGED: Condvar = Condvar::new();
+
+/// Runs a program that forks, returning once every thread has finished.
+pub fn run_forking(f: ForkFunction, ptr: *mut u64, schedule: Schedule) -> *mut u64 {
+    {
+        let mut forks = FORKS.lock().unwrap();
+        forks.entry = Some(f);
+        forks.schedule = schedule;
+    }
+    let res = f(ptr, 0);
+    let mut forks = FORKS.lock().unwrap();
+    while forks.pending > 0 {
+        match forks.schedule {
+            Schedule::Deterministic => forks = run_queued(forks),
+            Schedule::Threads(_) => forks = FORKS_CHANGED.wait(forks).unwrap(),
+        }
+    }
+    res
+}
+
+/// Called by JIT'd code on `Y`. The parent's cell becomes 0, the child starts
+/// on the next cell, which becomes 1.
+extern "C" fn fork(ptr: *mut u64, resume: i64) {
+    unsafe {
+        *ptr = 0;
+        *ptr.add(1) = 1;
+    }
+    let mut forks = FORKS.lock().unwrap();
+    forks.queue.push_back((ptr as usize + 8, resume));
+    forks.pending += 1;
+    if let Schedule::Threads(max) = forks.schedule {
+        // every queued thread needs a worker of its own, since it might be
+        // waiting on one that hasn't started yet
+        if forks.queue.len() > forks.idle && (max == 0 || forks.workers < max) {
+            forks.workers += 1;
+            thread::spawn(worker);
+        }
+    }
+    FORKS_CHANGED.notify_all();
+}
+
+fn worker() {
+    let mut forks = FORKS.lock().unwrap();
+    loop {
+        if forks.queue.is_empty() {
+            forks.idle += 1;
+            forks = FORKS_CHANGED.wait(forks).unwrap();
+            forks.idle -= 1;
+        } else {
+            forks = run_queued(forks);
+        }
+    }
+}
+
+/// Runs the next queued thread to completion without holding the lock.
+fn run_queued(mut forks: MutexGuard<'static, Forks>) -> MutexGuard<'static, Forks> {
+    let (ptr, resume) = forks.queue.pop_front().unwrap();
+    let f = forks.entry.unwrap();
+    drop(forks);
+    f(ptr as *mut u64, resume);
+    let mut forks = FORKS.lock().unwrap();
+    forks.pending -= 1;
+    FORKS_CHANGED.notify_all();
+    forks
+}
+
+/// Symbols the JIT resolves imports against before falling back to libc.
+pub fn symbols() -> Vec<(&'static str, *const u8)> {
+    vec![
+        ("bf_procedure_error", procedure_error as *const u8),
+        ("bf_fork", fork as *const u8),
+        ("bf_bounds_error", bounds_error as *const u8),
+        ("bf_read_input", read_input as *const u8),
+        ("bf_interrupted", interrupted as *const u8),
+        ("bf_write_output", write_output as *const u8),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbers(embedded: &str) -> Vec<u64> {
+        let mut input = Input {
+            embedded: embedded.as_bytes().to_vec(),
+            pos: 0,
+            then_stdin: false,
+            replay: None,
+            record: None,
+        };
+        std::iter::from_fn(|| input.decimal()).collect()
+    }
+
+    #[test]
+    fn decimal_input() {
+        assert_eq!(numbers("12 -3\n"), [12, 3u64.wrapping_neg()]);
+        assert_eq!(numbers("x7y-0"), [7, 0]);
+        assert_eq!(numbers("--5"), [5u64.wrapping_neg()]);
+        assert_eq!(numbers("-x5 -"), [5]);
+        assert_eq!(numbers("- 5"), [5]);
+        assert_eq!(numbers(""), []);
+    }
+
+    #[test]
+    fn decimal_output() {
+        assert_eq!(signed(255, 8), -1);
+        assert_eq!(signed(127, 8), 127);
+        assert_eq!(signed(128, 8), -128);
+        assert_eq!(signed(0xffff, 16), -1);
+        assert_eq!(signed(0xffff, 32), 65535);
+        assert_eq!(signed(0xffff_ffff, 32), -1);
+        assert_eq!(signed(u64::MAX, 64), -1);
+        assert_eq!(signed(5, 64), 5);
+    }
+}
@@ -0,0 +1,57 @@
+//! Installs signal handlers through libc's `signal`.
+
+extern "C" {
+    fn signal(signum: i32, handler: usize) -> usize;
+    fn siginterrupt(signum: i32, flag: i32) -> i32;
+}
+
+pub const SIGINT: i32 = 2;
+
+/// `None` where we don't know its number, which leaves --snapshot out.
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    not(any(target_arch = "mips", target_arch = "mips64", target_arch = "sparc64"))
+))]
+pub const SIGUSR1: Option<i32> = Some(10);
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    any(target_arch = "mips", target_arch = "mips64")
+))]
+pub const SIGUSR1: Option<i32> = Some(16);
+#[cfg(any(
+    all(target_os = "linux", target_arch = "sparc64"),
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+pub const SIGUSR1: Option<i32> = Some(30);
+#[cfg(any(target_os = "solaris", target_os = "illumos"))]
+pub const SIGUSR1: Option<i32> = Some(16);
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "solaris",
+    target_os = "illumos"
+)))]
+pub const SIGUSR1: Option<i32> = None;
+
+/// Calls `handler` whenever the process gets `signum`. It runs in the middle of
+/// whatever the process was doing, so it mustn't do more than set atomics.
+pub fn on(signum: i32, handler: extern "C" fn(i32)) {
+    unsafe { signal(signum, handler as usize) };
+}
+
+/// Makes `signum` interrupt a blocking read instead of restarting it once the
+/// handler returns, so a program waiting on stdin notices it.
+pub fn interrupt_reads(signum: i32) {
+    unsafe { siginterrupt(signum, 1) };
+}
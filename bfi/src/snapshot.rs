@@ -0,0 +1,244 @@
+//! Snapshots of a paused VM run, written on `SIGUSR1` with `--snapshot` and
+//! resumed by running the file.
+//!
+//! A snapshot holds everything needed to carry on: the program as bytecode,
+//! the nonzero part of the tape, the VM's `Position` and the input embedded
+//! after `!` that hasn't been read yet. Input already read from stdin and
+//! output already written aren't part of it, a resumed run reads from stdin
+//! afresh.
+
+use crate::{
+    bytecode::{self, varint, Op, Reader},
+    vm::Position,
+    CellWidth,
+};
+
+pub const MAGIC: &[u8; 4] = b"BFSS";
+
+/// Bump whenever the encoding below changes.
+const FORMAT_VERSION: u32 = 2;
+
+pub struct Snapshot {
+    pub ops: Vec<Op>,
+    pub width: CellWidth,
+    /// Index of the first cell in `cells`
+    pub first: usize,
+    pub cells: Vec<u64>,
+    pub at: Position,
+    /// Embedded input left to read, see `runtime::unread_embedded_input`
+    pub input: Vec<u8>,
+    /// Read stdin once `input` runs out, rather than giving EOF
+    pub then_stdin: bool,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of a run of `ops` paused at `at`, with `input` and
+    /// `then_stdin` as from `runtime::unread_embedded_input`.
+    pub fn new(
+        ops: &[Op],
+        width: CellWidth,
+        tape: &[u64],
+        at: Position,
+        (input, then_stdin): (Vec<u8>, bool),
+    ) -> Self {
+        let first = tape.iter().position(|&c| c != 0).unwrap_or(0);
+        let last = tape.iter().rposition(|&c| c != 0).map_or(first, |i| i + 1);
+        Self {
+            ops: ops.to_vec(),
+            width,
+            first,
+            cells: tape[first..last].to_vec(),
+            at,
+            input,
+            then_stdin,
+        }
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        let code = bytecode::write(&self.ops, self.width);
+        varint(code.len() as u64, &mut out);
+        out.extend_from_slice(&code);
+        varint(self.at.pc as u64, &mut out);
+        varint(self.at.ptr as u64, &mut out);
+        varint(self.first as u64, &mut out);
+        varint(self.cells.len() as u64, &mut out);
+        for &c in &self.cells {
+            varint(c, &mut out);
+        }
+        // sorted so the same state always makes the same file
+        let mut procedures: Vec<_> = self.at.procedures.iter().collect();
+        procedures.sort_unstable();
+        varint(procedures.len() as u64, &mut out);
+        for (&id, &start) in procedures {
+            varint(id, &mut out);
+            varint(start as u64, &mut out);
+        }
+        varint(self.at.calls.len() as u64, &mut out);
+        for &pc in &self.at.calls {
+            varint(pc as u64, &mut out);
+        }
+        varint(self.input.len() as u64, &mut out);
+        out.extend_from_slice(&self.input);
+        out.push(self.then_stdin as u8);
+        out
+    }
+
+    /// Decodes a snapshot for a tape of `tape_len` cells, checking that it
+    /// fits on it, that every position in it is in the program and that the
+    /// calls fit where it is, so resuming can't return from nowhere.
+    pub fn read(data: &[u8], tape_len: usize) -> Result<Self, String> {
+        let mut r = Reader { data };
+        if r.bytes(4)? != MAGIC {
+            return Err("not a snapshot".to_string());
+        }
+        let version = u32::from_le_bytes([r.byte()?, r.byte()?, r.byte()?, r.byte()?]);
+        if version != FORMAT_VERSION {
+            return Err(format!(
+                "snapshot format {} isn't supported, this bfi reads format {}",
+                version, FORMAT_VERSION
+            ));
+        }
+        let len = r.varint()? as usize;
+        let (ops, width) = bytecode::read(r.bytes(len)?)?;
+        let mut index = |what: &str, limit: usize| {
+            let i = r.varint()? as usize;
+            if i < limit {
+                Ok(i)
+            } else {
+                Err(format!("{} {} is out of range", what, i))
+            }
+        };
+        let pc = index("op", ops.len() + 1)?;
+        let ptr = index("cell", tape_len)?;
+        let first = index("cell", tape_len)?;
+        let count = index("cell count", tape_len - first + 1)?;
+        let mut cells = Vec::with_capacity(count.min(r.data.len()));
+        for _ in 0..count {
+            cells.push(r.varint()? & width.mask());
+        }
+        let mut at = Position {
+            pc,
+            ptr,
+            ..Position::default()
+        };
+        for _ in 0..r.varint()? {
+            let id = r.varint()?;
+            let start = r.varint()? as usize;
+            let defined = start > 0 && matches!(ops.get(start - 1), Some(Op::Define(_)));
+            if !defined {
+                return Err(format!("procedure {} doesn't start after a definition", id));
+            }
+            at.procedures.insert(id, start);
+        }
+        for _ in 0..r.varint()? {
+            let pc = r.varint()? as usize;
+            if pc > ops.len() {
+                return Err(format!("return address {} is past the end", pc));
+            }
+            at.calls.push(pc);
+        }
+        check_calls(&ops, &at)?;
+        let len = r.varint()? as usize;
+        let input = r.bytes(len)?.to_vec();
+        let then_stdin = match r.byte()? {
+            0 => false,
+            1 => true,
+            b => return Err(format!("bad stdin flag {}", b)),
+        };
+        if !r.data.is_empty() {
+            return Err("trailing data after the snapshot".to_string());
+        }
+        Ok(Self {
+            ops,
+            width,
+            first,
+            cells,
+            at,
+            input,
+            then_stdin,
+        })
+    }
+}
+
+/// Checks that `at.pc` and every return address are inside a procedure body
+/// exactly when there's a call under them on the stack, as in a real run.
+fn check_calls(ops: &[Op], at: &Position) -> Result<(), String> {
+    // how many procedure bodies each op is in
+    let mut depth = vec![0usize; ops.len() + 1];
+    for (i, op) in ops.iter().enumerate() {
+        if let Op::Define(len) = op {
+            let end = (i + *len as usize).min(ops.len());
+            for d in &mut depth[i + 1..end] {
+                *d += 1;
+            }
+        }
+    }
+    let stack = at.calls.iter().chain(std::iter::once(&at.pc));
+    for (below, &pc) in stack.enumerate() {
+        if (depth[pc] > 0) != (below > 0) {
+            return Err(format!(
+                "op {} doesn't fit {} calls running at the time",
+                pc, below
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TAPE_LEN: usize = 16;
+
+    /// `(+)` defined as procedure 0 and called, paused in the middle of it
+    fn paused_in_call() -> Snapshot {
+        let ops = [Op::Define(3), Op::Add(1), Op::Return, Op::Call];
+        let mut at = Position::start(4);
+        at.procedures.insert(0, 1);
+        at.pc = 1;
+        at.calls.push(4);
+        Snapshot::new(
+            &ops,
+            CellWidth::default(),
+            &[0; TAPE_LEN],
+            at,
+            (b"ab".to_vec(), true),
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let snapshot = paused_in_call();
+        let read = Snapshot::read(&snapshot.write(), TAPE_LEN).unwrap();
+        assert_eq!(read.ops, snapshot.ops);
+        assert_eq!(read.at, snapshot.at);
+        assert_eq!(read.input, b"ab");
+        assert!(read.then_stdin);
+    }
+
+    #[test]
+    fn calls_must_fit() {
+        let read = |snapshot: &Snapshot| Snapshot::read(&snapshot.write(), TAPE_LEN);
+        // on the `)` with nothing to return to
+        let mut snapshot = paused_in_call();
+        snapshot.at.pc = 2;
+        snapshot.at.calls.clear();
+        assert!(read(&snapshot).is_err());
+        // returning into the procedure, which nothing called
+        let mut snapshot = paused_in_call();
+        snapshot.at.calls = vec![2];
+        assert!(read(&snapshot).is_err());
+        // a call that's still running while the program carries on after it
+        let mut snapshot = paused_in_call();
+        snapshot.at.pc = 4;
+        assert!(read(&snapshot).is_err());
+        // procedures start right after their `(`
+        let mut snapshot = paused_in_call();
+        snapshot.at.procedures.insert(1, 3);
+        assert!(read(&snapshot).is_err());
+    }
+}
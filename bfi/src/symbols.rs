@@ -0,0 +1,230 @@
+//! Tells profilers and debuggers where JIT'd functions are, which they
+//! otherwise only see as anonymous memory.
+//!
+//! `perf` reads `/tmp/perf-<pid>.map`, one line per function with its address,
+//! size and name. `gdb` instead sets a breakpoint on `__jit_debug_register_code`
+//! and reads an object file describing the new code out of the list in
+//! `__jit_debug_descriptor`, see "JIT Compilation Interface" in the gdb manual.
+//! The object files only have a symbol table, there are no source spans to make
+//! line tables from, so loops show up as the shared functions and chunks they
+//! were compiled into.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    ptr,
+    sync::Mutex,
+};
+
+/// One compiled function
+pub struct Symbol {
+    pub name: String,
+    pub addr: usize,
+    pub size: usize,
+}
+
+static PERF_MAP: Mutex<Option<File>> = Mutex::new(None);
+
+/// Appends `symbols` to `/tmp/perf-<pid>.map`, creating it on first use.
+pub fn write_perf_map(symbols: &[Symbol]) {
+    let mut map = PERF_MAP.lock().unwrap();
+    if map.is_none() {
+        let path = format!("/tmp/perf-{}.map", std::process::id());
+        match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(f) => *map = Some(f),
+            Err(e) => {
+                eprintln!("error: could not write {}: {}", path, e);
+                return;
+            }
+        }
+    }
+    let mut lines = String::new();
+    for s in symbols {
+        lines += &format!("{:x} {:x} {}\n", s.addr, s.size, s.name);
+    }
+    // perf reads the map after the process exits, so write it out right away
+    let _ = map.as_mut().unwrap().write_all(lines.as_bytes());
+}
+
+#[repr(C)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+/// gdb breaks here to pick up the entry in `relevant_entry`
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    // keeps the call from being optimized away
+    std::hint::black_box(());
+}
+
+#[no_mangle]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: 0,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut(),
+};
+
+/// Guards `__jit_debug_descriptor`, gdb only reads it while we're stopped
+static DESCRIPTOR: Mutex<()> = Mutex::new(());
+
+/// Code registered with gdb, see `register`
+pub struct Registration {
+    entry: *mut JitCodeEntry,
+    /// The object file `entry` points into
+    _symfile: Box<[u8]>,
+}
+
+/// Registers `symbols` with gdb. They stay registered until the
+/// `Registration` is passed to `unregister`, which has to happen before the
+/// code is freed.
+pub fn register(symbols: &[Symbol]) -> Option<Registration> {
+    let symfile = elf(symbols)?.into_boxed_slice();
+    let entry = Box::into_raw(Box::new(JitCodeEntry {
+        next_entry: ptr::null_mut(),
+        prev_entry: ptr::null_mut(),
+        symfile_addr: symfile.as_ptr(),
+        symfile_size: symfile.len() as u64,
+    }));
+    let _guard = DESCRIPTOR.lock().unwrap();
+    unsafe {
+        let d = ptr::addr_of_mut!(__jit_debug_descriptor);
+        (*entry).next_entry = (*d).first_entry;
+        if let Some(next) = (*entry).next_entry.as_mut() {
+            next.prev_entry = entry;
+        }
+        (*d).first_entry = entry;
+        (*d).relevant_entry = entry;
+        (*d).action_flag = JIT_REGISTER_FN;
+        __jit_debug_register_code();
+    }
+    Some(Registration {
+        entry,
+        _symfile: symfile,
+    })
+}
+
+pub fn unregister(registration: Registration) {
+    let entry = registration.entry;
+    let _guard = DESCRIPTOR.lock().unwrap();
+    unsafe {
+        let d = ptr::addr_of_mut!(__jit_debug_descriptor);
+        match (*entry).prev_entry.as_mut() {
+            Some(prev) => prev.next_entry = (*entry).next_entry,
+            None => (*d).first_entry = (*entry).next_entry,
+        }
+        if let Some(next) = (*entry).next_entry.as_mut() {
+            next.prev_entry = (*entry).prev_entry;
+        }
+        (*d).relevant_entry = entry;
+        (*d).action_flag = JIT_UNREGISTER_FN;
+        __jit_debug_register_code();
+        drop(Box::from_raw(entry));
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+const MACHINE: u16 = 62;
+#[cfg(target_arch = "aarch64")]
+const MACHINE: u16 = 183;
+
+/// A relocatable ELF file with a `.text` section covering all of `symbols`
+/// and a function symbol for each. `.text` takes no space in the file, gdb
+/// only needs its address. None on targets gdb wouldn't know what to do with.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn elf(symbols: &[Symbol]) -> Option<Vec<u8>> {
+    const HEADER: usize = 64;
+    const SECTION_HEADER: usize = 64;
+    const SYMBOL: usize = 24;
+    let lo = symbols.iter().map(|s| s.addr).min()?;
+    let hi = symbols.iter().map(|s| s.addr + s.size).max()?;
+
+    let shstrtab = b"\0.text\0.symtab\0.strtab\0.shstrtab\0";
+    let mut strtab = vec![0u8];
+    let mut symtab = vec![0u8; SYMBOL];
+    for s in symbols {
+        let name = strtab.len() as u32;
+        strtab.extend_from_slice(s.name.as_bytes());
+        strtab.push(0);
+        symtab.extend_from_slice(&name.to_le_bytes());
+        // global function, in .text
+        symtab.push(0x12);
+        symtab.push(0);
+        symtab.extend_from_slice(&1u16.to_le_bytes());
+        symtab.extend_from_slice(&((s.addr - lo) as u64).to_le_bytes());
+        symtab.extend_from_slice(&(s.size as u64).to_le_bytes());
+    }
+
+    let mut out = vec![0u8; HEADER];
+    let place = |out: &mut Vec<u8>, data: &[u8]| {
+        out.resize((out.len() + 7) & !7, 0);
+        let offset = out.len();
+        out.extend_from_slice(data);
+        offset as u64
+    };
+    let symtab_at = place(&mut out, &symtab);
+    let strtab_at = place(&mut out, &strtab);
+    let shstrtab_at = place(&mut out, shstrtab);
+    let sections_at = place(&mut out, &[]);
+
+    let mut section = |name: u32, kind: u32, flags: u64, addr: u64, offset: u64, size: u64| {
+        let (link, info, align, entsize) = match kind {
+            0 => (0, 0, 0, 0),
+            // the first global symbol comes right after the null one
+            2 => (3, 1, 8, SYMBOL as u64),
+            _ => (0, 0, 16, 0),
+        };
+        out.extend_from_slice(&name.to_le_bytes());
+        out.extend_from_slice(&kind.to_le_bytes());
+        out.extend_from_slice(&flags.to_le_bytes());
+        out.extend_from_slice(&addr.to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&(link as u32).to_le_bytes());
+        out.extend_from_slice(&(info as u32).to_le_bytes());
+        out.extend_from_slice(&(align as u64).to_le_bytes());
+        out.extend_from_slice(&entsize.to_le_bytes());
+    };
+    section(0, 0, 0, 0, 0, 0);
+    // SHT_NOBITS, allocated and executable
+    section(1, 8, 0x6, lo as u64, 0, (hi - lo) as u64);
+    section(7, 2, 0, 0, symtab_at, symtab.len() as u64);
+    section(15, 3, 0, 0, strtab_at, strtab.len() as u64);
+    section(23, 3, 0, 0, shstrtab_at, shstrtab.len() as u64);
+
+    let header = &mut out[..HEADER];
+    // 64-bit, little endian, version 1
+    header[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+    // ET_REL
+    header[16..18].copy_from_slice(&1u16.to_le_bytes());
+    header[18..20].copy_from_slice(&MACHINE.to_le_bytes());
+    header[20..24].copy_from_slice(&1u32.to_le_bytes());
+    header[40..48].copy_from_slice(&sections_at.to_le_bytes());
+    header[52..54].copy_from_slice(&(HEADER as u16).to_le_bytes());
+    header[58..60].copy_from_slice(&(SECTION_HEADER as u16).to_le_bytes());
+    // five sections, names in the last one
+    header[60..62].copy_from_slice(&5u16.to_le_bytes());
+    header[62..64].copy_from_slice(&4u16.to_le_bytes());
+    Some(out)
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn elf(_: &[Symbol]) -> Option<Vec<u8>> {
+    None
+}
@@ -0,0 +1,160 @@
+use std::{collections::HashMap, sync::atomic::Ordering};
+
+use crate::{runtime, FragmentFunction, OptimizedBFInstruction, JIT};
+
+enum LoopTier {
+    /// Still interpreted, with the number of times the loop header ran
+    Interpreted(u32),
+    Compiled(FragmentFunction),
+}
+
+/// Interprets `OptimizedBFInstruction`s and hands loops over to the JIT once
+/// their header has run `threshold` times.
+///
+/// Switching only happens at a loop header, where the compiled function
+/// starts, so both tiers work on the same tape without any state to transfer.
+///
+/// pbrain procedures are always compiled when they're defined, so both tiers
+/// can call them through the runtime's procedure table.
+pub struct Tiered {
+    jit: JIT,
+    threshold: u32,
+    // loops are keyed by their address in the (immutable) instruction tree
+    loops: HashMap<*const OptimizedBFInstruction, LoopTier>,
+    procedures: HashMap<*const OptimizedBFInstruction, FragmentFunction>,
+    compiled: usize,
+    /// Cell width mask, taken from the JIT
+    mask: u64,
+}
+
+impl Tiered {
+    pub fn new(jit: JIT, threshold: u32) -> Self {
+        Self {
+            mask: jit.cell_width().mask(),
+            jit,
+            threshold,
+            loops: HashMap::new(),
+            procedures: HashMap::new(),
+            compiled: 0,
+        }
+    }
+
+    pub fn jit(&self) -> &JIT {
+        &self.jit
+    }
+
+    /// The JIT back, to free the loops it compiled with `JIT::free_memory`.
+    pub fn into_jit(self) -> JIT {
+        self.jit
+    }
+
+    pub fn compiled_loops(&self) -> usize {
+        self.compiled
+    }
+
+    pub fn seen_loops(&self) -> usize {
+        self.loops.len()
+    }
+
+    /// Runs `insns` with the data pointer at `tape[ptr]`, returning where the pointer ended up.
+    pub fn run(&mut self, insns: &[OptimizedBFInstruction], tape: &mut [u64], ptr: usize) -> usize {
+        let mut ptr = ptr;
+        for insn in insns {
+            if !matches!(
+                insn,
+                OptimizedBFInstruction::DataPtrModify(_)
+                    | OptimizedBFInstruction::WhileDataValueNonZero(_)
+            ) {
+                touch(tape, ptr);
+            }
+            match insn {
+                OptimizedBFInstruction::DataPtrModify(x) => ptr = ptr.wrapping_add(*x as usize),
+                OptimizedBFInstruction::DataValueModify(x) => {
+                    tape[ptr] = tape[ptr].wrapping_add(*x as u64) & self.mask
+                }
+                OptimizedBFInstruction::DataValuePutchar => runtime::write_output(tape[ptr]),
+                OptimizedBFInstruction::DataValueScanchar => {
+                    tape[ptr] = runtime::read_input(&tape[ptr]) & self.mask;
+                }
+                OptimizedBFInstruction::WhileDataValueNonZero(body) => {
+                    ptr = self.run_loop(insn, body, tape, ptr)
+                }
+                OptimizedBFInstruction::DefineProcedure(body) => {
+                    let slot = procedure_slot(tape[ptr]);
+                    let (jit, n) = (&mut self.jit, self.procedures.len());
+                    let f = *self
+                        .procedures
+                        .entry(insn)
+                        .or_insert_with(|| jit.jit_fragment(&format!("proc{}", n), body));
+                    slot.store(f as usize, Ordering::Relaxed);
+                }
+                OptimizedBFInstruction::CallProcedure => {
+                    let f = procedure_slot(tape[ptr]).load(Ordering::Relaxed);
+                    if f == 0 {
+                        runtime::procedure_error(tape[ptr] as i64);
+                    }
+                    let f = unsafe { std::mem::transmute::<usize, FragmentFunction>(f) };
+                    let base = tape.as_mut_ptr();
+                    let end = f(unsafe { base.add(ptr) });
+                    ptr = unsafe { end.offset_from(base) } as usize;
+                }
+                OptimizedBFInstruction::Fork => panic!("`Y` needs --engine jit"),
+            }
+        }
+        ptr
+    }
+
+    fn run_loop(
+        &mut self,
+        insn: &OptimizedBFInstruction,
+        body: &[OptimizedBFInstruction],
+        tape: &mut [u64],
+        mut ptr: usize,
+    ) -> usize {
+        let key = insn as *const _;
+        loop {
+            touch(tape, ptr);
+            let tier = self.loops.entry(key).or_insert(LoopTier::Interpreted(0));
+            match tier {
+                LoopTier::Compiled(f) => {
+                    let base = tape.as_mut_ptr();
+                    let end = f(unsafe { base.add(ptr) });
+                    return unsafe { end.offset_from(base) } as usize;
+                }
+                LoopTier::Interpreted(_) if tape[ptr] == 0 => return ptr,
+                LoopTier::Interpreted(_) if runtime::INTERRUPTED.load(Ordering::Relaxed) => {
+                    runtime::interrupted(&tape[ptr])
+                }
+                LoopTier::Interpreted(count) => {
+                    *count += 1;
+                    if *count >= self.threshold {
+                        let name = format!("loop{}", self.compiled);
+                        let f = self.jit.jit_fragment(&name, std::slice::from_ref(insn));
+                        *tier = LoopTier::Compiled(f);
+                        self.compiled += 1;
+                        // back to the header, this time running the compiled loop
+                        continue;
+                    }
+                }
+            }
+            ptr = self.run(body, tape, ptr);
+        }
+    }
+}
+
+/// Records the cell under `ptr` with `runtime::touch`, for the report.
+/// Every access goes through here first, so going off the tape stops with the
+/// same error as `--checked`, like in the VM.
+fn touch(tape: &[u64], ptr: usize) {
+    let addr = (tape.as_ptr() as usize).wrapping_add(ptr.wrapping_mul(8));
+    if ptr >= tape.len() {
+        runtime::bounds_error(addr, addr);
+    }
+    runtime::touch(addr, addr);
+}
+
+fn procedure_slot(id: u64) -> &'static std::sync::atomic::AtomicUsize {
+    runtime::PROCEDURES
+        .get(id as usize)
+        .unwrap_or_else(|| runtime::procedure_error(id as i64))
+}
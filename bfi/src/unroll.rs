@@ -0,0 +1,319 @@
+//! Constant tracking: follows the cells whose values are known from the start of
+//! the program (where the whole tape is 0) and replaces loops whose trip count
+//! that determines with straight-line code.
+
+use std::collections::HashMap;
+
+use crate::OptimizedBFInstruction as Opt;
+
+/// Most instructions a loop with a general body may be unrolled into
+const UNROLL_LIMIT: u64 = 256;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stats {
+    /// Loops that never run, removed
+    pub dead: usize,
+    /// Loops that only add to cells, turned into one addition per cell
+    pub folded: usize,
+    /// Other loops, written out once per iteration
+    pub unrolled: usize,
+}
+
+/// Runs the pass over a whole program, which has to start on a fresh tape and
+/// must not fork: other threads would change cells behind the pass' back.
+/// `mask` is the cell width's, since that decides when cells wrap around.
+pub fn unroll(insns: Vec<Opt>, mask: u64) -> (Vec<Opt>, Stats) {
+    let mut u = Unroller {
+        state: State::zeroed(mask),
+        stats: Stats::default(),
+    };
+    let out = u.sequence(insns);
+    (out, u.stats)
+}
+
+/// What is known about the tape, relative to where the pointer was when the
+/// state was last reset.
+#[derive(Debug)]
+struct State {
+    ptr: i64,
+    /// Cells with a known value, or `None` for ones known to be unknown
+    cells: HashMap<i64, Option<u64>>,
+    /// Cells missing from `cells` are 0, rather than unknown
+    rest_zero: bool,
+    mask: u64,
+}
+
+impl State {
+    fn zeroed(mask: u64) -> Self {
+        Self {
+            ptr: 0,
+            cells: HashMap::new(),
+            rest_zero: true,
+            mask,
+        }
+    }
+
+    fn unknown(&self) -> Self {
+        Self {
+            rest_zero: false,
+            ..Self::zeroed(self.mask)
+        }
+    }
+
+    fn get(&self) -> Option<u64> {
+        match self.cells.get(&self.ptr) {
+            Some(&v) => v,
+            None if self.rest_zero => Some(0),
+            None => None,
+        }
+    }
+
+    fn set(&mut self, v: Option<u64>) {
+        self.cells.insert(self.ptr, v);
+    }
+
+    fn add(&mut self, x: i64) {
+        let mask = self.mask;
+        self.set(self.get().map(|v| v.wrapping_add(x as u64) & mask));
+    }
+}
+
+struct Unroller {
+    state: State,
+    stats: Stats,
+}
+
+impl Unroller {
+    fn sequence(&mut self, insns: Vec<Opt>) -> Vec<Opt> {
+        let mut out = Vec::new();
+        for insn in insns {
+            self.insn(insn, &mut out);
+        }
+        out
+    }
+
+    fn insn(&mut self, insn: Opt, out: &mut Vec<Opt>) {
+        match insn {
+            Opt::WhileDataValueNonZero(body) => self.whole_loop(body, out),
+            Opt::DefineProcedure(body) => {
+                // procedures run whenever they're called, on whatever the tape holds then
+                let unknown = self.state.unknown();
+                let outer = std::mem::replace(&mut self.state, unknown);
+                let body = self.sequence(body);
+                self.state = outer;
+                out.push(Opt::DefineProcedure(body));
+            }
+            insn => {
+                self.step(&insn);
+                push(out, insn);
+            }
+        }
+    }
+
+    /// Updates the state for a straight-line instruction.
+    fn step(&mut self, insn: &Opt) {
+        match insn {
+            Opt::DataPtrModify(x) => self.state.ptr += x,
+            Opt::DataValueModify(x) => self.state.add(*x),
+            Opt::DataValuePutchar => {}
+            Opt::DataValueScanchar => self.state.set(None),
+            Opt::CallProcedure | Opt::Fork => self.state = self.state.unknown(),
+            Opt::WhileDataValueNonZero(_) | Opt::DefineProcedure(_) => unreachable!(),
+        }
+    }
+
+    fn whole_loop(&mut self, body: Vec<Opt>, out: &mut Vec<Opt>) {
+        let counter = self.state.get();
+        if counter == Some(0) {
+            self.stats.dead += 1;
+            return;
+        }
+        if let (Some(n), Some(deltas)) = (counter, straight_deltas(&body)) {
+            if let Some(trips) = trip_count(n, deltas.get(&0).copied().unwrap_or(0)) {
+                if body
+                    .iter()
+                    .all(|i| matches!(i, Opt::DataPtrModify(_) | Opt::DataValueModify(_)))
+                {
+                    self.stats.folded += 1;
+                    self.fold(&deltas, trips, out);
+                    return;
+                }
+                let len = trips.checked_mul(body.len() as u64);
+                if len.is_some_and(|len| len <= UNROLL_LIMIT) {
+                    self.stats.unrolled += 1;
+                    for _ in 0..trips {
+                        for insn in &body {
+                            self.insn(clone_straight(insn), out);
+                        }
+                    }
+                    return;
+                }
+            }
+        }
+        // unknown trip count, nothing inside can rely on what's known now
+        self.state = self.state.unknown();
+        let body = self.sequence(body);
+        self.state = self.state.unknown();
+        self.state.set(Some(0));
+        out.push(Opt::WhileDataValueNonZero(body));
+    }
+
+    /// Emits the combined effect of `trips` runs of a loop that adds `deltas`.
+    fn fold(&mut self, deltas: &HashMap<i64, i64>, trips: u64, out: &mut Vec<Opt>) {
+        let mut offsets: Vec<_> = deltas.keys().copied().collect();
+        offsets.sort_unstable();
+        let mut at = 0;
+        for offset in offsets {
+            let total = (deltas[&offset] as u64).wrapping_mul(trips) as i64;
+            for insn in [Opt::DataPtrModify(offset - at), Opt::DataValueModify(total)] {
+                self.step(&insn);
+                push(out, insn);
+            }
+            at = offset;
+        }
+        let back = Opt::DataPtrModify(-at);
+        self.step(&back);
+        push(out, back);
+    }
+}
+
+/// Net change to each cell from one run of `body`, if the body is balanced and
+/// has no loops, procedures or input.
+fn straight_deltas(body: &[Opt]) -> Option<HashMap<i64, i64>> {
+    let mut ptr = 0;
+    let mut deltas = HashMap::new();
+    for insn in body {
+        match insn {
+            Opt::DataPtrModify(x) => ptr += x,
+            Opt::DataValueModify(x) => {
+                let d = deltas.entry(ptr).or_insert(0i64);
+                *d = d.wrapping_add(*x);
+            }
+            Opt::DataValuePutchar => {}
+            _ => return None,
+        }
+    }
+    if ptr != 0 {
+        return None;
+    }
+    Some(deltas)
+}
+
+/// How often a loop that adds `step` to its counter runs when the counter
+/// starts at `n`, if it gets to exactly 0 without wrapping around.
+fn trip_count(n: u64, step: i64) -> Option<u64> {
+    let n = n as i64;
+    if step == 0 || n.signum() == step.signum() || n.checked_rem(step) != Some(0) {
+        return None;
+    }
+    Some(n.checked_div(step)?.unsigned_abs())
+}
+
+fn clone_straight(insn: &Opt) -> Opt {
+    match insn {
+        Opt::DataPtrModify(x) => Opt::DataPtrModify(*x),
+        Opt::DataValueModify(x) => Opt::DataValueModify(*x),
+        Opt::DataValuePutchar => Opt::DataValuePutchar,
+        _ => unreachable!(),
+    }
+}
+
+/// Appends `insn`, merging it into the previous instruction like the merge
+/// pass does and dropping instructions that cancel out.
+fn push(out: &mut Vec<Opt>, insn: Opt) {
+    match (out.last_mut(), &insn) {
+        (Some(Opt::DataPtrModify(a)), Opt::DataPtrModify(b))
+        | (Some(Opt::DataValueModify(a)), Opt::DataValueModify(b)) => {
+            *a = a.wrapping_add(*b);
+            if *a == 0 {
+                out.pop();
+            }
+        }
+        (_, Opt::DataPtrModify(0)) | (_, Opt::DataValueModify(0)) => {}
+        _ => out.push(insn),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parser;
+
+    fn unrolled(source: &str, mask: u64) -> (Vec<Opt>, Stats) {
+        let insns = Parser::new(source).parse();
+        unroll(Opt::optimize(insns, &mut 0, 0, false), mask)
+    }
+
+    fn optimized(source: &str) -> Vec<Opt> {
+        Opt::optimize(Parser::new(source).parse(), &mut 0, 0, false)
+    }
+
+    #[test]
+    fn dead_loops() {
+        let (out, stats) = unrolled("[-]>[.]+", u64::MAX);
+        assert_eq!(out, optimized(">+"));
+        assert_eq!(stats.dead, 2);
+    }
+
+    #[test]
+    fn folds_adding_loops() {
+        let (out, stats) = unrolled("+++[>++<-]>.", u64::MAX);
+        assert_eq!(out, optimized(">++++++."));
+        assert_eq!(stats.folded, 1);
+    }
+
+    #[test]
+    fn unrolls_short_loops() {
+        let (out, stats) = unrolled("++[>.<-]", u64::MAX);
+        assert_eq!(out, optimized("++>.<->.<-"));
+        assert_eq!(stats.unrolled, 1);
+    }
+
+    #[test]
+    fn wraps_at_the_cell_width() {
+        // 255 trips down to 0 with 8-bit cells, never with 64-bit ones
+        let (_, stats) = unrolled("-[>+<-]", 0xff);
+        assert_eq!(stats.folded, 1);
+        let (out, stats) = unrolled("-[>+<-]", u64::MAX);
+        assert_eq!(out, optimized("-[>+<-]"));
+        assert_eq!(stats.folded, 0);
+    }
+
+    #[test]
+    fn unknown_cells_stop_tracking() {
+        let source = ",[>+<-]+++[>.<-]";
+        let (out, stats) = unrolled(source, u64::MAX);
+        assert_eq!(out, optimized(",[>+<-]+++>.<->.<->.<-"));
+        assert_eq!(stats.unrolled, 1);
+        // nothing is known inside a loop that may not run
+        let (out, _) = unrolled(",[++[>.<-]]", u64::MAX);
+        assert_eq!(out, optimized(",[++[>.<-]]"));
+    }
+
+    #[test]
+    fn huge_trip_counts() {
+        // 2^62 trips of 4 instructions is 2^64, which wraps around to 0, and
+        // i64::MIN / -1 overflows
+        let (_, stats) = unroll(
+            vec![
+                Opt::DataValueModify(1 << 62),
+                Opt::WhileDataValueNonZero(vec![
+                    Opt::DataValuePutchar,
+                    Opt::DataValueModify(-1),
+                    Opt::DataValuePutchar,
+                    Opt::DataValuePutchar,
+                ]),
+            ],
+            u64::MAX,
+        );
+        assert_eq!(stats.unrolled, 0);
+        let (_, stats) = unroll(
+            vec![
+                Opt::DataValueModify(i64::MIN),
+                Opt::WhileDataValueNonZero(vec![Opt::DataValueModify(-1)]),
+            ],
+            u64::MAX,
+        );
+        assert_eq!(stats.folded, 0);
+    }
+}
@@ -0,0 +1,262 @@
+//! Runs bytecode without generating any machine code, for where the JIT can't
+//! map memory executable.
+//!
+//! Dispatch is threaded: every op is decoded once up front into the function
+//! that runs it plus its operands, so the loop only calls through the pointer
+//! instead of matching on the op each time. Every cell access is checked
+//! against the tape, going off it stops with the same error as `--checked`,
+//! and recorded with `runtime::touch` for the report.
+//!
+//! A run can be paused at the next backward jump or procedure call, which is
+//! how snapshots are taken: the whole state is the tape plus a `Position`.
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{bytecode::Op, runtime, CellWidth};
+
+/// Runs one op, returning the index of the next one
+type Handler = fn(&mut State, &Insn, usize) -> usize;
+
+struct Insn {
+    run: Handler,
+    a: i64,
+    b: i64,
+}
+
+struct State<'t> {
+    tape: &'t mut [u64],
+    mask: u64,
+    at: Position,
+    /// Where to carry on from, once a pause stopped the run
+    paused: Option<usize>,
+}
+
+/// Everything about a run but the tape
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Position {
+    /// The next op to run
+    pub pc: usize,
+    pub ptr: usize,
+    /// Where each defined pbrain procedure starts
+    pub procedures: HashMap<u64, usize>,
+    /// Where to go back to after each procedure that's running
+    pub calls: Vec<usize>,
+}
+
+impl Position {
+    /// The start of the program, with the data pointer at `ptr`
+    pub fn start(ptr: usize) -> Self {
+        Self {
+            ptr,
+            ..Self::default()
+        }
+    }
+}
+
+static PAUSE: AtomicBool = AtomicBool::new(false);
+
+/// Makes the VM stop at the next backward jump or procedure call, see
+/// `Vm::resume`. Only touches an atomic, so signal handlers can call it.
+pub fn request_pause() {
+    PAUSE.store(true, Ordering::Relaxed);
+}
+
+/// Stops the run at `pc` if a pause was requested
+fn pause_at(s: &mut State, pc: usize) -> usize {
+    if PAUSE.swap(false, Ordering::Relaxed) {
+        s.paused = Some(pc);
+        usize::MAX
+    } else {
+        pc
+    }
+}
+
+pub struct Vm {
+    code: Vec<Insn>,
+    mask: u64,
+}
+
+impl Vm {
+    pub fn new(ops: &[Op], width: CellWidth) -> Self {
+        let code = ops
+            .iter()
+            .map(|op| {
+                let (run, a, b): (Handler, i64, i64) = match *op {
+                    Op::Move(x) => (op_move, x, 0),
+                    Op::Add(x) => (op_add, x, 0),
+                    Op::AddMove(x, y) => (op_add_move, x, y),
+                    Op::Clear => (op_set, 0, 0),
+                    Op::Set(x) => (op_set, x, 0),
+                    Op::MulAdd(offset, factor) => (op_mul_add, offset, factor),
+                    Op::Scan(x) => (op_scan, x, 0),
+                    Op::Put => (op_put, 0, 0),
+                    Op::Get => (op_get, 0, 0),
+                    Op::JumpZero(d) => (op_jump_zero, d as i64 + 1, 0),
+                    Op::JumpNonZero(d) => (op_jump_non_zero, d as i64 - 1, 0),
+                    Op::Define(d) => (op_define, d as i64, 0),
+                    Op::Return => (op_return, 0, 0),
+                    Op::Call => (op_call, 0, 0),
+                };
+                Insn { run, a, b }
+            })
+            .collect();
+        Self {
+            code,
+            mask: width.mask(),
+        }
+    }
+
+    /// Runs the program with the data pointer at `tape[ptr]`, returning where
+    /// the pointer ended up.
+    pub fn run(&self, tape: &mut [u64], ptr: usize) -> usize {
+        let mut at = Position::start(ptr);
+        loop {
+            match self.resume(tape, at) {
+                Ok(ptr) => return ptr,
+                Err(paused) => at = paused,
+            }
+        }
+    }
+
+    /// Runs the program from `at` until it ends, returning where the pointer
+    /// ended up, or until `request_pause` stops it, returning where to resume.
+    pub fn resume(&self, tape: &mut [u64], at: Position) -> Result<usize, Position> {
+        let mut pc = at.pc;
+        let mut state = State {
+            tape,
+            mask: self.mask,
+            at,
+            paused: None,
+        };
+        while let Some(insn) = self.code.get(pc) {
+            pc = (insn.run)(&mut state, insn, pc);
+        }
+        match state.paused {
+            Some(pc) => Err(Position { pc, ..state.at }),
+            None => Ok(state.at.ptr),
+        }
+    }
+}
+
+impl State<'_> {
+    fn cell(&mut self) -> &mut u64 {
+        self.at(0)
+    }
+
+    fn at(&mut self, offset: i64) -> &mut u64 {
+        let i = self.at.ptr.wrapping_add(offset as usize);
+        if i >= self.tape.len() {
+            self.off_tape(i);
+        }
+        let c = &mut self.tape[i];
+        let addr = c as *mut u64 as usize;
+        runtime::touch(addr, addr);
+        c
+    }
+
+    fn off_tape(&self, i: usize) -> ! {
+        let addr = (self.tape.as_ptr() as usize).wrapping_add(i.wrapping_mul(8));
+        runtime::bounds_error(addr, addr)
+    }
+
+    fn procedure(&mut self) -> u64 {
+        let id = *self.cell();
+        if id as usize >= runtime::PROCEDURE_COUNT {
+            runtime::procedure_error(id as i64);
+        }
+        id
+    }
+}
+
+fn op_move(s: &mut State, insn: &Insn, pc: usize) -> usize {
+    s.at.ptr = s.at.ptr.wrapping_add(insn.a as usize);
+    pc + 1
+}
+
+fn op_add(s: &mut State, insn: &Insn, pc: usize) -> usize {
+    let mask = s.mask;
+    let c = s.cell();
+    *c = c.wrapping_add(insn.a as u64) & mask;
+    pc + 1
+}
+
+fn op_add_move(s: &mut State, insn: &Insn, pc: usize) -> usize {
+    op_add(s, insn, pc);
+    s.at.ptr = s.at.ptr.wrapping_add(insn.b as usize);
+    pc + 1
+}
+
+fn op_set(s: &mut State, insn: &Insn, pc: usize) -> usize {
+    let mask = s.mask;
+    *s.cell() = insn.a as u64 & mask;
+    pc + 1
+}
+
+fn op_mul_add(s: &mut State, insn: &Insn, pc: usize) -> usize {
+    let (n, mask) = (*s.cell(), s.mask);
+    // the loop never ran, so it never got to the cell, which may be off the tape
+    if n == 0 {
+        return pc + 1;
+    }
+    let c = s.at(insn.a);
+    *c = c.wrapping_add(n.wrapping_mul(insn.b as u64)) & mask;
+    pc + 1
+}
+
+fn op_scan(s: &mut State, insn: &Insn, pc: usize) -> usize {
+    while *s.cell() != 0 {
+        s.at.ptr = s.at.ptr.wrapping_add(insn.a as usize);
+    }
+    pc + 1
+}
+
+fn op_put(s: &mut State, _: &Insn, pc: usize) -> usize {
+    runtime::write_output(*s.cell());
+    pc + 1
+}
+
+fn op_get(s: &mut State, _: &Insn, pc: usize) -> usize {
+    let c = runtime::read_input(s.cell());
+    *s.cell() = c & s.mask;
+    pc + 1
+}
+
+fn op_jump_zero(s: &mut State, insn: &Insn, pc: usize) -> usize {
+    if *s.cell() == 0 {
+        pc + insn.a as usize
+    } else {
+        pc + 1
+    }
+}
+
+fn op_jump_non_zero(s: &mut State, insn: &Insn, pc: usize) -> usize {
+    if *s.cell() != 0 {
+        pause_at(s, pc - insn.a as usize)
+    } else {
+        pc + 1
+    }
+}
+
+fn op_define(s: &mut State, insn: &Insn, pc: usize) -> usize {
+    let id = s.procedure();
+    s.at.procedures.insert(id, pc + 1);
+    pc + insn.a as usize
+}
+
+fn op_return(s: &mut State, _: &Insn, _: usize) -> usize {
+    s.at.calls.pop().unwrap()
+}
+
+fn op_call(s: &mut State, _: &Insn, pc: usize) -> usize {
+    let id = s.procedure();
+    let start = *s
+        .at
+        .procedures
+        .get(&id)
+        .unwrap_or_else(|| runtime::procedure_error(id as i64));
+    s.at.calls.push(pc + 1);
+    pause_at(s, start)
+}
@@ -0,0 +1,728 @@
+//! Runs the bundled programs under every engine, optimizer setting and cell
+//! width they work with, and compares what they print against tests/golden.
+//!
+//! Input comes from `tests/golden/NAME.in` where a program reads any, or is
+//! replayed from `tests/golden/NAME.replay`, recorded with `--record`. The
+//! expected output is `tests/golden/NAME.out`.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use bfi::{interp, replay, runtime, CellWidth, Parser};
+
+const ENGINES: [&str; 3] = ["jit", "tiered", "vm"];
+const ALL_WIDTHS: [u32; 4] = [8, 16, 32, 64];
+
+fn check(program: &str, widths: &[u32]) {
+    check_with(program, &ENGINES, widths, &[]);
+}
+
+/// Like `check`, but only under `engines` and with `extra` arguments, for
+/// programs that need an extension.
+fn check_with(program: &str, engines: &[&str], widths: &[u32], extra: &[&str]) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let name = Path::new(program).file_stem().unwrap().to_str().unwrap();
+    let golden = root.join("tests/golden");
+    let input = fs::read(golden.join(format!("{}.in", name))).unwrap_or_default();
+    let replay = golden.join(format!("{}.replay", name));
+    let expected = fs::read_to_string(golden.join(format!("{}.out", name))).unwrap();
+
+    // every configuration runs at once, the slow ones are slow to compile, not to run
+    let mut runs = Vec::new();
+    for engine in engines {
+        for width in widths {
+            for unroll in [true, false].iter() {
+                let width = width.to_string();
+                let mut args = vec![
+                    "--quiet",
+                    "--no-cache",
+                    "--engine",
+                    engine,
+                    "--cell-width",
+                    &width,
+                ];
+                args.extend(extra);
+                // tiny chunks compiled on two threads put chunk boundaries
+                // everywhere, and each of the other options is on in one of
+                // the two runs and off in the other
+                if *unroll {
+                    args.extend(["--checked", "--no-outline"]);
+                } else {
+                    args.extend([
+                        "--no-unroll",
+                        "--chunk-size",
+                        "64",
+                        "--compile-threads",
+                        "2",
+                    ]);
+                }
+                let mut command = Command::new(env!("CARGO_BIN_EXE_bfi"));
+                if replay.exists() {
+                    command.arg("--replay").arg(&replay);
+                }
+                let mut child = command
+                    .args(&args)
+                    .arg(root.join("programs").join(program))
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::null())
+                    .spawn()
+                    .unwrap();
+                child.stdin.take().unwrap().write_all(&input).unwrap();
+                runs.push((args.join(" "), child));
+            }
+        }
+    }
+    for (args, child) in runs {
+        let out = child.wait_with_output().unwrap();
+        assert!(out.status.success(), "{} failed with {}", program, args);
+        assert_eq!(
+            String::from_utf8_lossy(&out.stdout),
+            expected,
+            "{} printed something else with {}",
+            program,
+            args
+        );
+    }
+}
+
+/// Runs `program` compiled to a `.bfc` file, which goes through the VM.
+fn check_bytecode(program: &str) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let name = Path::new(program).file_stem().unwrap().to_str().unwrap();
+    let golden = root.join("tests/golden");
+    let bfc = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.bfc", name));
+    let status = Command::new(env!("CARGO_BIN_EXE_bfi"))
+        .args(["compile", "--quiet", "--no-cache", "-o"])
+        .arg(&bfc)
+        .arg(root.join("programs").join(program))
+        .status()
+        .unwrap();
+    assert!(status.success(), "compiling {} failed", program);
+    let out = Command::new(env!("CARGO_BIN_EXE_bfi"))
+        .arg("--quiet")
+        .arg(&bfc)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "running {} failed", bfc.display());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        fs::read_to_string(golden.join(format!("{}.out", name))).unwrap()
+    );
+}
+
+#[test]
+fn triangle() {
+    check("triangle.bf", &ALL_WIDTHS);
+}
+
+#[test]
+fn collatz() {
+    // ends its input with a 0, since EOF reads as -1
+    check("collatz.b", &ALL_WIDTHS);
+}
+
+#[test]
+fn prime() {
+    check("PRIME.BF", &ALL_WIDTHS);
+}
+
+#[test]
+fn hanoi() {
+    check("hanoi.bf", &ALL_WIDTHS);
+}
+
+#[test]
+fn mandelbrot() {
+    check("mandelbrot.b", &ALL_WIDTHS);
+}
+
+#[test]
+fn beer() {
+    check("ryan-beer.bf", &ALL_WIDTHS);
+}
+
+#[test]
+fn pbrain() {
+    check_with("pbrain.b", &ENGINES, &ALL_WIDTHS, &["--pbrain"]);
+}
+
+#[test]
+fn brainfork() {
+    let deterministic = ["--brainfork", "--schedule", "deterministic"];
+    check_with("brainfork.b", &["jit"], &ALL_WIDTHS, &deterministic);
+
+    // threads print in any order, but each prints the same thing
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let out = Command::new(env!("CARGO_BIN_EXE_bfi"))
+        .args(["--quiet", "--no-cache", "--brainfork"])
+        .arg(root.join("programs/brainfork.b"))
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let mut printed = out.stdout.clone();
+    printed.sort_unstable();
+    let mut expected = fs::read(root.join("tests/golden/brainfork.out")).unwrap();
+    expected.sort_unstable();
+    assert_eq!(printed, expected);
+    let main: Vec<_> = out
+        .stdout
+        .iter()
+        .filter(|c| !c.is_ascii_lowercase())
+        .collect();
+    assert_eq!(main, b"ABC\n".iter().collect::<Vec<_>>());
+}
+
+/// Writes `program` spelled with `spell` for each command into a directory
+/// of its own under `dialect`, for `check_with` to run.
+fn translate(program: &str, dialect: &str, spell: impl Fn(char) -> String) -> String {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let source = fs::read_to_string(root.join("programs").join(program)).unwrap();
+    let translated: Vec<_> = source
+        .chars()
+        .filter(|c| "+-<>.,[]".contains(*c))
+        .map(spell)
+        .collect();
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(dialect);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(program);
+    fs::write(&path, translated.join(" ")).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn dialects() {
+    let words = |stem: &'static str| {
+        move |c| {
+            let (a, b) = match c {
+                '>' => ('.', '?'),
+                '<' => ('?', '.'),
+                '+' => ('.', '.'),
+                '-' => ('!', '!'),
+                '.' => ('!', '.'),
+                ',' => ('.', '!'),
+                '[' => ('!', '?'),
+                _ => ('?', '!'),
+            };
+            format!("{0}{1} {0}{2}", stem, a, b)
+        }
+    };
+    let ook = translate("PRIME.BF", "ook", words("Ook"));
+    check_with(&ook, &ENGINES, &[64], &["--dialect", "ook"]);
+    let blub = translate("PRIME.BF", "blub", words("Blub"));
+    check_with(&blub, &ENGINES, &[64], &["--dialect", "blub"]);
+
+    // tokens that are prefixes of each other, so the longest has to win
+    let names: HashMap<char, &str> = [
+        ('>', "mo"),
+        ('<', "moo"),
+        ('+', "m"),
+        ('-', "mm"),
+        ('.', "say"),
+        (',', "sayyy"),
+        ('[', "("),
+        (']', ")"),
+    ]
+    .iter()
+    .copied()
+    .collect();
+    let map = Path::new(env!("CARGO_TARGET_TMPDIR")).join("cow.toml");
+    let mut toml = "[tokens]\n".to_string();
+    for (c, name) in &names {
+        toml += &format!("\"{}\" = \"{}\"\n", name, c);
+    }
+    fs::write(&map, toml).unwrap();
+    let cow = translate("PRIME.BF", "cow", |c| names[&c].to_string());
+    check_with(
+        &cow,
+        &ENGINES,
+        &[64],
+        &["--token-map", map.to_str().unwrap()],
+    );
+}
+
+#[test]
+fn io_modes() {
+    let program = Path::new(env!("CARGO_TARGET_TMPDIR")).join("echo.b");
+    fs::write(&program, ",[.,]").unwrap();
+    let runs: [(&str, &[u32], &str, &str); 3] = [
+        ("decimal", &[8], "3 -1 200 --5 0", "3\n-1\n-56\n-5\n"),
+        (
+            "decimal",
+            &[16, 32, 64],
+            "3 -1 200 --5 0",
+            "3\n-1\n200\n-5\n",
+        ),
+        ("utf8", &[32, 64], "aé€😀\0", "aé€😀"),
+    ];
+    for (mode, widths, input, expected) in runs.iter() {
+        for engine in ENGINES.iter() {
+            for width in widths.iter() {
+                let width = width.to_string();
+                let mut child = Command::new(env!("CARGO_BIN_EXE_bfi"))
+                    .args(["--quiet", "--no-cache", "--engine", engine])
+                    .args(["--cell-width", &width, "--input", mode, "--output", mode])
+                    .arg(&program)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::null())
+                    .spawn()
+                    .unwrap();
+                child
+                    .stdin
+                    .take()
+                    .unwrap()
+                    .write_all(input.as_bytes())
+                    .unwrap();
+                let out = child.wait_with_output().unwrap();
+                assert!(out.status.success());
+                assert_eq!(
+                    String::from_utf8_lossy(&out.stdout),
+                    *expected,
+                    "--io {} with {} and {}-bit cells",
+                    mode,
+                    engine,
+                    width
+                );
+            }
+        }
+    }
+}
+
+/// The goldens were written down by hand or by other interpreters, so they're
+/// checked against `interp`, which shares nothing with the engines but the
+/// parser and the runtime's I/O. pbrain.b and brainfork.b need commands it
+/// doesn't have, mandelbrot takes too long unoptimized.
+#[test]
+fn goldens_match_reference() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let golden = root.join("tests/golden");
+    // at the narrowest width each is tested with, some rely on cells wrapping
+    let programs = [
+        ("triangle.bf", 8),
+        ("collatz.b", 8),
+        ("PRIME.BF", 8),
+        ("hanoi.bf", 8),
+        ("ryan-beer.bf", 8),
+        ("gameoflife.b", 8),
+        ("z9a.b", 8),
+        ("LostKng.b", 8),
+    ];
+    runtime::capture_output();
+    for &(program, width) in programs.iter() {
+        let name = Path::new(program).file_stem().unwrap().to_str().unwrap();
+        let source = fs::read_to_string(root.join("programs").join(program)).unwrap();
+        let input = match fs::read_to_string(golden.join(format!("{}.replay", name))) {
+            Ok(text) => replay::parse(&text).unwrap(),
+            Err(_) => fs::read(golden.join(format!("{}.in", name))).unwrap_or_default(),
+        };
+        runtime::set_embedded_input(input, false);
+        runtime::take_output();
+
+        let mut tape = vec![0; 1 << 20];
+        let insns = Parser::new(&source).parse();
+        let width = CellWidth::from_bits(width).unwrap();
+        let end = interp::run(&insns, &mut tape, 1 << 19, u64::MAX, width);
+        assert!(end.is_ok(), "{} stopped with {:?}", program, end);
+        assert_eq!(
+            String::from_utf8_lossy(&runtime::take_output()),
+            fs::read_to_string(golden.join(format!("{}.out", name))).unwrap(),
+            "the golden output of {} is wrong",
+            program
+        );
+    }
+}
+
+#[test]
+fn tape_used() {
+    // the far cell is cleared again and the last two loops never run, so
+    // neither the nonzero cells nor the whole loop bodies give the right answer
+    let program = Path::new(env!("CARGO_TARGET_TMPDIR")).join("tape-used.b");
+    let far = ">".repeat(20) + "+" + &"<".repeat(20);
+    let multiply = "[<<<+>>>-]";
+    fs::write(
+        &program,
+        format!("+>+>+<<[>]>>+[-]<<<<<<<<+-[{}]{}", far, multiply),
+    )
+    .unwrap();
+    for engine in ENGINES.iter() {
+        let out = Command::new(env!("CARGO_BIN_EXE_bfi"))
+            .args(["--quiet", "--no-cache", "--no-unroll", "--engine", engine])
+            .arg(&program)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        let report = String::from_utf8_lossy(&out.stderr);
+        assert!(
+            report.contains("tape used: cells -3 to 5\n"),
+            "{} reported {}",
+            engine,
+            report
+        );
+    }
+}
+
+#[test]
+fn checked() {
+    // walks left until it falls off the start of the tape, 9000000 cells away
+    let program = Path::new(env!("CARGO_TARGET_TMPDIR")).join("off-tape.b");
+    fs::write(&program, "+[<+]").unwrap();
+    let runs: [&[&str]; 4] = [
+        &["--engine", "jit"],
+        &["--engine", "vm"],
+        &["--engine", "tiered"],
+        // never compiles the loop, so the interpreter falls off
+        &["--engine", "tiered", "--jit-threshold", "4000000000"],
+    ];
+    for args in runs.iter() {
+        let out = Command::new(env!("CARGO_BIN_EXE_bfi"))
+            .args(["--quiet", "--no-cache", "--checked"])
+            .args(*args)
+            .arg(&program)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(1), "{:?}", args);
+        assert_eq!(
+            String::from_utf8_lossy(&out.stderr),
+            "error: pointer moved off the tape to cell -9000001, \
+             the tape has cells -9000000 to 125217727\n",
+            "{:?}",
+            args
+        );
+    }
+    // the loop at the start of the tape never runs, so neither does the
+    // `<` in it
+    let program = Path::new(env!("CARGO_TARGET_TMPDIR")).join("at-start.b");
+    fs::write(&program, "<".repeat(9000000) + "[<+>]+.").unwrap();
+    for engine in ["jit", "vm", "tiered"].iter() {
+        let out = Command::new(env!("CARGO_BIN_EXE_bfi"))
+            .args(["--quiet", "--no-cache", "--checked", "--no-unroll"])
+            .args(["--engine", engine])
+            .arg(&program)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "{}: {:?}", engine, out);
+        assert_eq!(out.stdout, b"\x01", "{}", engine);
+    }
+}
+
+#[test]
+fn tiny_chunks() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let expected = fs::read(root.join("tests/golden/triangle.out")).unwrap();
+    for size in ["1", "2", "4", "7"] {
+        let out = Command::new(env!("CARGO_BIN_EXE_bfi"))
+            .args(["--quiet", "--no-cache", "--no-unroll", "--chunk-size", size])
+            .arg(root.join("programs/triangle.bf"))
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "--chunk-size {} failed", size);
+        assert_eq!(out.stdout, expected, "--chunk-size {}", size);
+    }
+}
+
+#[test]
+fn bytecode_file() {
+    check_bytecode("hanoi.bf");
+}
+
+#[test]
+fn snapshot() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let snapshot = Path::new(env!("CARGO_TARGET_TMPDIR")).join("mandelbrot.bfs");
+    let _ = fs::remove_file(&snapshot);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bfi"))
+        .args(["--no-cache", "--engine", "vm", "--snapshot"])
+        .arg(&snapshot)
+        .arg(root.join("programs/mandelbrot.b"))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // the handler is in place once the VM says it's running
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    while !line.starts_with("Running VM") {
+        line.clear();
+        assert!(stdout.read_line(&mut line).unwrap() > 0);
+    }
+    let status = Command::new("kill")
+        .args(["-USR1", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    io::copy(&mut stdout, &mut io::sink()).unwrap();
+    assert!(child.wait().unwrap().success());
+
+    let out = Command::new(env!("CARGO_BIN_EXE_bfi"))
+        .arg("--quiet")
+        .arg(&snapshot)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    // the resumed run prints the rest of the picture
+    let expected = fs::read(root.join("tests/golden/mandelbrot.out")).unwrap();
+    assert!(!out.stdout.is_empty());
+    assert!(expected.ends_with(&out.stdout));
+}
+
+#[test]
+fn interrupt() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    for (engine, running) in [("jit", "All engines go!"), ("vm", "Running VM")] {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_bfi"))
+            .args(["--no-cache", "--engine", engine])
+            .arg(root.join("programs/mandelbrot.b"))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // the handler is in place once the engine says it's running
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        while !line.starts_with(running) {
+            line.clear();
+            assert!(stdout.read_line(&mut line).unwrap() > 0);
+        }
+        let status = Command::new("kill")
+            .args(["-INT", &child.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        io::copy(&mut stdout, &mut io::sink()).unwrap();
+        let out = child.wait_with_output().unwrap();
+        assert_eq!(out.status.code(), Some(130), "{}", engine);
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains("Interrupted after"), "{}", stderr);
+        assert!(stderr.contains("near it:"), "{}", stderr);
+    }
+}
+
+#[test]
+fn cache() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let home = Path::new(env!("CARGO_TARGET_TMPDIR")).join("cache-home");
+    let _ = fs::remove_dir_all(&home);
+    // whether the run loaded the program from the cache
+    let cached = |args: &[&str]| {
+        let out = Command::new(env!("CARGO_BIN_EXE_bfi"))
+            .env("XDG_CACHE_HOME", &home)
+            .args(args)
+            .arg(root.join("programs/triangle.bf"))
+            .stderr(Stdio::null())
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        String::from_utf8_lossy(&out.stdout).contains("Loaded optimized code from cache")
+    };
+    assert!(!cached(&[]));
+    assert!(cached(&[]));
+    // options that change what the optimizer makes of the program get entries of their own
+    assert!(!cached(&["--cell-width", "8"]));
+    assert!(cached(&["--cell-width", "8"]));
+    assert!(!cached(&["--no-unroll"]));
+    assert!(cached(&["--no-unroll"]));
+    assert!(cached(&[]));
+
+    // broken entries are ignored and replaced
+    for entry in fs::read_dir(home.join("bfi")).unwrap() {
+        fs::write(entry.unwrap().path(), b"BFIC").unwrap();
+    }
+    assert!(!cached(&[]));
+    assert!(cached(&[]));
+}
+
+#[test]
+fn dump_dir_error() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // a directory can't be created inside a file
+    let dir = root.join("programs/triangle.bf/dumps");
+    let out = Command::new(env!("CARGO_BIN_EXE_bfi"))
+        .args(["--quiet", "--no-cache", "--dump", "all", "--dump-dir"])
+        .arg(&dir)
+        .arg(root.join("programs/triangle.bf"))
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.starts_with("error: could not create dump directory"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn perf_map() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let child = Command::new(env!("CARGO_BIN_EXE_bfi"))
+        .args(["--quiet", "--no-cache", "--perf-map", "--chunk-size", "300"])
+        .arg(root.join("programs/hanoi.bf"))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let map = format!("/tmp/perf-{}.map", child.id());
+    assert!(child.wait_with_output().unwrap().status.success());
+    let listed = fs::read_to_string(&map).unwrap();
+    fs::remove_file(&map).unwrap();
+    let names: Vec<_> = listed
+        .lines()
+        .map(|line| {
+            let fields: Vec<_> = line.split(' ').collect();
+            assert_eq!(fields.len(), 3, "bad line {:?}", line);
+            assert!(u64::from_str_radix(fields[0], 16).unwrap() > 0);
+            assert!(u64::from_str_radix(fields[1], 16).unwrap() > 0);
+            fields[2]
+        })
+        .collect();
+    assert!(names.contains(&"bf"));
+    assert!(names.contains(&"bf_shared0"));
+    assert!(names.contains(&"bf_chunk0"));
+}
+
+/// Runs `bfi` with `args` and `stdin`, for the subcommands that don't run
+/// a program.
+fn bfi(args: &[&str], stdin: &str) -> std::process::Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bfi"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn fmt_errors() {
+    for args in [&["fmt"][..], &["fmt", "--minify"]] {
+        let out = bfi(args, "+]");
+        assert_eq!(out.status.code(), Some(1), "{:?}", args);
+        assert_eq!(
+            String::from_utf8_lossy(&out.stderr),
+            "error: unmatched `]` at byte 1\n"
+        );
+    }
+    let out = bfi(&["fmt", "/nonexistent/program.b"], "");
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr)
+        .starts_with("error: could not read /nonexistent/program.b: "));
+}
+
+#[test]
+fn lint_errors() {
+    let program = Path::new(env!("CARGO_TARGET_TMPDIR")).join("unmatched.b");
+    fs::write(&program, "+]").unwrap();
+    let out = bfi(&["lint", program.to_str().unwrap()], "");
+    assert_eq!(out.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8_lossy(&out.stderr),
+        format!("error: {}: unmatched `]` at byte 1\n", program.display())
+    );
+    let out = bfi(&["lint", "/nonexistent/program.b"], "");
+    assert_eq!(out.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&out.stderr)
+        .starts_with("error: could not read /nonexistent/program.b: "));
+}
+
+#[test]
+fn coverage() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let program = dir.join("coverage.b");
+    fs::write(
+        &program,
+        "outer\n++++[>++\n[>+<-]<-]\n>[-]\n[ never\n+++.\n]\n",
+    )
+    .unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_bfi"))
+        .args(["--quiet", "--no-cache", "--coverage"])
+        .arg(&program)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let listing = fs::read_to_string(dir.join("coverage.cov")).unwrap();
+    let counts: Vec<_> = listing
+        .lines()
+        .skip(3)
+        .map(|l| l.split(':').next().unwrap().trim())
+        .collect();
+    assert_eq!(counts, ["-", "4", "8", "1*", "1", "#####", "#####"]);
+    let lcov = fs::read_to_string(dir.join("coverage.info")).unwrap();
+    // the last loop is reached once and never entered
+    assert!(lcov.contains("BRDA:5,3,0,0\nBRDA:5,3,1,1\n"));
+    assert!(lcov.contains("LF:6\nLH:4\n"));
+
+    // two threads counting on their own cells, in the same counters
+    let forks = dir.join("coverage-forks.b");
+    fs::write(&forks, "Y[>>>>>>>>>>]>>\n-[>-\n[>-[-]<-]\n<-]\n").unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_bfi"))
+        .args(["--quiet", "--no-cache", "--coverage", "--brainfork"])
+        .args(["--cell-width", "8"])
+        .arg(&forks)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let listing = fs::read_to_string(dir.join("coverage-forks.cov")).unwrap();
+    let counts: Vec<_> = listing
+        .lines()
+        .skip(3)
+        .map(|l| l.split(':').next().unwrap().trim())
+        .collect();
+    assert_eq!(counts, ["2", "510", "33162750", "510"]);
+
+    // a program named like the listing is left alone
+    let named_cov = dir.join("coverage-named.cov");
+    fs::write(&named_cov, "+.").unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_bfi"))
+        .args(["--quiet", "--no-cache", "--coverage"])
+        .arg(&named_cov)
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(2));
+    assert!(out.stdout.is_empty());
+    assert_eq!(fs::read_to_string(&named_cov).unwrap(), "+.");
+}
+
+// the next three count on cells wrapping around, which takes too long with wider ones
+
+#[test]
+fn game_of_life() {
+    check("gameoflife.b", &[8, 16]);
+}
+
+#[test]
+fn z9a() {
+    check("z9a.b", &[8, 16]);
+}
+
+#[test]
+fn lost_kingdom() {
+    check("LostKng.b", &[8]);
+}